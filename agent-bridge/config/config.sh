@@ -4,4 +4,22 @@ curl -s -X PUT -H 'Content-Type: application/json' --data @config/agents-mqtt-co
 curl -s -X PUT -H 'Content-Type: application/json' --data @config/processes-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/processes/config
 curl -s -X PUT -H 'Content-Type: application/json' --data @config/networks-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/connections/config
 curl -s -X PUT -H 'Content-Type: application/json' --data @config/listening-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/listening/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/custom-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/custom/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/collector-status-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/collector-status/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/hardware-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/hardware/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/interfaces-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/interfaces/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/routes-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/routes/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/neighbours-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/neighbours/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/dns-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/dns/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/storage-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/storage/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/dependencies-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/dependencies/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/packages-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/packages/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/units-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/units/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/accounts-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/accounts/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/jobs-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/jobs/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/ssh-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/ssh/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/certificates-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/certificates/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/process-tree-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/process-tree/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/executables-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/executables/config
+curl -s -X PUT -H 'Content-Type: application/json' --data @config/libraries-mqtt-connector-config.json http://$TEST_BRIDGE_HOST:8083/connectors/libraries/config
 curl -s -X GET -H 'Content-Type: application/json' http://$TEST_BRIDGE_HOST:8083/connectors/
@@ -4,4 +4,22 @@ curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/conn
 curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/processes
 curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/net-connections
 curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/net-listening
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/custom
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/collector-status
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/hardware
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/interfaces
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/routes
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/neighbours
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/dns
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/storage
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/dependencies
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/packages
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/units
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/accounts
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/jobs
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/ssh
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/certificates
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/process-tree
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/executables
+curl -s -X DELETE -H 'Content-Type: application/json' http://localhost:8083/connectors/libraries
 curl -s -X GET -H 'Content-Type: application/json' http://localhost:8083/connectors/
@@ -0,0 +1,173 @@
+//Fleet wide certificate expiry from the certificates record of every node. The same certificate is often deployed on
+//many nodes, so the report has one entry per certificate (by fingerprint) listing everywhere it was found.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Certificate {
+    pub path: String,
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub sans: Vec<String>,
+    pub issuer: String,
+    pub not_after: Option<DateTime<Utc>>,
+    pub is_ca: bool,
+    pub fingerprint: String,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+struct NodeCertificates {
+    certificates: Vec<Certificate>,
+    //Records from agents older than the flag are whole
+    #[serde(default = "whole")]
+    complete: bool,
+}
+
+fn whole() -> bool {
+    true
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Location {
+    pub node: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExpiryEntry {
+    //expired or expiring
+    pub status: String,
+    pub days_left: i64,
+    pub not_after: DateTime<Utc>,
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub sans: Vec<String>,
+    pub issuer: String,
+    pub fingerprint: String,
+    pub locations: Vec<Location>,
+}
+
+#[derive(Default)]
+pub struct CertificateStore {
+    nodes: HashMap<String, Vec<Certificate>>,
+}
+
+impl CertificateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //A complete record lists everything the node has, so it replaces what we had for that node. A scan the agent's
+    //budget cut short only replaces the files it got to, the rest stay as they were last seen.
+    pub fn update(&mut self, message: &Value) -> Result<String, Box<dyn Error>> {
+        let node = message.get("node").and_then(Value::as_str).ok_or("certificates record without a node")?.to_string();
+        let record: NodeCertificates = serde_json::from_value(message.clone())?;
+        if record.complete {
+            self.nodes.insert(node.clone(), record.certificates);
+        } else {
+            let certificates = self.nodes.entry(node.clone()).or_default();
+            let paths: HashSet<&str> = record.certificates.iter().map(|c| c.path.as_str()).collect();
+            certificates.retain(|c| !paths.contains(c.path.as_str()));
+            certificates.extend(record.certificates.iter().cloned());
+        }
+        Ok(node)
+    }
+
+    //Certificates already expired or expiring within warn_days, soonest first
+    pub fn expiry_report(&self, now: DateTime<Utc>, warn_days: i64) -> Vec<ExpiryEntry> {
+        let mut by_fingerprint: BTreeMap<&str, ExpiryEntry> = BTreeMap::new();
+        for (node, certificates) in &self.nodes {
+            for certificate in certificates {
+                let not_after = match certificate.not_after {
+                    Some(not_after) => not_after,
+                    None => continue,
+                };
+                let days_left = (not_after - now).num_days();
+                if days_left > warn_days {
+                    continue;
+                }
+                let entry = by_fingerprint.entry(&certificate.fingerprint).or_insert_with(|| ExpiryEntry {
+                    status: if not_after <= now {"expired".to_string()} else {"expiring".to_string()},
+                    days_left: days_left,
+                    not_after: not_after,
+                    subject: certificate.subject.clone(),
+                    common_name: certificate.common_name.clone(),
+                    sans: certificate.sans.clone(),
+                    issuer: certificate.issuer.clone(),
+                    fingerprint: certificate.fingerprint.clone(),
+                    locations: Vec::new(),
+                });
+                entry.locations.push(Location { node: node.clone(), path: certificate.path.clone() });
+            }
+        }
+        let mut report: Vec<ExpiryEntry> = by_fingerprint.into_values().collect();
+        for entry in report.iter_mut() {
+            entry.locations.sort_by(|a, b| (&a.node, &a.path).cmp(&(&b.node, &b.path)));
+        }
+        report.sort_by(|a, b| a.not_after.cmp(&b.not_after).then_with(|| a.fingerprint.cmp(&b.fingerprint)));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn store() -> CertificateStore {
+        let mut store = CertificateStore::new();
+        store.update(&json!({
+            "node": "web01",
+            "certificates": [
+                {"path": "/etc/ssl/www.pem", "subject": "CN=www.example.com", "issuer": "CN=R3", "not_after": "2026-11-01T00:00:00Z", "fingerprint": "AA"},
+                {"path": "/etc/ssl/old.pem", "subject": "CN=old.example.com", "issuer": "CN=R3", "not_after": "2026-10-01T00:00:00Z", "fingerprint": "BB"},
+                {"path": "/etc/ssl/root.pem", "subject": "CN=Root", "issuer": "CN=Root", "not_after": "2040-01-01T00:00:00Z", "fingerprint": "CC"}
+            ]
+        })).unwrap();
+        store.update(&json!({
+            "node": "web02",
+            "certificates": [{"path": "/etc/nginx/www.pem", "subject": "CN=www.example.com", "issuer": "CN=R3", "not_after": "2026-11-01T00:00:00Z", "fingerprint": "AA"}]
+        })).unwrap();
+        store
+    }
+
+    #[test]
+    fn report_groups_by_certificate() {
+        let now: DateTime<Utc> = "2026-10-19T00:00:00Z".parse().unwrap();
+        let report = store().expiry_report(now, 30);
+        assert_eq!(report.len(), 2);
+        assert_eq!((report[0].status.as_str(), report[0].days_left, report[0].fingerprint.as_str()), ("expired", -18, "BB"));
+        assert_eq!((report[1].status.as_str(), report[1].days_left), ("expiring", 13));
+        assert_eq!(report[1].locations, vec![Location { node: "web01".to_string(), path: "/etc/ssl/www.pem".to_string() }, Location { node: "web02".to_string(), path: "/etc/nginx/www.pem".to_string() }]);
+    }
+
+    #[test]
+    fn renewed_certificates_drop_out() {
+        let mut store = store();
+        store.update(&json!({"node": "web02", "certificates": []})).unwrap();
+        store.update(&json!({"node": "web01", "certificates": []})).unwrap();
+        assert!(store.expiry_report("2026-10-19T00:00:00Z".parse().unwrap(), 30).is_empty());
+        assert!(store.update(&json!({"certificates": []})).is_err());
+    }
+
+    #[test]
+    fn partial_scans_keep_what_they_did_not_reach() {
+        let mut store = store();
+        //Renewed www.pem, never got as far as old.pem
+        store.update(&json!({
+            "node": "web01",
+            "complete": false,
+            "certificates": [{"path": "/etc/ssl/www.pem", "subject": "CN=www.example.com", "issuer": "CN=R3", "not_after": "2027-01-20T00:00:00Z", "fingerprint": "DD"}]
+        })).unwrap();
+        let report = store.expiry_report("2026-10-19T00:00:00Z".parse().unwrap(), 30);
+        assert_eq!(report.iter().map(|e| e.fingerprint.as_str()).collect::<Vec<_>>(), vec!["BB", "AA"]);
+        assert_eq!(report[1].locations.iter().map(|l| l.node.as_str()).collect::<Vec<_>>(), vec!["web02"]);
+        store.update(&json!({"node": "web01", "complete": true, "certificates": []})).unwrap();
+        assert_eq!(store.expiry_report("2026-10-19T00:00:00Z".parse().unwrap(), 30).len(), 1);
+    }
+}
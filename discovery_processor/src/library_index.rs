@@ -0,0 +1,211 @@
+//Which nodes and services have a given shared library loaded, from the libraries records of every node. A node sends
+//one record per library under a snapshot id and then an end record with the count. A whole snapshot replaces the
+//node's libraries, one the agent's budget cut short (or which lost records on the way) only updates the libraries it
+//has.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct LibraryUser {
+    pub pid: u32,
+    pub exe: String,
+    pub unit: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Library {
+    pub path: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub package: Option<String>,
+    pub package_version: Option<String>,
+    pub deleted: bool,
+    pub processes: Vec<LibraryUser>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+struct SnapshotEnd {
+    libraries: usize,
+    complete: bool,
+}
+
+//One row per node, service and library file
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LibraryUse {
+    pub node: String,
+    //The systemd unit, or the executable for processes outside one
+    pub service: String,
+    pub path: String,
+    pub version: Option<String>,
+    pub package: Option<String>,
+    pub package_version: Option<String>,
+    pub deleted: bool,
+    pub pids: Vec<u32>,
+}
+
+//"libssl" matches libssl.so.3 and libssl-1.1.so but not libssl3helper.so, a package name matches too
+fn name_matches(library: &Library, name: &str) -> bool {
+    let file = library.name.as_str();
+    file == name
+        || file.strip_prefix(name).is_some_and(|rest| rest.starts_with('.') || rest.starts_with('-'))
+        || library.package.as_deref().is_some_and(|package| package == name || package.split(':').next() == Some(name))
+}
+
+//"3.0" matches 3.0 and 3.0.2 but not 3.01, against the file name version or the package version
+fn version_matches(library: &Library, version: &str) -> bool {
+    let matches = |candidate: &str| {
+        //Package versions can carry an epoch
+        let candidate = candidate.split_once(':').map_or(candidate, |(_, rest)| rest);
+        candidate.strip_prefix(version).is_some_and(|rest| rest.is_empty() || !rest.starts_with(|c: char| c.is_ascii_digit()))
+    };
+    library.version.as_deref().is_some_and(matches) || library.package_version.as_deref().is_some_and(matches)
+}
+
+#[derive(Default)]
+pub struct LibraryIndex {
+    nodes: HashMap<String, Vec<Library>>,
+    //Snapshot still arriving for each node, by id
+    pending: HashMap<String, (String, Vec<Library>)>,
+}
+
+impl LibraryIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, message: &Value) -> Result<String, Box<dyn Error>> {
+        let node = message.get("node").and_then(Value::as_str).ok_or("libraries record without a node")?.to_string();
+        let snapshot = message.get("snapshot").and_then(Value::as_str).ok_or("libraries record without a snapshot")?.to_string();
+        //A new snapshot means the rest of the last one isn't coming
+        if self.pending.get(&node).is_none_or(|(pending, _)| *pending != snapshot) {
+            self.pending.insert(node.clone(), (snapshot.clone(), Vec::new()));
+        }
+        let end = match message.get("snapshot_end") {
+            Some(end) => serde_json::from_value::<SnapshotEnd>(end.clone())?,
+            None => {
+                let library: Library = serde_json::from_value(message.clone())?;
+                self.pending.get_mut(&node).unwrap().1.push(library);
+                return Ok(node)
+            }
+        };
+        let (_, libraries) = self.pending.remove(&node).unwrap();
+        if end.complete && libraries.len() == end.libraries {
+            self.nodes.insert(node.clone(), libraries);
+        } else {
+            let known = self.nodes.entry(node.clone()).or_default();
+            known.retain(|library| !libraries.iter().any(|update| update.path == library.path));
+            known.extend(libraries);
+        }
+        Ok(node)
+    }
+
+    pub fn query(&self, name: &str, version: Option<&str>) -> Vec<LibraryUse> {
+        let mut uses = Vec::new();
+        for (node, libraries) in &self.nodes {
+            for library in libraries {
+                if !name_matches(library, name) || !version.is_none_or(|version| version_matches(library, version)) {
+                    continue
+                }
+                let mut services: HashMap<String, Vec<u32>> = HashMap::new();
+                for process in &library.processes {
+                    let service = process.unit.clone().unwrap_or_else(|| process.exe.clone());
+                    services.entry(service).or_default().push(process.pid);
+                }
+                for (service, mut pids) in services {
+                    pids.sort();
+                    uses.push(LibraryUse {
+                        node: node.clone(),
+                        service: service,
+                        path: library.path.clone(),
+                        version: library.version.clone(),
+                        package: library.package.clone(),
+                        package_version: library.package_version.clone(),
+                        deleted: library.deleted,
+                        pids: pids,
+                    });
+                }
+            }
+        }
+        uses.sort_by(|a, b| (&a.node, &a.service, &a.path).cmp(&(&b.node, &b.service, &b.path)));
+        uses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    //Sends a node's libraries the way the agent does
+    fn snapshot(index: &mut LibraryIndex, node: &str, snapshot: &str, libraries: Vec<Value>, complete: bool) {
+        let count = libraries.len();
+        for mut library in libraries {
+            library["node"] = json!(node);
+            library["snapshot"] = json!(snapshot);
+            index.update(&library).unwrap();
+        }
+        index.update(&json!({"node": node, "snapshot": snapshot, "snapshot_end": {"libraries": count, "complete": complete}})).unwrap();
+    }
+
+    fn index() -> LibraryIndex {
+        let mut index = LibraryIndex::new();
+        snapshot(&mut index, "web01", "a", vec![
+            json!({"path": "/usr/lib/x86_64-linux-gnu/libssl.so.3", "name": "libssl.so.3", "version": "3", "package": "libssl3", "package_version": "3.0.2-0ubuntu1.10", "processes": [
+                {"pid": 100, "exe": "/usr/sbin/nginx", "unit": "nginx.service"},
+                {"pid": 101, "exe": "/usr/sbin/nginx", "unit": "nginx.service"},
+                {"pid": 900, "exe": "/opt/tool/bin/tool"}
+            ]}),
+            json!({"path": "/usr/lib/x86_64-linux-gnu/libc.so.6", "name": "libc.so.6", "version": "6", "package": "libc6", "package_version": "2.35-0ubuntu3", "processes": [{"pid": 100, "exe": "/usr/sbin/nginx", "unit": "nginx.service"}]}),
+        ], true);
+        snapshot(&mut index, "db01", "b", vec![
+            json!({"path": "/usr/lib64/libssl.so.1.1", "name": "libssl.so.1.1", "version": "1.1", "package": "openssl-libs", "package_version": "1:1.1.1k-9.el8", "deleted": true, "processes": [{"pid": 7, "exe": "/usr/bin/postgres", "unit": "postgresql.service"}]}),
+        ], true);
+        index
+    }
+
+    #[test]
+    fn hosts_and_services_with_a_library() {
+        let uses = index().query("libssl", None);
+        let rows: Vec<_> = uses.iter().map(|u| (u.node.as_str(), u.service.as_str(), u.pids.clone())).collect();
+        assert_eq!(rows, vec![("db01", "postgresql.service", vec![7]), ("web01", "/opt/tool/bin/tool", vec![900]), ("web01", "nginx.service", vec![100, 101])]);
+        assert!(uses[0].deleted);
+    }
+
+    #[test]
+    fn versions_match_on_boundaries() {
+        let index = index();
+        assert_eq!(index.query("libssl", Some("3.0.2")).len(), 2);
+        assert_eq!(index.query("libssl", Some("1.1.1k")).iter().map(|u| u.node.as_str()).collect::<Vec<_>>(), vec!["db01"]);
+        assert!(index.query("libssl", Some("3.0.20")).is_empty());
+        assert_eq!(index.query("libc6", None).len(), 1);
+        assert!(index.query("libs", None).is_empty());
+        assert!(LibraryIndex::new().update(&json!({"snapshot": "a"})).is_err());
+        assert!(LibraryIndex::new().update(&json!({"node": "web01", "path": "/lib/libz.so.1"})).is_err());
+    }
+
+    #[test]
+    fn only_whole_snapshots_replace_a_node() {
+        let mut index = index();
+        let libc = json!({"path": "/usr/lib/x86_64-linux-gnu/libc.so.6", "name": "libc.so.6", "version": "6", "processes": [{"pid": 300, "exe": "/usr/bin/redis-server"}]});
+        //Cut short before reaching nginx, libssl stays and libc picks up redis
+        snapshot(&mut index, "web01", "c", vec![libc.clone()], false);
+        assert_eq!(index.query("libssl", None).len(), 3);
+        assert_eq!(index.query("libc", None).iter().map(|u| u.service.as_str()).collect::<Vec<_>>(), vec!["/usr/bin/redis-server"]);
+        //Still arriving, nothing changes until the end record
+        let mut pending = libc.clone();
+        pending["node"] = json!("web01");
+        pending["snapshot"] = json!("d");
+        index.update(&pending).unwrap();
+        assert_eq!(index.query("libssl", None).len(), 3);
+        //A record went missing, so the count doesn't add up and it's only merged
+        index.update(&json!({"node": "web01", "snapshot": "d", "snapshot_end": {"libraries": 2, "complete": true}})).unwrap();
+        assert_eq!(index.query("libssl", None).len(), 3);
+        snapshot(&mut index, "web01", "e", vec![libc], true);
+        assert_eq!(index.query("libssl", None).iter().map(|u| u.node.as_str()).collect::<Vec<_>>(), vec!["db01"]);
+    }
+}
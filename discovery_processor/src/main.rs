@@ -7,8 +7,27 @@ use rdkafka::error::KafkaResult;
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::topic_partition_list::TopicPartitionList;
 use rdkafka::util::get_rdkafka_version;
-use rdkafka::Message;
+use rdkafka::{Message, Offset};
 use log::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+mod certificate_expiry;
+mod library_index;
+mod ssh_trust;
+
+use certificate_expiry::CertificateStore;
+use chrono::Utc;
+use library_index::LibraryIndex;
+use serde_json::Value;
+use ssh_trust::{TrustEdge, TrustGraph};
+
+//Certificates expiring within this many days make the expiry report
+const EXPIRY_WARN_DAYS: i64 = 30;
+//For the metadata and watermark requests a replay starts with
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+//A replay gives up on the partitions it hasn't finished once no record has arrived for this long
+const REPLAY_QUIET_TIME: Duration = Duration::from_secs(10);
 
 struct LoggingConsumerContext;
 
@@ -25,7 +44,7 @@ impl ConsumerContext for LoggingConsumerContext {
 
 type LoggingConsumer = StreamConsumer<LoggingConsumerContext>;
 
-fn create_consumer(brokers: &str, group_id: &str, topic: &str) -> LoggingConsumer {
+fn create_consumer(brokers: &str, group_id: &str, topics: &[&str]) -> LoggingConsumer {
     let context = LoggingConsumerContext;
 
     let consumer: LoggingConsumer = ClientConfig::new()
@@ -43,33 +62,211 @@ fn create_consumer(brokers: &str, group_id: &str, topic: &str) -> LoggingConsume
         .expect("Consumer creation failed");
 
     consumer
-        .subscribe(&[topic])
-        .expect("Can't subscribe to specified topic");
+        .subscribe(topics)
+        .expect("Can't subscribe to specified topics");
 
     consumer
 }
 
+//Reads topics from the start, for state which is only kept in memory. The partitions are assigned rather than
+//subscribed to, so no group is joined and nothing is committed. Returns the offset each partition has to reach to
+//have caught up with the topic as it was at the start, empty partitions are left out.
+fn create_reader(brokers: &str, topics: &[&str]) -> KafkaResult<(StreamConsumer, HashMap<(String, i32), i64>)> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        //librdkafka wants one even for assigned partitions, it's never joined
+        .set("group.id", "discovery-query")
+        .set("bootstrap.servers", brokers)
+        .set("enable.partition.eof", "false")
+        .set("enable.auto.commit", "false")
+        .set("enable.auto.offset.store", "false")
+        .create()?;
+
+    let mut assignment = TopicPartitionList::new();
+    let mut ends = HashMap::new();
+    for topic in topics {
+        let metadata = consumer.fetch_metadata(Some(topic), REPLAY_TIMEOUT)?;
+        for partition in metadata.topics().iter().flat_map(|t| t.partitions()) {
+            let (low, high) = consumer.fetch_watermarks(topic, partition.id(), REPLAY_TIMEOUT)?;
+            assignment.add_partition_offset(topic, partition.id(), Offset::Offset(low))?;
+            if high > low {
+                ends.insert((topic.to_string(), partition.id()), high);
+            }
+        }
+    }
+    consumer.assign(&assignment)?;
+
+    Ok((consumer, ends))
+}
+
+//Hands every record to handle until each partition has caught up with its end
+async fn replay(consumer: &StreamConsumer, mut ends: HashMap<(String, i32), i64>, mut handle: impl FnMut(&str, &str)) {
+    while !ends.is_empty() {
+        let received = match tokio::time::timeout(REPLAY_QUIET_TIME, consumer.recv()).await {
+            Ok(received) => received,
+            Err(_) => {
+                warn!("Gave up replaying {} partitions, some nodes may be missing until they report again", ends.len());
+                return
+            }
+        };
+        match received {
+            Err(e) => warn!("Kafka error: {}", e),
+            Ok(m) => {
+                let partition = (m.topic().to_string(), m.partition());
+                if ends.get(&partition).is_some_and(|end| m.offset() + 1 >= *end) {
+                    ends.remove(&partition);
+                }
+                if let Some(Ok(payload)) = m.payload_view::<str>() {
+                    handle(m.topic(), payload);
+                }
+            }
+        }
+    }
+}
+
+fn create_producer(brokers: &str) -> FutureProducer {
+    ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("message.timeout.ms", "5000")
+        .create()
+        .expect("Producer creation failed")
+}
+
+fn parse_record(payload: &str) -> Option<Value> {
+    match serde_json::from_str(payload) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            warn!("Unreadable record: {}", e);
+            None
+        }
+    }
+}
+
+fn update_trust_graph(graph: &mut TrustGraph, payload: &str) {
+    if let Some(Err(e)) = parse_record(payload).map(|record| graph.update(&record)) {
+        warn!("Ignoring ssh record: {}", e);
+    }
+}
+
+//Each node's edges go out keyed by that node. Every node whose edges changed since they last went out is sent again,
+//so B dropping A's key clears the edge from A's message as well as B's.
+fn ssh_trust_edges(graph: &TrustGraph, published: &mut HashMap<String, Vec<TrustEdge>>) -> Vec<(String, String)> {
+    let mut changed = Vec::new();
+    for (node, edges) in graph.edges_by_node() {
+        if published.get(&node) != Some(&edges) {
+            info!("{} ssh trust edges touch {}", edges.len(), node);
+            changed.push((node.clone(), serde_json::json!({"node": node, "edges": edges}).to_string()));
+            published.insert(node, edges);
+        }
+    }
+    changed
+}
+
+fn update_certificates(store: &mut CertificateStore, payload: &str) {
+    if let Some(Err(e)) = parse_record(payload).map(|record| store.update(&record)) {
+        warn!("Ignoring certificates record: {}", e);
+    }
+}
+
+//The whole fleet's report goes out whenever any node's certificates change
+fn certificate_expiry_report(store: &CertificateStore) -> (String, String) {
+    let now = Utc::now();
+    let report = store.expiry_report(now, EXPIRY_WARN_DAYS);
+    info!("{} certificates expired or expiring within {} days", report.len(), EXPIRY_WARN_DAYS);
+    ("fleet".to_string(), serde_json::json!({"generated_at": now, "warn_days": EXPIRY_WARN_DAYS, "certificates": report}).to_string())
+}
+
+async fn publish(producer: &FutureProducer, topic: &str, records: Vec<(String, String)>) {
+    for (key, body) in records {
+        if let Err((e, _)) = producer.send(FutureRecord::to(topic).key(&key).payload(&body), Duration::from_secs(5)).await {
+            warn!("Failed to publish to {}: {}", topic, e);
+        }
+    }
+}
+
+//Replays every node's libraries records up to the end of the topic, then prints where the library is loaded
+async fn query_library(brokers: &str, name: &str, version: Option<&str>) {
+    let (consumer, ends) = match create_reader(brokers, &["mqtt.nodes.libraries"]) {
+        Ok(reader) => reader,
+        Err(e) => {
+            error!("Cannot read the libraries topic: {}", e);
+            return
+        }
+    };
+    let mut index = LibraryIndex::new();
+    replay(&consumer, ends, |_, payload| {
+        if let Some(Err(e)) = parse_record(payload).map(|record| index.update(&record)) {
+            warn!("Ignoring libraries record: {}", e);
+        }
+    }).await;
+    let uses = index.query(name, version);
+    info!("{} node and service pairs have {} {} loaded", uses.len(), name, version.unwrap_or(""));
+    println!("{}", serde_json::to_string_pretty(&uses).unwrap());
+}
+
 #[tokio::main]
 async fn main() {
-    let input_topic = "mqtt.agents";
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("query-library") {
+        match args.get(2) {
+            Some(name) => query_library("localhost:9092", name, args.get(3).map(String::as_str)).await,
+            None => {
+                eprintln!("usage: {} query-library <name> [version]", args[0]);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    let agents_topic = "mqtt.agents";
+    let ssh_topic = "mqtt.nodes.ssh";
+    let certificates_topic = "mqtt.nodes.certificates";
+    let trust_topic = "discovery.ssh_trust";
+    let expiry_topic = "discovery.certificate_expiry";
     let brokers = "localhost:9092";
     let group_id = "discovery";
-    let consumer = create_consumer(brokers, group_id, input_topic);
+    let consumer = create_consumer(brokers, group_id, &[agents_topic]);
+    let producer = create_producer(brokers);
+    let mut trust_graph = TrustGraph::new();
+    let mut published_edges = HashMap::new();
+    let mut certificate_store = CertificateStore::new();
+    //The trust graph and certificate store only live in memory, so they're rebuilt from the start of their topics
+    //before anything is published. The same reader then carries on with new records.
+    let (reader, ends) = create_reader(brokers, &[ssh_topic, certificates_topic]).expect("Can't read the node topics");
+    replay(&reader, ends, |topic, payload| {
+        if topic == ssh_topic {
+            update_trust_graph(&mut trust_graph, payload);
+        } else {
+            update_certificates(&mut certificate_store, payload);
+        }
+    }).await;
+    publish(&producer, trust_topic, ssh_trust_edges(&trust_graph, &mut published_edges)).await;
+    publish(&producer, expiry_topic, vec![certificate_expiry_report(&certificate_store)]).await;
     println!("Starting");
     loop {
-        match consumer.recv().await {
-            Err(e) => warn!("Kafka error: {}", e),
-            Ok(m) => {
-                let payload = match m.payload_view::<str>() {
-                    None => "",
-                    Some(Ok(s)) => s,
-                    Some(Err(e)) => {
-                        warn!("Error while deserialzing message payload {:?}", e);
-                        ""
+        tokio::select! {
+            received = consumer.recv() => match received {
+                Err(e) => warn!("Kafka error: {}", e),
+                Ok(m) => match m.payload_view::<str>() {
+                    Some(Ok(payload)) => println!("{}", payload),
+                    Some(Err(e)) => warn!("Error while deserialzing message payload {:?}", e),
+                    None => {}
+                },
+            },
+            received = reader.recv() => match received {
+                Err(e) => warn!("Kafka error: {}", e),
+                Ok(m) => {
+                    let payload = match m.payload_view::<str>() {
+                        Some(Ok(payload)) => payload,
+                        _ => continue,
+                    };
+                    if m.topic() == ssh_topic {
+                        update_trust_graph(&mut trust_graph, payload);
+                        publish(&producer, trust_topic, ssh_trust_edges(&trust_graph, &mut published_edges)).await;
+                    } else {
+                        update_certificates(&mut certificate_store, payload);
+                        publish(&producer, expiry_topic, vec![certificate_expiry_report(&certificate_store)]).await;
                     }
-                };
-                println!("{}",payload);
-            }
+                }
+            },
         }
     }
 }
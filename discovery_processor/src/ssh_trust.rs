@@ -0,0 +1,224 @@
+//"Host A can SSH to host B" edges from the ssh records of every node. A client public key on A whose fingerprint is in
+//an authorized_keys file on B lets that user on A log in to B. A's known_hosts holding B's host key (or naming it)
+//only says A has connected to B before, that's the weaker known_host edge.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Key {
+    pub user: Option<String>,
+    pub fingerprint: String,
+    pub options: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct KnownHost {
+    pub user: Option<String>,
+    pub marker: Option<String>,
+    pub hosts: Vec<String>,
+    pub fingerprint: String,
+}
+
+//The parts of the node agent's ssh record the graph needs
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct NodeSsh {
+    pub host_names: Vec<String>,
+    pub host_keys: Vec<Key>,
+    pub client_keys: Vec<Key>,
+    pub authorized_keys: Vec<Key>,
+    pub known_hosts: Vec<KnownHost>,
+    //Records from agents older than the flag are whole
+    #[serde(default = "whole")]
+    pub complete: bool,
+}
+
+fn whole() -> bool {
+    true
+}
+
+impl NodeSsh {
+    //Fills in the accounts a cut short record didn't get to from what we had before. Accounts it did get to, and
+    //everything not belonging to an account, are taken from the record.
+    fn merge_previous(&mut self, previous: NodeSsh) {
+        let mut reached: BTreeSet<String> = BTreeSet::new();
+        reached.extend(self.client_keys.iter().chain(&self.authorized_keys).filter_map(|key| key.user.clone()));
+        reached.extend(self.known_hosts.iter().filter_map(|known| known.user.clone()));
+        let unreached = |user: &Option<String>| user.as_ref().is_some_and(|user| !reached.contains(user));
+        self.client_keys.extend(previous.client_keys.into_iter().filter(|key| unreached(&key.user)));
+        self.authorized_keys.extend(previous.authorized_keys.into_iter().filter(|key| unreached(&key.user)));
+        self.known_hosts.extend(previous.known_hosts.into_iter().filter(|known| unreached(&known.user)));
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TrustEdge {
+    //authorized_key or known_host
+    pub kind: String,
+    pub from_node: String,
+    //None when the system wide known_hosts file is the evidence
+    pub from_user: Option<String>,
+    pub to_node: String,
+    pub to_user: Option<String>,
+    pub fingerprint: String,
+    //authorized_keys options such as from= and command= which restrict the login
+    pub options: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct TrustGraph {
+    nodes: HashMap<String, NodeSsh>,
+}
+
+impl TrustGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //A complete ssh record is the whole picture for its node so it replaces what we had, a cut short one keeps the
+    //accounts it didn't reach
+    pub fn update(&mut self, message: &Value) -> Result<String, Box<dyn Error>> {
+        let node = message.get("node").and_then(Value::as_str).ok_or("ssh record without a node")?.to_string();
+        let mut record: NodeSsh = serde_json::from_value(message.clone())?;
+        if !record.complete {
+            if let Some(previous) = self.nodes.remove(&node) {
+                record.merge_previous(previous);
+            }
+        }
+        self.nodes.insert(node.clone(), record);
+        Ok(node)
+    }
+
+    pub fn edges(&self) -> Vec<TrustEdge> {
+        let mut edges = BTreeSet::new();
+        for (from_node, from) in &self.nodes {
+            for (to_node, to) in &self.nodes {
+                if from_node == to_node {
+                    continue;
+                }
+                for client_key in &from.client_keys {
+                    for authorized in to.authorized_keys.iter().filter(|key| key.fingerprint == client_key.fingerprint) {
+                        edges.insert(TrustEdge {
+                            kind: "authorized_key".to_string(),
+                            from_node: from_node.clone(),
+                            from_user: client_key.user.clone(),
+                            to_node: to_node.clone(),
+                            to_user: authorized.user.clone(),
+                            fingerprint: authorized.fingerprint.clone(),
+                            options: authorized.options.clone(),
+                        });
+                    }
+                }
+                //Revoked and CA entries don't say anything about a particular host
+                for known in from.known_hosts.iter().filter(|known| known.marker.is_none()) {
+                    let by_key = to.host_keys.iter().any(|key| key.fingerprint == known.fingerprint);
+                    let by_name = known.hosts.iter().any(|host| to.host_names.iter().any(|name| name.eq_ignore_ascii_case(host)));
+                    if by_key || by_name {
+                        edges.insert(TrustEdge {
+                            kind: "known_host".to_string(),
+                            from_node: from_node.clone(),
+                            from_user: known.user.clone(),
+                            to_node: to_node.clone(),
+                            to_user: None,
+                            fingerprint: known.fingerprint.clone(),
+                            options: Vec::new(),
+                        });
+                    }
+                }
+            }
+        }
+        edges.into_iter().collect()
+    }
+
+    //The edges at either end of each node. Every node we have a record for is in there, with no edges left if need be.
+    pub fn edges_by_node(&self) -> BTreeMap<String, Vec<TrustEdge>> {
+        let mut by_node: BTreeMap<String, Vec<TrustEdge>> = self.nodes.keys().map(|node| (node.clone(), Vec::new())).collect();
+        for edge in self.edges() {
+            by_node.entry(edge.to_node.clone()).or_default().push(edge.clone());
+            by_node.entry(edge.from_node.clone()).or_default().push(edge);
+        }
+        by_node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn graph() -> TrustGraph {
+        let mut graph = TrustGraph::new();
+        graph.update(&json!({
+            "node": "build01",
+            "host_names": ["build01", "10.0.0.5"],
+            "host_keys": [{"user": null, "fingerprint": "SHA256:build01-host"}],
+            "client_keys": [{"user": "deploy", "fingerprint": "SHA256:deploy"}],
+            "known_hosts": [
+                {"user": "deploy", "hosts": [], "fingerprint": "SHA256:web01-host"},
+                {"user": "deploy", "marker": "@cert-authority", "hosts": ["*"], "fingerprint": "SHA256:ca"}
+            ]
+        })).unwrap();
+        graph.update(&json!({
+            "node": "web01",
+            "host_names": ["web01", "10.0.0.12"],
+            "host_keys": [{"fingerprint": "SHA256:web01-host"}],
+            "authorized_keys": [{"user": "www", "fingerprint": "SHA256:deploy", "options": ["from=\"10.0.0.5\""]}],
+            "known_hosts": [{"user": "root", "hosts": ["10.0.0.5"], "fingerprint": "SHA256:old-key"}]
+        })).unwrap();
+        graph
+    }
+
+    #[test]
+    fn matching_fingerprints_make_edges() {
+        let edges = graph().edges();
+        assert_eq!(edges.len(), 3);
+        let login = edges.iter().find(|e| e.kind == "authorized_key").unwrap();
+        assert_eq!((login.from_node.as_str(), login.from_user.as_deref(), login.to_node.as_str(), login.to_user.as_deref()), ("build01", Some("deploy"), "web01", Some("www")));
+        assert_eq!(login.options, vec!["from=\"10.0.0.5\""]);
+        //Hashed known_hosts entries still match on the host key, plain ones on the name
+        assert!(edges.iter().any(|e| e.kind == "known_host" && e.from_node == "build01" && e.to_node == "web01"));
+        assert!(edges.iter().any(|e| e.kind == "known_host" && e.from_node == "web01" && e.to_node == "build01"));
+    }
+
+    #[test]
+    fn updates_replace_a_nodes_record() {
+        let mut graph = graph();
+        graph.update(&json!({"node": "web01", "host_keys": [{"fingerprint": "SHA256:web01-host"}]})).unwrap();
+        let edges = graph.edges();
+        assert_eq!(edges.len(), 1);
+        assert_eq!((edges[0].kind.as_str(), edges[0].from_node.as_str(), edges[0].to_node.as_str()), ("known_host", "build01", "web01"));
+        assert!(graph.update(&json!({"host_names": []})).is_err());
+    }
+
+    #[test]
+    fn cut_short_records_keep_unreached_accounts() {
+        let mut graph = graph();
+        //Got as far as root on web01 but not www
+        graph.update(&json!({
+            "node": "web01",
+            "complete": false,
+            "host_names": ["web01", "10.0.0.12"],
+            "host_keys": [{"fingerprint": "SHA256:web01-host"}],
+            "known_hosts": [{"user": "root", "hosts": ["db01"], "fingerprint": "SHA256:db01-host"}]
+        })).unwrap();
+        let edges = graph.edges();
+        assert!(edges.iter().any(|e| e.kind == "authorized_key" && e.to_user.as_deref() == Some("www")));
+        //root's old entry pointing at build01 was replaced
+        assert!(!edges.iter().any(|e| e.from_node == "web01"));
+    }
+
+    #[test]
+    fn edges_are_listed_at_both_ends() {
+        let mut graph = graph();
+        assert_eq!(graph.edges_by_node().values().map(Vec::len).collect::<Vec<_>>(), vec![3, 3]);
+        //web01 drops build01's key, build01's list loses the login too
+        graph.update(&json!({"node": "web01", "host_names": ["web01"], "host_keys": [{"fingerprint": "SHA256:web01-host"}]})).unwrap();
+        let by_node = graph.edges_by_node();
+        assert_eq!(by_node["build01"].iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(), vec!["known_host"]);
+        assert_eq!(by_node["web01"], by_node["build01"]);
+    }
+}
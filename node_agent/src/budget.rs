@@ -0,0 +1,256 @@
+//Resource limits for collection so a full walk of /proc doesn't show up as a spike on busy servers. The agent drops its
+//CPU and IO priority once at start up (scripts inherit it) and each collector run gets a Budget which paces its scans
+//and stops it once its time is up.
+use log::*;
+use node_agent::lifecycle::Shutdown;
+use serde::Deserialize;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//Don't bother sleeping for less than this, we catch up on the next tick instead
+const MIN_PACING_SLEEP: Duration = Duration::from_millis(10);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    //Only gets disk time when nothing else wants it
+    Idle,
+    //Normal scheduling at the given io_priority (0 highest, 7 lowest)
+    BestEffort,
+    //Leave the IO priority alone
+    Unchanged,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CollectorBudget {
+    pub scan_rate: Option<u32>,
+    pub time_budget_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BudgetConfig {
+    //Niceness for the whole agent, 0 leaves it alone
+    pub nice: i32,
+    pub io_class: IoClass,
+    pub io_priority: u8,
+    //Default limits for every collector, entries per second and seconds per run
+    pub scan_rate: Option<u32>,
+    pub time_budget_secs: Option<u64>,
+    //Per collector overrides, by collector name
+    pub collectors: HashMap<String, CollectorBudget>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        Self {
+            nice: 10,
+            io_class: IoClass::BestEffort,
+            io_priority: 7,
+            scan_rate: None,
+            time_budget_secs: None,
+            collectors: HashMap::new(),
+        }
+    }
+}
+
+impl BudgetConfig {
+    pub fn for_collector(&self, name: &str, shutdown: &Shutdown) -> Budget {
+        let overrides = self.collectors.get(name).cloned().unwrap_or_default();
+        let scan_rate = overrides.scan_rate.or(self.scan_rate);
+        let time_budget = overrides.time_budget_secs.or(self.time_budget_secs).map(Duration::from_secs);
+        Budget::new(scan_rate, time_budget).with_shutdown(shutdown.clone())
+    }
+}
+
+//Drops the CPU and IO priority of the calling thread, threads and processes started afterwards inherit it
+pub fn lower_priority(config: &BudgetConfig) -> io::Result<()> {
+    if config.nice != 0 {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, config.nice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        info!("Running at nice {}", config.nice);
+    }
+    let (class, data) = match config.io_class {
+        IoClass::Idle => (3, 0),
+        IoClass::BestEffort => (2, config.io_priority.min(7) as i32),
+        IoClass::Unchanged => return Ok(()),
+    };
+    //ioprio_set(IOPRIO_WHO_PROCESS, 0, IOPRIO_PRIO_VALUE(class, data)), there is no libc wrapper for it
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, 1, 0, (class << 13) | data) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    info!("Running with IO class {:?} priority {}", config.io_class, data);
+    Ok(())
+}
+
+//Limits for a single collector run. Scanning code calls tick() once per entry (a /proc directory, an fd...) and stops
+//as soon as it returns false. Collectors only run on the main thread so plain Cells are enough.
+pub struct Budget {
+    per_entry: Option<Duration>,
+    deadline: Option<Instant>,
+    time_budget: Option<Duration>,
+    shutdown: Option<Shutdown>,
+    //Called on every check so a long collection keeps the systemd watchdog fed
+    heartbeat: Option<Rc<dyn Fn()>>,
+    next_slot: Cell<Option<Instant>>,
+    entries: Cell<u64>,
+    cut_short: Cell<Option<&'static str>>,
+}
+
+impl Budget {
+    pub fn new(scan_rate: Option<u32>, time_budget: Option<Duration>) -> Self {
+        Self {
+            per_entry: scan_rate.filter(|rate| *rate > 0).map(|rate| Duration::from_secs(1) / rate),
+            deadline: time_budget.map(|budget| Instant::now() + budget),
+            time_budget: time_budget,
+            shutdown: None,
+            heartbeat: None,
+            next_slot: Cell::new(None),
+            entries: Cell::new(0),
+            cut_short: Cell::new(None),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Rc<dyn Fn()>) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    //Accounts for one scanned entry, sleeping if we're going faster than the scan rate. Returns false once the
+    //collector should stop.
+    pub fn tick(&self) -> bool {
+        if !self.check() {
+            return false
+        }
+        self.entries.set(self.entries.get() + 1);
+        if let Some(per_entry) = self.per_entry {
+            let now = Instant::now();
+            let slot = self.next_slot.get().unwrap_or(now).max(now - MIN_PACING_SLEEP) + per_entry;
+            self.next_slot.set(Some(slot));
+            if slot > now + MIN_PACING_SLEEP {
+                thread::sleep(slot - now);
+            }
+        }
+        true
+    }
+
+    //Whether the collector may carry on, without counting an entry
+    pub fn check(&self) -> bool {
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat();
+        }
+        if self.cut_short.get().is_some() {
+            return false
+        }
+        if self.shutdown.as_ref().map_or(false, |shutdown| shutdown.requested()) {
+            self.cut_short.set(Some("shutdown requested"));
+            return false
+        }
+        if self.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            self.cut_short.set(Some("time budget exceeded"));
+            return false
+        }
+        true
+    }
+
+    //Time left before the deadline, if there is one
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn time_budget(&self) -> Option<Duration> {
+        self.time_budget
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries.get()
+    }
+
+    //Why the collection stopped early, None if it ran to completion
+    pub fn cut_short(&self) -> Option<&'static str> {
+        self.cut_short.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_budget_never_stops() {
+        let budget = Budget::unlimited();
+        for _ in 0..10000 {
+            assert!(budget.tick());
+        }
+        assert_eq!(budget.entries(), 10000);
+        assert!(budget.cut_short().is_none());
+    }
+
+    #[test]
+    fn heartbeat_runs_while_scanning() {
+        let beats = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&beats);
+        let budget = Budget::unlimited().with_heartbeat(Rc::new(move || counter.set(counter.get() + 1)));
+        for _ in 0..5 {
+            budget.tick();
+        }
+        assert_eq!(beats.get(), 5);
+    }
+
+    #[test]
+    fn scan_rate_paces_entries() {
+        let budget = Budget::new(Some(200), None);
+        let started = Instant::now();
+        for _ in 0..40 {
+            budget.tick();
+        }
+        //40 entries at 200 per second is 200ms, allow for the catch up window
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn time_budget_cuts_collection_short() {
+        let budget = Budget::new(Some(100), Some(Duration::from_millis(50)));
+        let mut scanned = 0;
+        while budget.tick() {
+            scanned += 1;
+        }
+        assert!(scanned < 100);
+        assert_eq!(budget.cut_short(), Some("time budget exceeded"));
+    }
+
+    #[test]
+    fn shutdown_stops_collection() {
+        let shutdown = Shutdown::new();
+        let budget = Budget::unlimited().with_shutdown(shutdown.clone());
+        assert!(budget.tick());
+        shutdown.request();
+        assert!(!budget.tick());
+        assert_eq!(budget.cut_short(), Some("shutdown requested"));
+    }
+
+    #[test]
+    fn collector_overrides_win() {
+        let config: BudgetConfig = serde_json::from_str(r#"{"scan_rate":1000,"time_budget_secs":60,"collectors":{"processes":{"time_budget_secs":5}}}"#).unwrap();
+        assert_eq!(config.io_class, IoClass::BestEffort);
+        let budget = config.for_collector("processes", &Shutdown::new());
+        assert_eq!(budget.time_budget(), Some(Duration::from_secs(5)));
+        let budget = config.for_collector("listeners", &Shutdown::new());
+        assert_eq!(budget.time_budget(), Some(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,516 @@
+//Collectors are the pluggable data sources of the agent. Each one declares a name, how often it wants to run and
+//what privileges it needs, and hands back records which main wraps in the standard envelope and publishes.
+use crate::budget::{Budget, BudgetConfig};
+use node_agent::inventory_client::AgentInfo;
+use node_agent::lifecycle::Shutdown;
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub mod accounts;
+pub mod banners;
+pub mod certificates;
+pub mod dns;
+pub mod executables;
+pub mod hardware;
+pub mod interfaces;
+pub mod jobs;
+pub mod libraries;
+pub mod neighbours;
+pub mod network;
+pub mod node;
+pub mod os;
+pub mod packages;
+pub mod probing;
+pub mod process_tree;
+pub mod processes;
+pub mod routes;
+pub mod script;
+pub mod ssh;
+pub mod storage;
+pub mod tls;
+pub mod units;
+pub mod virt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    //Runs on the first pass only
+    Once,
+    //Runs on the first pass and then again each time the interval has passed (daemon mode)
+    Every(Duration),
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Once => write!(f, "once"),
+            Schedule::Every(interval) => write!(f, "every {}s", interval.as_secs()),
+        }
+    }
+}
+
+//What a collector needs to see everything. Collectors still run without it but their results will be partial.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Privilege {
+    Root,
+    Capability(&'static str),
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Privilege::Root => write!(f, "root"),
+            Privilege::Capability(cap) => write!(f, "{}", cap),
+        }
+    }
+}
+
+//A single message produced by a collector. The topic is relative to /nodes/<agent_id>, an empty topic is the node record itself.
+#[derive(Debug)]
+pub struct Record {
+    pub topic: String,
+    pub payload: Value,
+}
+
+impl Record {
+    pub fn new<T: Serialize>(topic: &str, data: &T) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            topic: topic.to_string(),
+            payload: serde_json::to_value(data)?,
+        })
+    }
+
+    pub fn mqtt_topic(&self, agent_id: &str) -> String {
+        if self.topic.is_empty() {
+            format!("/nodes/{}", agent_id)
+        } else {
+            format!("/nodes/{}/{}", agent_id, self.topic)
+        }
+    }
+
+    //Adds the standard envelope fields so every message can be tied back to its node, run and collector
+    pub fn into_envelope(self, agent: &AgentInfo, collector: &str) -> Value {
+        let mut envelope = match self.payload {
+            Value::Object(map) => map,
+            other => {
+                let mut map = serde_json::Map::new();
+                map.insert("data".to_string(), other);
+                map
+            }
+        };
+        envelope.insert("correlation_id".to_string(), Value::String(agent.correlation_id.to_string()));
+        envelope.insert("node".to_string(), Value::String(agent.agent_id.clone()));
+        envelope.insert("collector".to_string(), Value::String(collector.to_string()));
+        envelope.insert("collected_at".to_string(), Value::String(Utc::now().to_rfc3339()));
+        Value::Object(envelope)
+    }
+}
+
+pub trait Collector {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    fn schedule(&self) -> Schedule;
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![]
+    }
+    //Long scans should call budget.tick() per entry and stop when it says so, whatever was gathered is still published
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>>;
+    //Problems from the last run which didn't stop it producing records, reported in its status record
+    fn take_warnings(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+//Published on /nodes/<agent_id>/collector_status after every run so failing or partial collectors are visible upstream
+#[derive(Serialize, Debug)]
+pub struct CollectorStatus {
+    pub status: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u128,
+    pub records: usize,
+    pub errors: Vec<String>,
+    //Set when a resource budget or shutdown stopped the collector before it finished
+    pub cut_short: Option<String>,
+    pub entries_scanned: u64,
+}
+
+//Summary of a registered collector for `node_agent collectors`
+pub struct CollectorInfo {
+    pub name: String,
+    pub description: String,
+    pub schedule: Schedule,
+    pub privileges: Vec<Privilege>,
+    pub enabled: bool,
+}
+
+struct Entry {
+    collector: Box<dyn Collector>,
+    enabled: bool,
+    interval: Option<Duration>,
+    last_run: Option<Instant>,
+}
+
+impl Entry {
+    fn schedule(&self) -> Schedule {
+        match (self.collector.schedule(), self.interval) {
+            (Schedule::Every(_), Some(interval)) => Schedule::Every(interval),
+            (schedule, _) => schedule,
+        }
+    }
+    fn next_run(&self) -> Option<Instant> {
+        match (self.last_run, self.schedule()) {
+            (None, _) => Some(Instant::now()),
+            (Some(_), Schedule::Once) => None,
+            (Some(last), Schedule::Every(interval)) => Some(last + interval),
+        }
+    }
+}
+
+pub struct Registry {
+    entries: Vec<Entry>,
+    heartbeat: Option<Rc<dyn Fn()>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self { entries: Vec::new(), heartbeat: None }
+    }
+
+    //Handed to every collector's budget, main uses it to ping the systemd watchdog during long collections
+    pub fn set_heartbeat(&mut self, heartbeat: Rc<dyn Fn()>) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|entry| entry.collector.name() == name)
+    }
+
+    pub fn register(&mut self, collector: Box<dyn Collector>, enabled: bool) {
+        debug!("Registering collector {} (enabled: {})", collector.name(), enabled);
+        self.entries.push(Entry {
+            collector: collector,
+            enabled: enabled,
+            interval: None,
+            last_run: None,
+        });
+    }
+
+    fn entry_mut(&mut self, name: &str) -> Result<&mut Entry, Box<dyn Error>> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.collector.name() == name)
+            .ok_or_else(|| format!("Unknown collector '{}', run `node_agent collectors` to list them", name).into())
+    }
+
+    //Swaps a registered collector for one built from config, keeping whether it's enabled and its interval
+    pub fn replace(&mut self, collector: Box<dyn Collector>) -> Result<(), Box<dyn Error>> {
+        let name = collector.name().to_string();
+        self.entry_mut(&name)?.collector = collector;
+        Ok(())
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), Box<dyn Error>> {
+        self.entry_mut(name)?.enabled = enabled;
+        Ok(())
+    }
+
+    //Overrides the run interval of a scheduled collector
+    pub fn set_interval(&mut self, name: &str, interval: Duration) -> Result<(), Box<dyn Error>> {
+        let entry = self.entry_mut(name)?;
+        if entry.collector.schedule() == Schedule::Once {
+            warn!("Collector {} only runs once, ignoring interval", name);
+        }
+        entry.interval = Some(interval);
+        Ok(())
+    }
+
+    //Enables then disables, so a collector named in both lists ends up disabled
+    pub fn apply(&mut self, enable: &[String], disable: &[String]) -> Result<(), Box<dyn Error>> {
+        for name in enable {
+            self.set_enabled(name, true)?;
+        }
+        for name in disable {
+            self.set_enabled(name, false)?;
+        }
+        Ok(())
+    }
+
+    pub fn available(&self) -> Vec<CollectorInfo> {
+        self.entries
+            .iter()
+            .map(|entry| CollectorInfo {
+                name: entry.collector.name().to_string(),
+                description: entry.collector.description().to_string(),
+                schedule: entry.schedule(),
+                privileges: entry.collector.privileges(),
+                enabled: entry.enabled,
+            })
+            .collect()
+    }
+
+    //When the next enabled collector wants to run, None once nothing is left to do
+    pub fn next_due(&self) -> Option<Instant> {
+        self.entries.iter().filter(|entry| entry.enabled).filter_map(|entry| entry.next_run()).min()
+    }
+
+    //Runs every enabled collector which is due, handing each one's records to `publish` as soon as it finishes,
+    //followed by its status record. A failing collector is reported and skipped so it can't take the others down with it.
+    pub fn run_due<F>(&mut self, shutdown: &Shutdown, budgets: &BudgetConfig, mut publish: F)
+    where
+        F: FnMut(&str, Vec<Record>),
+    {
+        let privileged = running_as_root();
+        for entry in self.entries.iter_mut().filter(|entry| entry.enabled) {
+            if shutdown.requested() {
+                info!("Shutdown requested, skipping remaining collectors");
+                break;
+            }
+            match entry.next_run() {
+                Some(next) if next <= Instant::now() => {}
+                _ => continue,
+            }
+            let name = entry.collector.name().to_string();
+            if !privileged && !entry.collector.privileges().is_empty() {
+                warn!("Collector {} wants {:?} but the agent is not running as root, results may be incomplete", name, entry.collector.privileges());
+            }
+            debug!("Running collector {}", name);
+            let started_at = Utc::now();
+            let started = Instant::now();
+            entry.last_run = Some(started);
+            let mut budget = budgets.for_collector(&name, shutdown);
+            if let Some(heartbeat) = &self.heartbeat {
+                budget = budget.with_heartbeat(Rc::clone(heartbeat));
+            }
+            let result = entry.collector.collect(&budget);
+            let mut errors = entry.collector.take_warnings();
+            if let Some(reason) = budget.cut_short() {
+                warn!("Collector {} stopped early after {} entries: {}", name, budget.entries(), reason);
+                errors.push(format!("stopped early: {}", reason));
+            }
+            for warning in errors.iter() {
+                warn!("Collector {}: {}", name, warning);
+            }
+            let (mut records, status) = match result {
+                Ok(records) => {
+                    debug!("Collector {} produced {} records", name, records.len());
+                    let status = if errors.is_empty() {"ok"} else {"partial"};
+                    (records, status)
+                }
+                Err(e) => {
+                    error!("Collector {} failed: {}", name, e);
+                    errors.push(e.to_string());
+                    (Vec::new(), "failed")
+                }
+            };
+            let status = CollectorStatus {
+                status: status.to_string(),
+                started_at: started_at,
+                duration_ms: started.elapsed().as_millis(),
+                records: records.len(),
+                errors: errors,
+                cut_short: budget.cut_short().map(|reason| reason.to_string()),
+                entries_scanned: budget.entries(),
+            };
+            match Record::new("collector_status", &status) {
+                Ok(record) => records.push(record),
+                Err(e) => error!("Cannot build status record for {}: {}", name, e),
+            }
+            publish(&name, records);
+        }
+    }
+}
+
+//Effective uid from /proc/self/status ("Uid: real effective saved fs")
+pub fn running_as_root() -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|line| line.starts_with("Uid:"))
+                .and_then(|line| line.split_whitespace().nth(2).map(|uid| uid == "0"))
+        })
+        .unwrap_or(false)
+}
+
+//All the collectors built into the agent, enabled unless switched off in config or on the command line
+pub fn default_registry() -> Registry {
+    let mut registry = Registry::new();
+    let owners = packages::SharedOwners::default();
+    registry.register(Box::new(node::NodeCollector::new()), true);
+    registry.register(Box::new(hardware::HardwareCollector::new()), true);
+    registry.register(Box::new(interfaces::InterfaceCollector::new()), true);
+    registry.register(Box::new(routes::RouteCollector::new()), true);
+    registry.register(Box::new(processes::ProcessCollector::new(owners.clone())), true);
+    registry.register(Box::new(process_tree::ProcessTreeCollector::new()), true);
+    registry.register(Box::new(libraries::LibraryCollector::new(owners)), true);
+    registry.register(Box::new(network::ListenerCollector::new(banners::ServiceProbeConfig::default(), tls::TlsProbeConfig::default())), true);
+    registry.register(Box::new(network::ConnectionCollector::new()), true);
+    registry.register(Box::new(neighbours::NeighbourCollector::new()), true);
+    registry.register(Box::new(dns::DnsCollector::new()), true);
+    registry.register(Box::new(storage::StorageCollector::new()), true);
+    registry.register(Box::new(packages::PackageCollector::new()), true);
+    registry.register(Box::new(units::UnitCollector::new()), true);
+    registry.register(Box::new(accounts::AccountCollector::new()), true);
+    registry.register(Box::new(jobs::JobCollector::new()), true);
+    registry.register(Box::new(ssh::SshCollector::new()), true);
+    registry.register(Box::new(certificates::CertificateCollector::new(certificates::CertificateConfig::default())), true);
+    registry
+}
+
+//Adds a collector for each external script in the config. Names have to be unique as they end up in the envelope.
+pub fn register_scripts(registry: &mut Registry, scripts: &[script::ScriptConfig]) -> Result<(), Box<dyn Error>> {
+    for config in scripts {
+        if registry.contains(&config.name) {
+            return Err(format!("Script collector '{}' clashes with an existing collector", config.name).into());
+        }
+        registry.register(Box::new(script::ScriptCollector::new(config.clone())), true);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter {
+        runs: usize,
+        schedule: Schedule,
+    }
+
+    impl Collector for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+        fn description(&self) -> &str {
+            "Counts how often it has been run"
+        }
+        fn schedule(&self) -> Schedule {
+            self.schedule
+        }
+        fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+            self.runs += 1;
+            Ok(vec![Record::new("counter", &serde_json::json!({"runs": self.runs}))?])
+        }
+    }
+
+    struct Broken;
+
+    impl Collector for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+        fn description(&self) -> &str {
+            "Always fails"
+        }
+        fn schedule(&self) -> Schedule {
+            Schedule::Once
+        }
+        fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+            Err("nothing to see".into())
+        }
+    }
+
+    #[test]
+    fn unknown_collector_is_rejected() {
+        let mut registry = default_registry();
+        assert!(registry.set_enabled("processes", false).is_ok());
+        assert!(registry.set_enabled("no_such_collector", false).is_err());
+    }
+
+    #[test]
+    fn disable_wins_over_enable() {
+        let mut registry = default_registry();
+        registry.apply(&["processes".to_string()], &["processes".to_string()]).unwrap();
+        let processes = registry.available().into_iter().find(|c| c.name == "processes").unwrap();
+        assert!(!processes.enabled);
+    }
+
+    #[test]
+    fn run_once_collectors_only_run_once() {
+        let mut registry = Registry::new();
+        registry.register(Box::new(Counter { runs: 0, schedule: Schedule::Once }), true);
+        registry.register(Box::new(Broken), true);
+        let shutdown = Shutdown::new();
+        let mut published = 0;
+        let mut failed = 0;
+        for _ in 0..2 {
+            registry.run_due(&shutdown, &BudgetConfig::default(), |_, records| {
+                for record in records {
+                    if record.topic == "collector_status" {
+                        if record.payload["status"] == "failed" { failed += 1; }
+                    } else {
+                        published += 1;
+                    }
+                }
+            });
+        }
+        assert_eq!(published, 1);
+        assert_eq!(failed, 1);
+        assert!(registry.next_due().is_none());
+    }
+
+    #[test]
+    fn disabled_collectors_do_not_run() {
+        let mut registry = Registry::new();
+        registry.register(Box::new(Counter { runs: 0, schedule: Schedule::Every(Duration::from_secs(60)) }), false);
+        let mut published = 0;
+        registry.run_due(&Shutdown::new(), &BudgetConfig::default(), |_, records| published += records.len());
+        assert_eq!(published, 0);
+    }
+
+    struct Scanner;
+
+    impl Collector for Scanner {
+        fn name(&self) -> &str {
+            "scanner"
+        }
+        fn description(&self) -> &str {
+            "Scans until the budget runs out"
+        }
+        fn schedule(&self) -> Schedule {
+            Schedule::Once
+        }
+        fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+            while budget.tick() {}
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn budget_cut_is_reported() {
+        let mut registry = Registry::new();
+        registry.register(Box::new(Scanner), true);
+        let budgets: BudgetConfig = serde_json::from_str(r#"{"collectors":{"scanner":{"time_budget_secs":0}}}"#).unwrap();
+        let mut status = None;
+        registry.run_due(&Shutdown::new(), &budgets, |_, records| status = records.into_iter().find(|r| r.topic == "collector_status"));
+        let status = status.unwrap().payload;
+        assert_eq!(status["status"], "partial");
+        assert_eq!(status["cut_short"], "time budget exceeded");
+    }
+
+    #[test]
+    fn script_names_must_be_unique() {
+        let mut registry = default_registry();
+        let scripts: Vec<script::ScriptConfig> = serde_json::from_str(r#"[{"name":"processes","path":"/bin/true"}]"#).unwrap();
+        assert!(register_scripts(&mut registry, &scripts).is_err());
+    }
+
+    #[test]
+    fn envelope_adds_standard_fields() {
+        let agent = AgentInfo::new("123456567788990".to_string(), "default".to_string());
+        let record = Record::new("processes", &serde_json::json!({"pid": "1"})).unwrap();
+        assert_eq!(record.mqtt_topic(&agent.agent_id), "/nodes/123456567788990/processes");
+        let envelope = record.into_envelope(&agent, "processes");
+        assert_eq!(envelope["pid"], "1");
+        assert_eq!(envelope["node"], "123456567788990");
+        assert_eq!(envelope["collector"], "processes");
+        assert_eq!(envelope["correlation_id"], agent.correlation_id.to_string());
+    }
+}
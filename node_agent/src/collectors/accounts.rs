@@ -0,0 +1,388 @@
+//Who can log in and who holds privileges: local accounts and groups from /etc/passwd and /etc/group, password ageing
+//from /etc/shadow (never the hashes) and sudo rules from /etc/sudoers and its includes.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use chrono::{Duration as Days, NaiveDate};
+use log::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SUDOERS: &str = "/etc/sudoers";
+//Shells which mean the account can't log in interactively
+const NO_LOGIN_SHELLS: &[&str] = &["/usr/sbin/nologin", "/sbin/nologin", "/bin/false", "/usr/bin/false", "/bin/sync"];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PasswordStatus {
+    //set, locked, empty (no password needed!) or none (no shadow entry)
+    pub state: String,
+    pub last_changed: Option<NaiveDate>,
+    pub min_days: Option<u32>,
+    pub max_days: Option<u32>,
+    pub warn_days: Option<u32>,
+    pub inactive_days: Option<u32>,
+    pub expires: Option<NaiveDate>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub username: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub primary_group: Option<String>,
+    pub groups: Vec<String>,
+    pub gecos: String,
+    pub home: String,
+    pub shell: String,
+    pub can_login: bool,
+    //Below UID_MIN from login.defs, created for a service rather than a person
+    pub system_account: bool,
+    pub password: Option<PasswordStatus>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SudoRule {
+    pub file: String,
+    //Users, %groups or aliases the rule applies to
+    pub principals: Vec<String>,
+    pub hosts: Vec<String>,
+    pub run_as: Option<String>,
+    pub tags: Vec<String>,
+    pub commands: Vec<String>,
+}
+
+//name:password:uid:gid:gecos:home:shell
+pub fn parse_passwd(contents: &str, uid_min: u32) -> Vec<Account> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 7 {
+                return None
+            }
+            let uid = fields[2].parse().ok()?;
+            Some(Account {
+                username: fields[0].to_string(),
+                uid: uid,
+                gid: fields[3].parse().ok()?,
+                primary_group: None,
+                groups: Vec::new(),
+                gecos: fields[4].to_string(),
+                home: fields[5].to_string(),
+                shell: fields[6].to_string(),
+                can_login: !fields[6].is_empty() && !NO_LOGIN_SHELLS.contains(&fields[6]),
+                system_account: uid != 0 && uid < uid_min,
+                password: None,
+            })
+        })
+        .collect()
+}
+
+//name:password:gid:member,member
+pub fn parse_group(contents: &str) -> Vec<Group> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 4 {
+                return None
+            }
+            Some(Group {
+                name: fields[0].to_string(),
+                gid: fields[2].parse().ok()?,
+                members: fields[3].split(',').filter(|m| !m.is_empty()).map(String::from).collect(),
+            })
+        })
+        .collect()
+}
+
+//name:hash:last_change:min:max:warn:inactive:expire:reserved, dates are days since the epoch. The hash is only
+//looked at to tell locked and empty passwords apart.
+pub fn parse_shadow(contents: &str) -> HashMap<String, PasswordStatus> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let date = |days: &str| days.parse::<i64>().ok().and_then(|days| epoch.checked_add_signed(Days::days(days)));
+    let number = |value: &str| value.parse::<u32>().ok();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 8 {
+                return None
+            }
+            let state = if fields[1].is_empty() {
+                "empty"
+            } else if fields[1].starts_with('!') || fields[1].starts_with('*') {
+                "locked"
+            } else {
+                "set"
+            };
+            //0 last change means the user must change it at next login, not a 1970 change
+            Some((fields[0].to_string(), PasswordStatus {
+                state: state.to_string(),
+                last_changed: date(fields[2]).filter(|d| *d != epoch),
+                min_days: number(fields[3]),
+                max_days: number(fields[4]).filter(|max| *max != 99999),
+                warn_days: number(fields[5]),
+                inactive_days: number(fields[6]),
+                expires: date(fields[7]),
+            }))
+        })
+        .collect()
+}
+
+pub fn uid_min(login_defs: &str) -> u32 {
+    login_defs
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("UID_MIN"))
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(1000)
+}
+
+//sudoers(5) skips files in an includedir whose names end in ~ or contain a dot
+fn included_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            !name.ends_with('~') && !name.contains('.')
+        })
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+    files
+}
+
+//Rules from one sudoers file, following #include/@include and #includedir/@includedir. Defaults and alias
+//definitions aren't rules and are skipped.
+pub fn parse_sudoers(path: &Path, depth: usize) -> Vec<SudoRule> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            debug!("Cannot read {}: {}", path.display(), e);
+            return Vec::new()
+        }
+    };
+    let mut rules = Vec::new();
+    let mut pending = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if let Some(continued) = line.strip_suffix('\\') {
+            pending.push_str(continued);
+            continue;
+        }
+        let line = format!("{}{}", pending, line);
+        pending.clear();
+
+        let directive = line.strip_prefix('@').or_else(|| line.strip_prefix('#'));
+        if let Some(target) = directive.and_then(|d| d.strip_prefix("includedir ")) {
+            if depth < 8 {
+                for file in included_files(Path::new(target.trim())) {
+                    rules.extend(parse_sudoers(&file, depth + 1));
+                }
+            }
+            continue;
+        }
+        if let Some(target) = directive.and_then(|d| d.strip_prefix("include ")) {
+            if depth < 8 {
+                rules.extend(parse_sudoers(Path::new(target.trim()), depth + 1));
+            }
+            continue;
+        }
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() || line.starts_with("Defaults") || line.split_whitespace().next().map_or(false, |word| word.ends_with("_Alias")) {
+            continue;
+        }
+        if let Some(rule) = parse_sudo_rule(line, &path.display().to_string()) {
+            rules.push(rule);
+        }
+    }
+    rules
+}
+
+//"%admin,alice ALL=(ALL:ALL) NOPASSWD: /usr/bin/systemctl, /usr/bin/journalctl"
+pub fn parse_sudo_rule(line: &str, file: &str) -> Option<SudoRule> {
+    let (who, what) = line.split_once('=')?;
+    let mut who = who.split_whitespace();
+    let principals = who.next()?.split(',').map(|p| p.trim().to_string()).collect();
+    let hosts = who.collect::<Vec<&str>>().join("").split(',').map(String::from).filter(|h| !h.is_empty()).collect();
+    let mut rest = what.trim();
+    let mut run_as = None;
+    if let Some(spec) = rest.strip_prefix('(') {
+        let (inside, after) = spec.split_once(')')?;
+        run_as = Some(inside.trim().to_string());
+        rest = after.trim();
+    }
+    let mut tags = Vec::new();
+    while let Some((tag, after)) = rest.split_once(':') {
+        let tag = tag.trim();
+        if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+            break;
+        }
+        tags.push(tag.to_string());
+        rest = after.trim();
+    }
+    Some(SudoRule {
+        file: file.to_string(),
+        principals: principals,
+        hosts: hosts,
+        run_as: run_as,
+        tags: tags,
+        commands: rest.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect(),
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct Accounts {
+    pub accounts: Vec<Account>,
+    pub groups: Vec<Group>,
+    pub sudo_rules: Vec<SudoRule>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        let uid_min = fs::read_to_string("/etc/login.defs").map(|defs| uid_min(&defs)).unwrap_or(1000);
+        let mut accounts = fs::read_to_string("/etc/passwd").map(|c| parse_passwd(&c, uid_min)).unwrap_or_default();
+        let groups = fs::read_to_string("/etc/group").map(|c| parse_group(&c)).unwrap_or_default();
+        //Root only, without it accounts just have no password block
+        let shadow = fs::read_to_string("/etc/shadow").map(|c| parse_shadow(&c)).unwrap_or_default();
+        let shadow_readable = !shadow.is_empty();
+        for account in accounts.iter_mut() {
+            account.primary_group = groups.iter().find(|g| g.gid == account.gid).map(|g| g.name.clone());
+            account.groups = groups.iter().filter(|g| g.members.contains(&account.username)).map(|g| g.name.clone()).collect();
+            account.password = shadow.get(&account.username).cloned().or_else(|| {
+                if shadow_readable {
+                    Some(PasswordStatus { state: "none".to_string(), last_changed: None, min_days: None, max_days: None, warn_days: None, inactive_days: None, expires: None })
+                } else {
+                    None
+                }
+            });
+        }
+        Self {
+            accounts: accounts,
+            groups: groups,
+            sudo_rules: parse_sudoers(Path::new(SUDOERS), 0),
+        }
+    }
+}
+
+//uid to username, /etc/passwd first then NSS (LDAP, sssd...) for anything it doesn't have
+pub struct UserNames {
+    names: HashMap<u32, Option<String>>,
+}
+
+impl UserNames {
+    pub fn new() -> Self {
+        let names = fs::read_to_string("/etc/passwd")
+            .map(|c| parse_passwd(&c, 1000).into_iter().map(|a| (a.uid, Some(a.username))).collect())
+            .unwrap_or_default();
+        Self { names: names }
+    }
+
+    pub fn name(&mut self, uid: u32) -> Option<String> {
+        self.names.entry(uid).or_insert_with(|| getpwuid(uid)).clone()
+    }
+}
+
+fn getpwuid(uid: u32) -> Option<String> {
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let status = unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 || result.is_null() {
+        return None
+    }
+    Some(unsafe { CStr::from_ptr(entry.pw_name) }.to_string_lossy().into_owned())
+}
+
+pub struct AccountCollector;
+
+impl AccountCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for AccountCollector {
+    fn name(&self) -> &str {
+        "accounts"
+    }
+    fn description(&self) -> &str {
+        "Local accounts, groups, password ageing and sudo rules"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(3600))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        Ok(vec![Record::new("accounts", &Accounts::new())?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwd_and_group_are_parsed() {
+        let accounts = parse_passwd("root:x:0:0:root:/root:/bin/bash\nwww-data:x:33:33:www-data:/var/www:/usr/sbin/nologin\nalice:x:1000:1000:Alice,,,:/home/alice:/bin/zsh\n", 1000);
+        assert_eq!(accounts.len(), 3);
+        assert!(accounts[0].can_login && !accounts[0].system_account);
+        assert!(!accounts[1].can_login && accounts[1].system_account);
+        assert_eq!(accounts[2].shell, "/bin/zsh");
+        let groups = parse_group("sudo:x:27:alice,bob\nalice:x:1000:\n");
+        assert_eq!(groups[0].members, vec!["alice".to_string(), "bob".to_string()]);
+        assert!(groups[1].members.is_empty());
+    }
+
+    #[test]
+    fn shadow_never_keeps_the_hash() {
+        let shadow = parse_shadow("root:*:19000:0:99999:7:::\nalice:$6$salt$hash:19500:1:90:7:30:20000:\nbob::19500::::::\ncarol:!$6$x:0:::::: \n");
+        assert_eq!(shadow["root"].state, "locked");
+        assert_eq!(shadow["root"].max_days, None);
+        assert_eq!(shadow["alice"].state, "set");
+        assert_eq!(shadow["alice"].last_changed, NaiveDate::from_ymd_opt(2023, 5, 23));
+        assert_eq!(shadow["alice"].max_days, Some(90));
+        assert_eq!(shadow["bob"].state, "empty");
+        assert_eq!(shadow["carol"].last_changed, None);
+        assert!(!format!("{:?}", shadow).contains("$6$"));
+    }
+
+    #[test]
+    fn sudoers_rules_and_includes() {
+        let dir = std::env::temp_dir().join(format!("node_agent-sudo-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("sudoers.d")).unwrap();
+        fs::write(dir.join("sudoers"), format!("Defaults env_reset\nUser_Alias ADMINS = alice\nroot ALL=(ALL:ALL) ALL\n%sudo ALL=(ALL:ALL) ALL # admins\n@includedir {}\n", dir.join("sudoers.d").display())).unwrap();
+        fs::write(dir.join("sudoers.d/deploy"), "deploy web01,web02 = (www-data) NOPASSWD: /usr/bin/systemctl restart app, \\\n  /usr/bin/journalctl\n").unwrap();
+        fs::write(dir.join("sudoers.d/README.txt"), "ignored ALL=(ALL) ALL\n").unwrap();
+
+        let rules = parse_sudoers(&dir.join("sudoers"), 0);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[1].principals, vec!["%sudo".to_string()]);
+        assert_eq!(rules[1].run_as.as_deref(), Some("ALL:ALL"));
+        let deploy = &rules[2];
+        assert_eq!(deploy.hosts, vec!["web01".to_string(), "web02".to_string()]);
+        assert_eq!(deploy.tags, vec!["NOPASSWD".to_string()]);
+        assert_eq!(deploy.commands, vec!["/usr/bin/systemctl restart app".to_string(), "/usr/bin/journalctl".to_string()]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn root_uid_has_a_name() {
+        assert_eq!(UserNames::new().name(0).as_deref(), Some("root"));
+    }
+}
@@ -0,0 +1,335 @@
+//What speaks on each listening port, from the greeting services send when a client connects (SSH, SMTP, FTP, MySQL,
+//POP3/IMAP) or, for services that wait for the client, from a few harmless requests in turn: a PostgreSQL SSLRequest,
+//PING for Redis (then INFO for its version) and HEAD / for an HTTP Server header. PING comes before anything with a
+//Host: header in it since Redis treats those as an attack and logs it.
+use crate::budget::Budget;
+use crate::collectors::probing::{ProbeLimits, Prober};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const MAX_RESPONSE_BYTES: usize = 8192;
+const MAX_BANNER_CHARS: usize = 200;
+//PostgreSQL's SSLRequest: length 8 and the magic 80877103
+const POSTGRES_SSL_REQUEST: &[u8] = &[0, 0, 0, 8, 4, 210, 22, 47];
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServiceProbeConfig {
+    #[serde(flatten)]
+    pub limits: ProbeLimits,
+    //How long to wait for a greeting before deciding the service waits for the client
+    #[serde(default = "ServiceProbeConfig::default_greeting_wait_ms")]
+    pub greeting_wait_ms: u64,
+}
+
+impl ServiceProbeConfig {
+    fn default_greeting_wait_ms() -> u64 {
+        1000
+    }
+}
+
+impl Default for ServiceProbeConfig {
+    fn default() -> Self {
+        Self { limits: ProbeLimits::default(), greeting_wait_ms: Self::default_greeting_wait_ms() }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ServiceIdentity {
+    //ssh, smtp, ftp, pop3, imap, mysql, postgresql, redis, http, tls, or None when nothing gave it away
+    pub protocol: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    //First line of what the service said, printable characters only
+    pub banner: Option<String>,
+    pub probed_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+impl ServiceIdentity {
+    fn new(protocol: &str, product: Option<&str>, version: Option<&str>) -> Self {
+        Self {
+            protocol: Some(protocol.to_string()),
+            product: product.map(String::from),
+            version: version.map(String::from),
+            ..Default::default()
+        }
+    }
+}
+
+fn first_line(response: &[u8]) -> Option<String> {
+    let line: String = String::from_utf8_lossy(response).lines().next()?.chars().filter(|c| !c.is_control()).take(MAX_BANNER_CHARS).collect();
+    Some(line).filter(|line| !line.trim().is_empty())
+}
+
+//"OpenSSH_9.6p1" style words, split at the first _, / or - followed by a digit
+fn product_version(word: &str) -> (Option<&str>, Option<&str>) {
+    let split = word.char_indices().find(|(i, c)| (*c == '_' || *c == '/' || *c == '-') && word[i + 1..].starts_with(|d: char| d.is_ascii_digit()));
+    match split {
+        Some((i, _)) => (Some(&word[..i]), Some(&word[i + 1..])),
+        None => (Some(word).filter(|w| !w.is_empty()), None),
+    }
+}
+
+//Word after a product name in a greeting, when it looks like a version
+fn version_after<'a>(text: &'a str, product: &str) -> Option<&'a str> {
+    let rest = &text[text.find(product)? + product.len()..];
+    rest.split(|c: char| c.is_whitespace() || c == ')' || c == ']').find(|w| !w.is_empty()).filter(|w| w.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+//MySQL and MariaDB greet with a handshake packet: 3 byte length, sequence 0, protocol 10, NUL terminated version
+fn parse_mysql_handshake(response: &[u8]) -> Option<ServiceIdentity> {
+    if response.len() < 6 || response[3] != 0 {
+        return None
+    }
+    match response[4] {
+        10 => {
+            let end = response[5..].iter().position(|b| *b == 0)?;
+            let version = std::str::from_utf8(&response[5..5 + end]).ok()?;
+            let product = if version.contains("MariaDB") {"MariaDB"} else {"MySQL"};
+            Some(ServiceIdentity::new("mysql", Some(product), Some(version.trim_start_matches("5.5.5-"))))
+        }
+        //An error packet straight away, "Host ... is not allowed to connect"
+        0xff if response.windows(4).any(|w| w == b"Host") => Some(ServiceIdentity::new("mysql", None, None)),
+        _ => None,
+    }
+}
+
+//Services which talk first
+pub fn identify_greeting(response: &[u8]) -> Option<ServiceIdentity> {
+    if let Some(identity) = parse_mysql_handshake(response) {
+        return Some(identity)
+    }
+    let line = first_line(response)?;
+    let mut identity = if let Some(software) = line.strip_prefix("SSH-") {
+        //SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13
+        let software = software.splitn(2, '-').nth(1).unwrap_or("").split_whitespace().next().unwrap_or("");
+        let (product, version) = product_version(software);
+        ServiceIdentity::new("ssh", product, version)
+    } else if line.starts_with("+OK") {
+        ServiceIdentity::new("pop3", ["Dovecot"].iter().copied().find(|p| line.contains(p)), None)
+    } else if line.starts_with("* OK") {
+        ServiceIdentity::new("imap", ["Dovecot", "Cyrus", "Courier"].iter().copied().find(|p| line.contains(p)), None)
+    } else if line.starts_with("220") {
+        let smtp = line.contains("SMTP") || line.contains("Postfix") || line.contains("Exim") || line.contains("Sendmail");
+        let products = if smtp {&["Postfix", "Exim", "Sendmail", "Microsoft ESMTP"][..]} else {&["vsFTPd", "ProFTPD", "Pure-FTPd", "FileZilla Server"][..]};
+        let product = products.iter().copied().find(|p| line.contains(p));
+        let version = product.and_then(|p| version_after(&line, p));
+        ServiceIdentity::new(if smtp {"smtp"} else {"ftp"}, product, version)
+    } else {
+        return None
+    };
+    identity.banner = Some(line);
+    Some(identity)
+}
+
+//Status line and the Server header, "nginx/1.24.0" gives product and version
+pub fn identify_http(response: &[u8]) -> Option<ServiceIdentity> {
+    let text = String::from_utf8_lossy(response);
+    if !text.starts_with("HTTP/") {
+        return None
+    }
+    let server = text.lines().take_while(|line| !line.is_empty()).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        Some(value.trim()).filter(|_| name.eq_ignore_ascii_case("server"))
+    });
+    let (product, version) = server.map(|s| product_version(s.split_whitespace().next().unwrap_or(""))).unwrap_or((None, None));
+    let mut identity = ServiceIdentity::new("http", product, version);
+    identity.banner = server.map(|s| s.chars().take(MAX_BANNER_CHARS).collect()).or_else(|| first_line(response));
+    Some(identity)
+}
+
+//Answers to the SSLRequest or PING: a single S or N from PostgreSQL, PONG or a RESP error from Redis, an error page
+//from HTTP servers
+pub fn identify_reply(response: &[u8]) -> Option<ServiceIdentity> {
+    let redis_replies: [&[u8]; 4] = [b"+PONG", b"-ERR", b"-NOAUTH", b"-DENIED"];
+    match response {
+        [b'S'] | [b'N'] => Some(ServiceIdentity::new("postgresql", Some("PostgreSQL"), None)),
+        _ if redis_replies.iter().any(|reply| response.starts_with(reply)) => Some(ServiceIdentity::new("redis", Some("Redis"), None)),
+        //A TLS alert record, the port wants a handshake
+        [0x15, 0x03, ..] => Some(ServiceIdentity::new("tls", None, None)),
+        _ => identify_http(response),
+    }
+}
+
+pub fn redis_version(info: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(info).lines().find_map(|line| line.strip_prefix("redis_version:").map(|v| v.trim().to_string()))
+}
+
+//Reads until the peer stops talking, closes, or the cap, whichever comes first
+fn read_response(socket: &mut TcpStream, wait: Duration) -> Vec<u8> {
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 2048];
+    let deadline = Instant::now() + wait;
+    while response.len() < MAX_RESPONSE_BYTES {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || socket.set_read_timeout(Some(left)).is_err() {
+            break;
+        }
+        match socket.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => response.extend_from_slice(&buffer[..n]),
+        }
+        //Line based greetings are complete once a line ends, HTTP once the headers do
+        if response.starts_with(b"HTTP/") {
+            if response.windows(4).any(|w| w == b"\r\n\r\n") {
+                break;
+            }
+        } else if !response.is_empty() {
+            break;
+        }
+    }
+    response
+}
+
+fn exchange(address: SocketAddr, request: &[u8], wait: Duration, timeout: Duration) -> std::io::Result<Vec<u8>> {
+    let mut socket = TcpStream::connect_timeout(&address, timeout)?;
+    socket.set_write_timeout(Some(timeout))?;
+    if !request.is_empty() {
+        socket.write_all(request)?;
+    }
+    Ok(read_response(&mut socket, wait))
+}
+
+pub fn identify(address: SocketAddr, greeting_wait: Duration, timeout: Duration) -> ServiceIdentity {
+    let mut identity = match identify_with(address, greeting_wait, timeout) {
+        Ok(identity) => identity,
+        Err(e) => ServiceIdentity { error: Some(e.to_string()), ..Default::default() },
+    };
+    identity.probed_at = Some(Utc::now());
+    identity
+}
+
+fn identify_with(address: SocketAddr, greeting_wait: Duration, timeout: Duration) -> std::io::Result<ServiceIdentity> {
+    //Wait for a greeting, then send the SSLRequest down the same connection if none came
+    let mut socket = TcpStream::connect_timeout(&address, timeout)?;
+    socket.set_write_timeout(Some(timeout))?;
+    let greeting = read_response(&mut socket, greeting_wait);
+    if !greeting.is_empty() {
+        return Ok(identify_greeting(&greeting).unwrap_or(ServiceIdentity { banner: first_line(&greeting), ..Default::default() }))
+    }
+    socket.write_all(POSTGRES_SSL_REQUEST)?;
+    let mut reply = read_response(&mut socket, timeout);
+    drop(socket);
+    //Redis waits for the end of the line the SSLRequest never sends
+    if reply.is_empty() {
+        reply = exchange(address, b"PING\r\n", timeout, timeout)?;
+    }
+    match identify_reply(&reply) {
+        Some(mut identity) if identity.protocol.as_deref() == Some("redis") => {
+            //INFO changes nothing, it fails with NOAUTH when a password is set
+            identity.version = redis_version(&exchange(address, b"INFO server\r\n", timeout, timeout)?);
+            Ok(identity)
+        }
+        //The error page rarely carries the Server header a proper request gets
+        Some(identity) if identity.protocol.as_deref() != Some("http") => Ok(identity),
+        _ => {
+            let head = exchange(address, b"HEAD / HTTP/1.0\r\nHost: localhost\r\nUser-Agent: node_agent\r\nConnection: close\r\n\r\n", timeout, timeout)?;
+            Ok(identify_http(&head).or_else(|| identify_reply(&reply)).unwrap_or_default())
+        }
+    }
+}
+
+pub struct ServiceProber {
+    prober: Prober<ServiceIdentity>,
+    greeting_wait_ms: u64,
+}
+
+impl ServiceProber {
+    pub fn new(config: ServiceProbeConfig) -> Self {
+        Self { prober: Prober::new(config.limits), greeting_wait_ms: config.greeting_wait_ms }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.prober.limits().enabled
+    }
+
+    pub fn probe_all(&mut self, listeners: &[(SocketAddr, u32, String)], budget: &Budget) -> &HashMap<(SocketAddr, u32), ServiceIdentity> {
+        let greeting_wait = Duration::from_millis(self.greeting_wait_ms);
+        let timeout = self.prober.limits().timeout();
+        self.prober.probe_all(listeners, budget, |address| identify(address, greeting_wait, timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn greetings_are_recognised() {
+        let ssh = identify_greeting(b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n").unwrap();
+        assert_eq!((ssh.protocol.as_deref(), ssh.product.as_deref(), ssh.version.as_deref()), (Some("ssh"), Some("OpenSSH"), Some("9.6p1")));
+        let smtp = identify_greeting(b"220 mail.example.com ESMTP Exim 4.96 Mon, 19 Oct 2026 10:00:00 +0000\r\n").unwrap();
+        assert_eq!((smtp.protocol.as_deref(), smtp.product.as_deref(), smtp.version.as_deref()), (Some("smtp"), Some("Exim"), Some("4.96")));
+        let ftp = identify_greeting(b"220 (vsFTPd 3.0.5)\r\n").unwrap();
+        assert_eq!((ftp.protocol.as_deref(), ftp.product.as_deref(), ftp.version.as_deref()), (Some("ftp"), Some("vsFTPd"), Some("3.0.5")));
+        let mut mysql = vec![0x4a, 0, 0, 0, 10];
+        mysql.extend_from_slice(b"5.5.5-10.11.6-MariaDB-0+deb12u1\0rest");
+        let mariadb = identify_greeting(&mysql).unwrap();
+        assert_eq!((mariadb.product.as_deref(), mariadb.version.as_deref()), (Some("MariaDB"), Some("10.11.6-MariaDB-0+deb12u1")));
+        assert_eq!(identify_greeting(b"\x00\x01binary"), None);
+    }
+
+    #[test]
+    fn client_first_services() {
+        assert_eq!(identify_reply(b"N").unwrap().protocol.as_deref(), Some("postgresql"));
+        assert_eq!(identify_reply(b"+PONG\r\n").unwrap().protocol.as_deref(), Some("redis"));
+        assert_eq!(identify_reply(b"-NOAUTH Authentication required.\r\n").unwrap().protocol.as_deref(), Some("redis"));
+        assert_eq!(identify_reply(&[0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x32]).unwrap().protocol.as_deref(), Some("tls"));
+        let http = identify_http(b"HTTP/1.1 200 OK\r\nserver: nginx/1.24.0 (Ubuntu)\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert_eq!((http.product.as_deref(), http.version.as_deref(), http.banner.as_deref()), (Some("nginx"), Some("1.24.0"), Some("nginx/1.24.0 (Ubuntu)")));
+        assert_eq!(redis_version(b"$120\r\n# Server\r\nredis_version:7.2.4\r\nredis_mode:standalone\r\n"), Some("7.2.4".to_string()));
+    }
+
+    //Answers HEAD like a web server and ignores anything else until the client gives up
+    fn http_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut socket in listener.incoming().filter_map(Result::ok) {
+                thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 512];
+                    while let Ok(n) = socket.read(&mut buffer) {
+                        if n == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buffer[..n]);
+                        if request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            let _ = socket.write_all(b"HTTP/1.0 200 OK\r\nServer: Apache/2.4.58 (Ubuntu)\r\n\r\n");
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn probes_over_loopback() {
+        let http = identify(http_server(), Duration::from_millis(200), Duration::from_millis(500));
+        assert_eq!((http.protocol.as_deref(), http.product.as_deref(), http.version.as_deref()), (Some("http"), Some("Apache"), Some("2.4.58")));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut socket in listener.incoming().filter_map(Result::ok) {
+                let _ = socket.write_all(b"SSH-2.0-OpenSSH_9.2p1 Debian-2+deb12u3\r\n");
+            }
+        });
+        let mut prober = ServiceProber::new(ServiceProbeConfig {
+            limits: ProbeLimits { enabled: true, timeout_ms: 500, exclude_ports: vec![address.port() + 1], ..Default::default() },
+            greeting_wait_ms: 200,
+        });
+        let listeners = vec![(address, 7, "sshd".to_string()), (SocketAddr::new(address.ip(), address.port() + 1), 8, "other".to_string())];
+        let results = prober.probe_all(&listeners, &Budget::unlimited());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[&(address, 7)].version.as_deref(), Some("9.2p1"));
+    }
+}
@@ -0,0 +1,276 @@
+//Name resolution on the node in the README DNS Records schema: static /etc/hosts entries, the resolvers and search
+//domains from resolv.conf (and systemd-resolved when it sits in front of them) and the nsswitch.conf lookup order.
+use crate::budget::Budget;
+use crate::collectors::neighbours::interface_name;
+use crate::collectors::os::parse_key_values;
+use crate::collectors::{Collector, Record, Schedule};
+use crate::linux::sys_interagator::SystemInfo;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+//systemd-resolved's stub listener, resolv.conf pointing here means the real upstreams are resolved's
+const RESOLVED_STUB: &str = "127.0.0.53";
+const RESOLVED_DIR: &str = "/run/systemd/resolve";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DnsEntry {
+    //A/AAAA for /etc/hosts entries, nameserver and search for the resolver configuration
+    pub dns_type: String,
+    pub dns_name: String,
+    pub dns_address: Vec<IpAddr>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<IpAddr>,
+    pub search: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl ResolvConf {
+    pub fn parse(contents: &str) -> Self {
+        let mut conf = Self::default();
+        for line in contents.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => conf.nameservers.extend(words.next().and_then(|ns| ns.parse::<IpAddr>().ok())),
+                //The last of domain/search wins, as in the resolver
+                Some("search") | Some("domain") => conf.search = words.map(String::from).collect(),
+                Some("options") => conf.options.extend(words.map(String::from)),
+                _ => {}
+            }
+        }
+        conf
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ResolvedLink {
+    pub interface: String,
+    pub nameservers: Vec<IpAddr>,
+    pub domains: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Resolver {
+    pub resolv_conf: ResolvConf,
+    //Lookup order of the hosts database, e.g. ["files", "resolve", "dns"]
+    pub nsswitch_hosts: Vec<String>,
+    pub systemd_resolved: bool,
+    pub resolved_upstream: Option<ResolvConf>,
+    pub resolved_links: Vec<ResolvedLink>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DnsRecords {
+    pub node_id: String,
+    pub node_snapshot_start_time: DateTime<Utc>,
+    pub node_snapshot_stop_time: DateTime<Utc>,
+    pub dns_entries: Vec<DnsEntry>,
+    pub resolver: Resolver,
+}
+
+impl DnsRecords {
+    pub fn new() -> Self {
+        let start = Utc::now();
+        let hosts = fs::read_to_string("/etc/hosts").map(|c| parse_hosts(&c)).unwrap_or_default();
+        let resolver = read_resolver();
+        let mut dns_entries = hosts;
+        dns_entries.extend(resolver_entries(&resolver));
+        Self {
+            node_id: SystemInfo::get_machineid(),
+            node_snapshot_start_time: start,
+            node_snapshot_stop_time: Utc::now(),
+            dns_entries: dns_entries,
+            resolver: resolver,
+        }
+    }
+}
+
+//One entry per name, with every address /etc/hosts gives it in file order
+pub fn parse_hosts(contents: &str) -> Vec<DnsEntry> {
+    let mut names: BTreeMap<(String, String), Vec<IpAddr>> = BTreeMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let address: IpAddr = match words.next().and_then(|a| a.parse().ok()) {
+            Some(address) => address,
+            None => continue,
+        };
+        let dns_type = if address.is_ipv4() {"A"} else {"AAAA"};
+        for name in words {
+            let addresses = names.entry((dns_type.to_string(), name.to_lowercase())).or_default();
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+    }
+    names
+        .into_iter()
+        .map(|((dns_type, dns_name), dns_address)| DnsEntry { dns_type: dns_type, dns_name: dns_name, dns_address: dns_address })
+        .collect()
+}
+
+//"hosts: files mdns4_minimal [NOTFOUND=return] dns" -> ["files", "mdns4_minimal", "dns"]
+pub fn parse_nsswitch_hosts(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("hosts:"))
+        .map(|sources| sources.split_whitespace().filter(|s| !s.starts_with('[')).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+//resolved writes servers as 1.1.1.1, 1.1.1.1:53, [fd00::1]:53, fe80::1%eth0 or [fe80::1%eth0]:53, any of them
+//followed by #name. Just the address is kept.
+pub fn parse_resolved_server(server: &str) -> Option<IpAddr> {
+    let server = server.split('#').next()?;
+    //The interface sits inside the brackets when there's a port
+    let server = match server.split_once('%') {
+        Some((address, rest)) => format!("{}{}", address, rest.find(']').map_or("", |end| &rest[end..])),
+        None => server.to_string(),
+    };
+    server.parse::<SocketAddr>().map(|socket| socket.ip()).or_else(|_| server.parse()).ok()
+}
+
+//Per link state files, /run/systemd/resolve/netif/<ifindex> with DNS= and DOMAINS= lines
+fn read_resolved_links(dir: &Path) -> Vec<ResolvedLink> {
+    let mut links = Vec::new();
+    let entries = match fs::read_dir(dir.join("netif")) {
+        Ok(entries) => entries,
+        Err(_) => return links,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let values = match fs::read_to_string(entry.path()) {
+            Ok(contents) => parse_key_values(&contents),
+            Err(_) => continue,
+        };
+        let index = entry.file_name().to_string_lossy().into_owned();
+        let interface = index.parse().ok().and_then(interface_name).unwrap_or(index);
+        links.push(ResolvedLink {
+            interface: interface,
+            nameservers: values.get("DNS").map(|servers| servers.split_whitespace().filter_map(parse_resolved_server).collect()).unwrap_or_default(),
+            domains: values.get("DOMAINS").map(|domains| domains.split_whitespace().map(String::from).collect()).unwrap_or_default(),
+        });
+    }
+    links.sort_by(|a, b| a.interface.cmp(&b.interface));
+    links
+}
+
+fn read_resolver() -> Resolver {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").map(|c| ResolvConf::parse(&c)).unwrap_or_default();
+    let resolved_dir = Path::new(RESOLVED_DIR);
+    let systemd_resolved = resolved_dir.is_dir() && resolv_conf.nameservers.iter().any(|ns| ns.to_string() == RESOLVED_STUB);
+    Resolver {
+        nsswitch_hosts: fs::read_to_string("/etc/nsswitch.conf").map(|c| parse_nsswitch_hosts(&c)).unwrap_or_default(),
+        systemd_resolved: systemd_resolved,
+        resolved_upstream: if systemd_resolved {fs::read_to_string(resolved_dir.join("resolv.conf")).ok().map(|c| ResolvConf::parse(&c))} else {None},
+        resolved_links: if systemd_resolved {read_resolved_links(resolved_dir)} else {Vec::new()},
+        resolv_conf: resolv_conf,
+    }
+}
+
+//The resolvers actually used (resolved's upstreams rather than its stub) and search domains as schema entries
+pub fn resolver_entries(resolver: &Resolver) -> Vec<DnsEntry> {
+    let mut entries = Vec::new();
+    let conf = resolver.resolved_upstream.as_ref().unwrap_or(&resolver.resolv_conf);
+    let source = if resolver.resolved_upstream.is_some() {"systemd-resolved"} else {"resolv.conf"};
+    if !conf.nameservers.is_empty() {
+        entries.push(DnsEntry { dns_type: "nameserver".to_string(), dns_name: source.to_string(), dns_address: conf.nameservers.clone() });
+    }
+    for link in &resolver.resolved_links {
+        if !link.nameservers.is_empty() {
+            entries.push(DnsEntry { dns_type: "nameserver".to_string(), dns_name: link.interface.clone(), dns_address: link.nameservers.clone() });
+        }
+    }
+    for domain in &conf.search {
+        entries.push(DnsEntry { dns_type: "search".to_string(), dns_name: domain.clone(), dns_address: Vec::new() });
+    }
+    entries
+}
+
+pub struct DnsCollector;
+
+impl DnsCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for DnsCollector {
+    fn name(&self) -> &str {
+        "dns"
+    }
+    fn description(&self) -> &str {
+        "Hosts file entries, resolvers, search domains and nsswitch order"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(3600))
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        Ok(vec![Record::new("dns", &DnsRecords::new())?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_entries_group_by_name() {
+        let entries = parse_hosts("127.0.0.1 localhost\n::1 localhost ip6-localhost\n10.0.0.5 db.example.com db # primary\n10.0.0.6 db\n");
+        let db = entries.iter().find(|e| e.dns_name == "db" && e.dns_type == "A").unwrap();
+        assert_eq!(db.dns_address, vec!["10.0.0.5".parse::<IpAddr>().unwrap(), "10.0.0.6".parse().unwrap()]);
+        assert!(entries.iter().any(|e| e.dns_name == "localhost" && e.dns_type == "AAAA"));
+        assert!(!entries.iter().any(|e| e.dns_name == "primary"));
+    }
+
+    #[test]
+    fn resolv_conf_is_parsed() {
+        let conf = ResolvConf::parse("# generated\nnameserver 10.0.0.2\nnameserver fd00::1\ndomain old.example\nsearch example.com corp.example\noptions ndots:2 timeout:1\n");
+        assert_eq!(conf.nameservers.len(), 2);
+        assert_eq!(conf.search, vec!["example.com".to_string(), "corp.example".to_string()]);
+        assert_eq!(conf.options, vec!["ndots:2".to_string(), "timeout:1".to_string()]);
+    }
+
+    #[test]
+    fn resolved_upstreams_replace_the_stub() {
+        let resolver = Resolver {
+            resolv_conf: ResolvConf::parse("nameserver 127.0.0.53\nsearch example.com\n"),
+            systemd_resolved: true,
+            resolved_upstream: Some(ResolvConf::parse("nameserver 10.0.0.2\nsearch example.com\n")),
+            ..Default::default()
+        };
+        let entries = resolver_entries(&resolver);
+        assert_eq!(entries[0], DnsEntry { dns_type: "nameserver".to_string(), dns_name: "systemd-resolved".to_string(), dns_address: vec!["10.0.0.2".parse().unwrap()] });
+        assert_eq!(entries[1].dns_type, "search");
+    }
+
+    #[test]
+    fn resolved_servers_lose_port_interface_and_name() {
+        let servers: Vec<Option<IpAddr>> = ["1.1.1.1", "1.1.1.1:53#cloudflare-dns.com", "[fd00::1]:53", "fd00::1#dns.example", "fe80::1%eth0", "[fe80::1%eth0]:53#x", "bogus"]
+            .iter()
+            .map(|server| parse_resolved_server(server))
+            .collect();
+        let expected: Vec<Option<IpAddr>> = vec![
+            Some("1.1.1.1".parse().unwrap()),
+            Some("1.1.1.1".parse().unwrap()),
+            Some("fd00::1".parse().unwrap()),
+            Some("fd00::1".parse().unwrap()),
+            Some("fe80::1".parse().unwrap()),
+            Some("fe80::1".parse().unwrap()),
+            None,
+        ];
+        assert_eq!(servers, expected);
+    }
+
+    #[test]
+    fn nsswitch_hosts_order() {
+        assert_eq!(parse_nsswitch_hosts("passwd: files\nhosts:          files mdns4_minimal [NOTFOUND=return] dns\n"), vec!["files", "mdns4_minimal", "dns"]);
+    }
+}
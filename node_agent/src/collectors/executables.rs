@@ -0,0 +1,291 @@
+//What is actually behind /proc/<pid>/exe: its SHA-256, ELF metadata and file ownership. Hashing is the expensive
+//part, so results are cached by device, inode, mtime and size and each binary is only hashed and published once
+//however many processes run it. A hash the budget cuts off carries on from where it got to next run.
+use crate::budget::Budget;
+use crate::collectors::accounts::UserNames;
+use goblin::elf::header::{EM_386, EM_AARCH64, EM_ARM, EM_PPC64, EM_RISCV, EM_S390, EM_X86_64, ET_DYN, ET_EXEC};
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::Elf;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::{ptr, slice};
+
+//Bigger than any sane executable, not worth the IO
+const MAX_EXECUTABLE_BYTES: u64 = 512 * 1024 * 1024;
+//Budget ticks once per chunk hashed
+const CHUNK_BYTES: usize = 1024 * 1024;
+//Upgrades leave stale entries behind, start again once there are this many
+const MAX_CACHED: usize = 4096;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ElfInfo {
+    //elf32 or elf64
+    pub class: String,
+    pub architecture: String,
+    //executable, pie or shared_object
+    pub elf_type: String,
+    pub build_id: Option<String>,
+    pub interpreter: Option<String>,
+    //DT_NEEDED entries, as written in the binary
+    pub needed: Vec<String>,
+    pub static_linked: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Executable {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub uid: u32,
+    pub gid: u32,
+    pub owner: Option<String>,
+    pub mode: String,
+    pub setuid: bool,
+    pub setgid: bool,
+    //Anyone other than the owner can replace it
+    pub writable_by_others: bool,
+    //None for anything that isn't ELF (or is too broken for us to read)
+    pub elf: Option<ElfInfo>,
+}
+
+fn architecture(machine: u16) -> String {
+    match machine {
+        EM_X86_64 => "x86_64".to_string(),
+        EM_AARCH64 => "aarch64".to_string(),
+        EM_386 => "x86".to_string(),
+        EM_ARM => "arm".to_string(),
+        EM_RISCV => "riscv".to_string(),
+        EM_PPC64 => "ppc64".to_string(),
+        EM_S390 => "s390x".to_string(),
+        other => goblin::elf::header::machine_to_str(other).to_lowercase(),
+    }
+}
+
+pub fn parse_elf(bytes: &[u8]) -> Option<ElfInfo> {
+    let elf = Elf::parse(bytes).ok()?;
+    let build_id = elf.iter_note_headers(bytes)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name == "GNU")
+        .map(|note| note.desc.iter().map(|b| format!("{:02x}", b)).collect());
+    let elf_type = match elf.header.e_type {
+        ET_EXEC => "executable",
+        //Position independent executables are ET_DYN too, they're the ones asking for a loader
+        ET_DYN if elf.interpreter.is_some() => "pie",
+        ET_DYN => "shared_object",
+        _ => "other",
+    };
+    Some(ElfInfo {
+        class: if elf.is_64 {"elf64".to_string()} else {"elf32".to_string()},
+        architecture: architecture(elf.header.e_machine),
+        elf_type: elf_type.to_string(),
+        build_id: build_id,
+        interpreter: elf.interpreter.map(str::to_string),
+        needed: elf.libraries.iter().map(|library| library.to_string()).collect(),
+        static_linked: elf.interpreter.is_none() && elf.libraries.is_empty(),
+    })
+}
+
+//Read only mapping of a file so goblin can parse it in place, only the pages it looks at get read. Running
+//executables can't be written to (ETXTBSY) so they won't shrink under us.
+struct Mapped {
+    address: *mut libc::c_void,
+    length: usize,
+}
+
+impl Mapped {
+    fn new(file: &File, length: u64) -> Option<Self> {
+        if length == 0 {
+            return None
+        }
+        let address = unsafe { libc::mmap(ptr::null_mut(), length as usize, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if address == libc::MAP_FAILED {
+            return None
+        }
+        Some(Self { address: address, length: length as usize })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address as *const u8, self.length) }
+    }
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.address, self.length) };
+    }
+}
+
+//How far a hash got before the budget ran out
+#[derive(Clone)]
+struct PartialHash {
+    hasher: Sha256,
+    offset: u64,
+}
+
+//Hashes the rest of the file a chunk at a time so the budget can pace (or stop) us. Err carries how far it got.
+fn hash_within(file: &mut File, partial: PartialHash, budget: &Budget) -> Result<String, Option<PartialHash>> {
+    let PartialHash { mut hasher, mut offset } = partial;
+    file.seek(SeekFrom::Start(offset)).map_err(|_| None)?;
+    let mut chunk = vec![0; CHUNK_BYTES];
+    loop {
+        if !budget.tick() {
+            return Err(Some(PartialHash { hasher: hasher, offset: offset }))
+        }
+        match file.read(&mut chunk) {
+            Ok(0) => return Ok(format!("{:x}", hasher.finalize())),
+            Ok(read) => {
+                hasher.update(&chunk[..read]);
+                offset += read as u64;
+            }
+            Err(_) => return Err(None),
+        }
+    }
+}
+
+pub enum Lookup<'a> {
+    //Seen before, already published
+    Known(&'a Executable),
+    //First time we've seen this binary
+    New(&'a Executable),
+    //The budget ran out part way through hashing, ask again next run
+    Unfinished,
+    //Gone, not a regular file, too big or unreadable
+    Unreadable,
+}
+
+type FileKey = (u64, u64, i64, u64);
+
+#[derive(Default)]
+pub struct ExecutableCache {
+    entries: HashMap<FileKey, Executable>,
+    partial: HashMap<FileKey, PartialHash>,
+}
+
+impl ExecutableCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Looks up the binary at path (normally /proc/<pid>/exe so deleted and containerised binaries work too), reporting
+    //it under name
+    pub fn lookup(&mut self, path: &Path, name: &str, budget: &Budget, users: &mut UserNames) -> Lookup<'_> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Lookup::Unreadable,
+        };
+        let metadata = match file.metadata() {
+            Ok(metadata) if metadata.is_file() && metadata.len() <= MAX_EXECUTABLE_BYTES => metadata,
+            _ => return Lookup::Unreadable,
+        };
+        let key = (metadata.dev(), metadata.ino(), metadata.mtime(), metadata.len());
+        if self.entries.contains_key(&key) {
+            return Lookup::Known(&self.entries[&key])
+        }
+        let partial = self.partial.remove(&key).unwrap_or(PartialHash { hasher: Sha256::new(), offset: 0 });
+        let sha256 = match hash_within(&mut file, partial, budget) {
+            Ok(sha256) => sha256,
+            Err(Some(partial)) => {
+                if self.partial.len() >= MAX_CACHED {
+                    self.partial.clear();
+                }
+                self.partial.insert(key, partial);
+                return Lookup::Unfinished
+            }
+            Err(None) => return Lookup::Unreadable,
+        };
+        let mode = metadata.mode() & 0o7777;
+        let executable = Executable {
+            path: name.to_string(),
+            sha256: sha256,
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            owner: users.name(metadata.uid()),
+            mode: format!("{:04o}", mode),
+            setuid: mode & 0o4000 != 0,
+            setgid: mode & 0o2000 != 0,
+            writable_by_others: mode & 0o022 != 0,
+            elf: Mapped::new(&file, metadata.len()).and_then(|mapped| parse_elf(mapped.bytes())),
+        };
+        if self.entries.len() >= MAX_CACHED {
+            self.entries.clear();
+        }
+        Lookup::New(self.entries.entry(key).or_insert(executable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn own_binary_is_elf() {
+        let file = File::open(std::env::current_exe().unwrap()).unwrap();
+        let mapped = Mapped::new(&file, file.metadata().unwrap().len()).unwrap();
+        let elf = parse_elf(mapped.bytes()).unwrap();
+        assert_eq!(elf.architecture, std::env::consts::ARCH);
+        assert_eq!(elf.class, if cfg!(target_pointer_width = "64") {"elf64"} else {"elf32"});
+        assert!(elf.needed.iter().any(|library| library.starts_with("libc.so")));
+        assert!(elf.interpreter.is_some() && !elf.static_linked);
+        assert_eq!(parse_elf(b"#!/bin/sh\necho hi\n"), None);
+    }
+
+    #[test]
+    fn binaries_are_hashed_once() {
+        let path = std::env::temp_dir().join(format!("node_agent-exe-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, b"#!/bin/sh\necho hi\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4775)).unwrap();
+        let mut cache = ExecutableCache::new();
+        let mut users = UserNames::new();
+        let executable = match cache.lookup(&path, "/usr/local/bin/hi", &Budget::unlimited(), &mut users) {
+            Lookup::New(executable) => executable,
+            _ => panic!("not hashed"),
+        };
+        //sha256sum of the script
+        assert_eq!(executable.sha256, format!("{:x}", Sha256::digest(b"#!/bin/sh\necho hi\n")));
+        assert_eq!((executable.path.as_str(), executable.mode.as_str(), executable.setuid, executable.writable_by_others), ("/usr/local/bin/hi", "4775", true, true));
+        assert_eq!(executable.elf, None);
+        assert!(matches!(cache.lookup(&path, "/usr/local/bin/hi", &Budget::unlimited(), &mut users), Lookup::Known(_)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hashing_carries_on_where_the_budget_stopped_it() {
+        let path = std::env::temp_dir().join(format!("node_agent-exe-{}", uuid::Uuid::new_v4()));
+        let contents: Vec<u8> = (0..CHUNK_BYTES * 3 + 100).map(|i| i as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let mut cache = ExecutableCache::new();
+        let mut users = UserNames::new();
+        //Stops after two chunks
+        let shutdown = node_agent::lifecycle::Shutdown::new();
+        let checks = std::cell::Cell::new(0);
+        let stop = shutdown.clone();
+        let budget = Budget::unlimited().with_shutdown(shutdown).with_heartbeat(std::rc::Rc::new(move || {
+            checks.set(checks.get() + 1);
+            if checks.get() > 2 {
+                stop.request();
+            }
+        }));
+        assert!(matches!(cache.lookup(&path, "/opt/app/big", &budget, &mut users), Lookup::Unfinished));
+        assert_eq!(cache.partial.values().map(|partial| partial.offset).collect::<Vec<_>>(), vec![2 * CHUNK_BYTES as u64]);
+        match cache.lookup(&path, "/opt/app/big", &Budget::unlimited(), &mut users) {
+            Lookup::New(executable) => assert_eq!(executable.sha256, format!("{:x}", Sha256::digest(&contents))),
+            _ => panic!("not hashed"),
+        }
+        assert!(cache.partial.is_empty());
+        assert!(matches!(cache.lookup(Path::new("/nonexistent/exe"), "/nonexistent/exe", &Budget::unlimited(), &mut users), Lookup::Unreadable));
+        fs::remove_file(&path).unwrap();
+    }
+}
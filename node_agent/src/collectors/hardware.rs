@@ -0,0 +1,307 @@
+//Hardware inventory in the shape of the README Agent schema: DMI data from /sys/class/dmi/id, CPU topology
+//from /proc/cpuinfo and memory totals from sysinfo.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use crate::linux::sys_interagator::SystemInfo;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use sysinfo::{MemoryRefreshKind, RefreshKind, System};
+
+const DMI_PATH: &str = "/sys/class/dmi/id";
+
+//Firmware provided identity of the machine. Serial numbers and the uuid are only readable by root.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Dmi {
+    pub sys_vendor: Option<String>,
+    pub product_name: Option<String>,
+    pub product_version: Option<String>,
+    pub product_serial: Option<String>,
+    pub product_uuid: Option<String>,
+    pub board_vendor: Option<String>,
+    pub board_name: Option<String>,
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_date: Option<String>,
+    pub chassis_vendor: Option<String>,
+    pub chassis_type: Option<String>,
+    pub chassis_asset_tag: Option<String>,
+}
+
+impl Dmi {
+    pub fn read() -> Self {
+        Self::read_from(Path::new(DMI_PATH))
+    }
+
+    pub fn read_from(dir: &Path) -> Self {
+        let field = |name: &str| -> Option<String> {
+            let value = fs::read_to_string(dir.join(name)).ok()?;
+            let value = value.trim();
+            //Vendors fill unused fields with all sorts of placeholders
+            match value {
+                "" | "None" | "Not Specified" | "Not Applicable" | "To Be Filled By O.E.M." | "Default string" | "System Serial Number" => None,
+                _ => Some(value.to_string()),
+            }
+        };
+        Self {
+            sys_vendor: field("sys_vendor"),
+            product_name: field("product_name"),
+            product_version: field("product_version"),
+            product_serial: field("product_serial"),
+            product_uuid: field("product_uuid"),
+            board_vendor: field("board_vendor"),
+            board_name: field("board_name"),
+            bios_vendor: field("bios_vendor"),
+            bios_version: field("bios_version"),
+            bios_date: field("bios_date"),
+            chassis_vendor: field("chassis_vendor"),
+            chassis_type: field("chassis_type").map(|t| Self::chassis_type_name(&t)),
+            chassis_asset_tag: field("chassis_asset_tag"),
+        }
+    }
+
+    //SMBIOS chassis types (DSP0134 7.4.1)
+    fn chassis_type_name(code: &str) -> String {
+        let name = match code {
+            "1" => "Other",
+            "2" => "Unknown",
+            "3" => "Desktop",
+            "4" => "Low Profile Desktop",
+            "5" => "Pizza Box",
+            "6" => "Mini Tower",
+            "7" => "Tower",
+            "8" => "Portable",
+            "9" => "Laptop",
+            "10" => "Notebook",
+            "11" => "Hand Held",
+            "12" => "Docking Station",
+            "13" => "All in One",
+            "14" => "Sub Notebook",
+            "15" => "Space-saving",
+            "16" => "Lunch Box",
+            "17" => "Main Server Chassis",
+            "18" => "Expansion Chassis",
+            "19" => "SubChassis",
+            "20" => "Bus Expansion Chassis",
+            "21" => "Peripheral Chassis",
+            "22" => "RAID Chassis",
+            "23" => "Rack Mount Chassis",
+            "24" => "Sealed-case PC",
+            "25" => "Multi-system chassis",
+            "26" => "Compact PCI",
+            "27" => "Advanced TCA",
+            "28" => "Blade",
+            "29" => "Blade Enclosure",
+            "30" => "Tablet",
+            "31" => "Convertible",
+            "32" => "Detachable",
+            "33" => "IoT Gateway",
+            "34" => "Embedded PC",
+            "35" => "Mini PC",
+            "36" => "Stick PC",
+            other => return other.to_string(),
+        };
+        name.to_string()
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct CpuInfo {
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub sockets: usize,
+    pub cores: usize,
+    pub threads: usize,
+    pub flags: Vec<String>,
+}
+
+impl CpuInfo {
+    pub fn read() -> Self {
+        Self::parse(&fs::read_to_string("/proc/cpuinfo").unwrap_or_default())
+    }
+
+    //One block per logical CPU. Sockets come from "physical id" and cores from distinct (physical id, core id) pairs,
+    //architectures which don't report them (most ARM) count as one socket with a core per thread.
+    pub fn parse(cpuinfo: &str) -> Self {
+        let mut cpu = CpuInfo::default();
+        let mut sockets = HashSet::new();
+        let mut cores = HashSet::new();
+        for block in cpuinfo.split("\n\n").filter(|b| b.contains("processor")) {
+            cpu.threads += 1;
+            let mut physical_id = None;
+            let mut core_id = None;
+            for line in block.lines() {
+                let (key, value) = match line.split_once(':') {
+                    Some((key, value)) => (key.trim(), value.trim()),
+                    None => continue,
+                };
+                match key {
+                    "model name" | "Model" if cpu.model.is_none() => cpu.model = Some(value.to_string()),
+                    "vendor_id" | "CPU implementer" if cpu.vendor.is_none() => cpu.vendor = Some(value.to_string()),
+                    "physical id" => physical_id = Some(value.to_string()),
+                    "core id" => core_id = Some(value.to_string()),
+                    "flags" | "Features" if cpu.flags.is_empty() => cpu.flags = value.split_whitespace().map(String::from).collect(),
+                    _ => {}
+                }
+            }
+            if let Some(physical_id) = physical_id {
+                sockets.insert(physical_id.clone());
+                if let Some(core_id) = core_id {
+                    cores.insert((physical_id, core_id));
+                }
+            }
+        }
+        cpu.sockets = sockets.len().max(if cpu.threads > 0 {1} else {0});
+        cpu.cores = if cores.is_empty() {cpu.threads} else {cores.len()};
+        cpu
+    }
+}
+
+//The "host" block of the README Agent schema plus the extra detail we have to hand
+#[derive(Serialize, Debug)]
+pub struct Host {
+    pub bios_manufacturer: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_date: Option<String>,
+    pub system_manufacturer: Option<String>,
+    pub system_product: Option<String>,
+    pub system_version: Option<String>,
+    pub system_serial: Option<String>,
+    pub system_uuid: Option<String>,
+    pub board_manufacturer: Option<String>,
+    pub board_product: Option<String>,
+    pub chassis_type: Option<String>,
+    pub chassis_manufacturer: Option<String>,
+    pub chassis_asset_tag: Option<String>,
+    pub cpu_model: Option<String>,
+    pub cpu_vendor: Option<String>,
+    pub cpu_sockets: usize,
+    pub cpu_cores: usize,
+    pub cpu_threads: usize,
+    pub memory_gb: f64,
+    pub memory_bytes: u64,
+    pub swap_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Hardware {
+    pub agent_id: String,
+    pub hostname: String,
+    pub host: Host,
+}
+
+impl Hardware {
+    pub fn new() -> Self {
+        let dmi = Dmi::read();
+        let cpu = CpuInfo::read();
+        let system = System::new_with_specifics(RefreshKind::new().with_memory(MemoryRefreshKind::everything()));
+        let memory_bytes = system.total_memory();
+        Self {
+            agent_id: SystemInfo::get_machineid(),
+            hostname: SystemInfo::get_hostname(),
+            host: Host {
+                bios_manufacturer: dmi.bios_vendor,
+                bios_version: dmi.bios_version,
+                bios_date: dmi.bios_date,
+                system_manufacturer: dmi.sys_vendor,
+                system_product: dmi.product_name,
+                system_version: dmi.product_version,
+                system_serial: dmi.product_serial,
+                system_uuid: dmi.product_uuid,
+                board_manufacturer: dmi.board_vendor,
+                board_product: dmi.board_name,
+                chassis_type: dmi.chassis_type,
+                chassis_manufacturer: dmi.chassis_vendor,
+                chassis_asset_tag: dmi.chassis_asset_tag,
+                cpu_model: cpu.model,
+                cpu_vendor: cpu.vendor,
+                cpu_sockets: cpu.sockets,
+                cpu_cores: cpu.cores,
+                cpu_threads: cpu.threads,
+                //Rounded to a tenth, a few MB reserved by firmware would otherwise make identical machines look different
+                memory_gb: (memory_bytes as f64 / (1024.0 * 1024.0 * 1024.0) * 10.0).round() / 10.0,
+                memory_bytes: memory_bytes,
+                swap_bytes: system.total_swap(),
+            },
+        }
+    }
+}
+
+pub struct HardwareCollector;
+
+impl HardwareCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for HardwareCollector {
+    fn name(&self) -> &str {
+        "hardware"
+    }
+    fn description(&self) -> &str {
+        "BIOS, system, chassis, CPU and memory inventory"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(86400))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        //product_serial and product_uuid are root only
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        Ok(vec![Record::new("hardware", &Hardware::new())?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_SOCKETS: &str = "processor\t: 0\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) Gold 6130 CPU @ 2.10GHz\nphysical id\t: 0\ncore id\t\t: 0\nflags\t\t: fpu vme hypervisor\n\n\
+processor\t: 1\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) Gold 6130 CPU @ 2.10GHz\nphysical id\t: 0\ncore id\t\t: 0\n\n\
+processor\t: 2\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) Gold 6130 CPU @ 2.10GHz\nphysical id\t: 1\ncore id\t\t: 0\n\n\
+processor\t: 3\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) Gold 6130 CPU @ 2.10GHz\nphysical id\t: 1\ncore id\t\t: 1\n\n";
+
+    #[test]
+    fn cpu_topology_is_counted() {
+        let cpu = CpuInfo::parse(TWO_SOCKETS);
+        assert_eq!(cpu.sockets, 2);
+        assert_eq!(cpu.cores, 3);
+        assert_eq!(cpu.threads, 4);
+        assert_eq!(cpu.vendor.as_deref(), Some("GenuineIntel"));
+        assert!(cpu.flags.contains(&"hypervisor".to_string()));
+    }
+
+    #[test]
+    fn cpu_without_topology_counts_threads() {
+        let cpu = CpuInfo::parse("processor\t: 0\nFeatures\t: fp asimd\n\nprocessor\t: 1\nFeatures\t: fp asimd\n\n");
+        assert_eq!(cpu.sockets, 1);
+        assert_eq!(cpu.cores, 2);
+    }
+
+    #[test]
+    fn dmi_placeholders_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("node_agent-dmi-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("bios_vendor"), "Dell Inc.\n").unwrap();
+        fs::write(dir.join("product_serial"), "To Be Filled By O.E.M.\n").unwrap();
+        fs::write(dir.join("chassis_type"), "23\n").unwrap();
+        let dmi = Dmi::read_from(&dir);
+        assert_eq!(dmi.bios_vendor.as_deref(), Some("Dell Inc."));
+        assert_eq!(dmi.product_serial, None);
+        assert_eq!(dmi.chassis_type.as_deref(), Some("Rack Mount Chassis"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn hardware_matches_agent_schema() {
+        let hardware = serde_json::to_value(Hardware::new()).unwrap();
+        assert!(hardware["host"]["cpu_cores"].as_u64().unwrap() > 0);
+        assert!(hardware["host"]["memory_gb"].as_f64().unwrap() > 0.0);
+        assert!(hardware["host"].get("bios_manufacturer").is_some());
+    }
+}
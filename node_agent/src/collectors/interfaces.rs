@@ -0,0 +1,294 @@
+//Every network interface on the node, up or down, with its link settings from /sys/class/net, how it is stacked
+//(bond, bridge and VLAN membership) and all of its addresses.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Record, Schedule};
+use pnet::datalink;
+use pnet::ipnetwork::IpNetwork;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::Duration;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const VLAN_CONFIG: &str = "/proc/net/vlan/config";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Address {
+    pub address: IpAddr,
+    pub prefix: u8,
+    pub family: String,
+    pub scope: String,
+}
+
+impl Address {
+    pub fn from_network(network: &IpNetwork) -> Self {
+        Self {
+            address: network.ip(),
+            prefix: network.prefix(),
+            family: if network.is_ipv4() {"ipv4".to_string()} else {"ipv6".to_string()},
+            scope: scope(&network.ip()).to_string(),
+        }
+    }
+}
+
+//Same names the kernel uses for address scopes (ip addr show)
+pub fn scope(address: &IpAddr) -> &'static str {
+    match address {
+        IpAddr::V4(v4) => ipv4_scope(v4),
+        IpAddr::V6(v6) => ipv6_scope(v6),
+    }
+}
+
+fn ipv4_scope(address: &Ipv4Addr) -> &'static str {
+    if address.is_loopback() {
+        "host"
+    } else if address.is_link_local() {
+        "link"
+    } else {
+        "global"
+    }
+}
+
+fn ipv6_scope(address: &Ipv6Addr) -> &'static str {
+    let first = address.segments()[0];
+    if address.is_loopback() {
+        "host"
+    } else if first & 0xffc0 == 0xfe80 {
+        "link"
+    } else if first & 0xffc0 == 0xfec0 {
+        "site"
+    } else {
+        "global"
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Bond {
+    pub mode: Option<String>,
+    pub slaves: Vec<String>,
+    pub active_slave: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Vlan {
+    pub id: Option<u16>,
+    pub parent: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Interface {
+    pub name: String,
+    pub index: Option<u32>,
+    pub kind: String,
+    pub mac: Option<String>,
+    pub mtu: Option<u32>,
+    pub operstate: Option<String>,
+    pub speed_mbps: Option<u32>,
+    pub duplex: Option<String>,
+    pub driver: Option<String>,
+    //Bond or bridge this interface is enslaved to
+    pub master: Option<String>,
+    pub bond: Option<Bond>,
+    pub bridge_ports: Vec<String>,
+    pub vlan: Option<Vlan>,
+    pub addresses: Vec<Address>,
+}
+
+impl Interface {
+    //Reads one interface from a /sys/class/net style directory. vlans is the parsed /proc/net/vlan/config.
+    pub fn read(sys_class_net: &Path, name: &str, vlans: &HashMap<String, (u16, String)>) -> Self {
+        let dir = sys_class_net.join(name);
+        let read = |file: &str| -> Option<String> {
+            fs::read_to_string(dir.join(file)).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+        };
+        let link_name = |file: &str| -> Option<String> {
+            fs::read_link(dir.join(file)).ok().and_then(|target| target.file_name().map(|n| n.to_string_lossy().into_owned()))
+        };
+        let list_dir = |file: &str| -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(dir.join(file))
+                .map(|entries| entries.filter_map(Result::ok).map(|e| e.file_name().to_string_lossy().into_owned()).collect())
+                .unwrap_or_default();
+            names.sort();
+            names
+        };
+
+        let bond = if dir.join("bonding").is_dir() {
+            Some(Bond {
+                //"802.3ad 4" -> "802.3ad"
+                mode: read("bonding/mode").and_then(|m| m.split_whitespace().next().map(String::from)),
+                slaves: read("bonding/slaves").map(|s| s.split_whitespace().map(String::from).collect()).unwrap_or_default(),
+                active_slave: read("bonding/active_slave"),
+            })
+        } else {
+            None
+        };
+        let is_bridge = dir.join("bridge").is_dir();
+        //VLAN parent shows up as a lower_<parent> link even when the 8021q proc file isn't there
+        let lower = list_dir("").into_iter().find_map(|entry| entry.strip_prefix("lower_").map(String::from));
+        let vlan = match vlans.get(name) {
+            Some((id, parent)) => Some(Vlan { id: Some(*id), parent: Some(parent.clone()) }),
+            None if read("uevent").map_or(false, |u| u.contains("DEVTYPE=vlan")) => Some(Vlan { id: None, parent: lower }),
+            None => None,
+        };
+        let kind = if name == "lo" {
+            "loopback"
+        } else if bond.is_some() {
+            "bond"
+        } else if is_bridge {
+            "bridge"
+        } else if vlan.is_some() {
+            "vlan"
+        } else if dir.join("device").exists() {
+            "physical"
+        } else {
+            "virtual"
+        };
+
+        Self {
+            name: name.to_string(),
+            index: read("ifindex").and_then(|i| i.parse().ok()),
+            kind: kind.to_string(),
+            mac: read("address").filter(|mac| mac != "00:00:00:00:00:00"),
+            mtu: read("mtu").and_then(|m| m.parse().ok()),
+            operstate: read("operstate"),
+            //Reads fail or give -1 when the link is down or the driver doesn't know
+            speed_mbps: read("speed").and_then(|s| s.parse::<i64>().ok()).filter(|s| *s > 0).map(|s| s as u32),
+            duplex: read("duplex"),
+            driver: link_name("device/driver"),
+            master: link_name("master"),
+            bond: bond,
+            bridge_ports: if is_bridge {list_dir("brif")} else {Vec::new()},
+            vlan: vlan,
+            addresses: Vec::new(),
+        }
+    }
+}
+
+//"eth0.100       | 100  | eth0" lines after the two header lines
+pub fn parse_vlan_config(contents: &str) -> HashMap<String, (u16, String)> {
+    let mut vlans = HashMap::new();
+    for line in contents.lines().skip(2) {
+        let columns: Vec<&str> = line.split('|').map(|c| c.trim()).collect();
+        if columns.len() == 3 {
+            if let Ok(id) = columns[1].parse() {
+                vlans.insert(columns[0].to_string(), (id, columns[2].to_string()));
+            }
+        }
+    }
+    vlans
+}
+
+pub fn get_interfaces() -> Vec<Interface> {
+    let vlans = fs::read_to_string(VLAN_CONFIG).map(|c| parse_vlan_config(&c)).unwrap_or_default();
+    let mut addresses: HashMap<String, Vec<Address>> = HashMap::new();
+    for interface in datalink::interfaces() {
+        addresses.entry(interface.name.clone()).or_default().extend(interface.ips.iter().map(Address::from_network));
+    }
+    let mut names: Vec<String> = fs::read_dir(SYS_CLASS_NET)
+        .map(|entries| entries.filter_map(Result::ok).map(|e| e.file_name().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+        .iter()
+        .map(|name| {
+            let mut interface = Interface::read(Path::new(SYS_CLASS_NET), name, &vlans);
+            interface.addresses = addresses.remove(name).unwrap_or_default();
+            interface
+        })
+        .collect()
+}
+
+pub struct InterfaceCollector;
+
+impl InterfaceCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for InterfaceCollector {
+    fn name(&self) -> &str {
+        "interfaces"
+    }
+    fn description(&self) -> &str {
+        "Network interfaces with link settings, bond/bridge/VLAN membership and addresses"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(600))
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        for interface in get_interfaces() {
+            records.push(Record::new("interfaces", &interface)?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn write(dir: &Path, file: &str, contents: &str) {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn bond_and_slaves_are_read() {
+        let root = std::env::temp_dir().join(format!("node_agent-net-{}", uuid::Uuid::new_v4()));
+        let bond0 = root.join("bond0");
+        write(&bond0, "bonding/mode", "802.3ad 4\n");
+        write(&bond0, "bonding/slaves", "eth0 eth1\n");
+        write(&bond0, "mtu", "9000\n");
+        write(&bond0, "speed", "20000\n");
+        let eth0 = root.join("eth0");
+        write(&eth0, "address", "52:54:00:12:34:56\n");
+        write(&eth0, "speed", "-1\n");
+        fs::create_dir_all(root.join("drivers/ixgbe")).unwrap();
+        fs::create_dir_all(eth0.join("device")).unwrap();
+        symlink(root.join("drivers/ixgbe"), eth0.join("device/driver")).unwrap();
+        symlink(&bond0, eth0.join("master")).unwrap();
+
+        let bond = Interface::read(&root, "bond0", &HashMap::new());
+        assert_eq!(bond.kind, "bond");
+        assert_eq!(bond.mtu, Some(9000));
+        assert_eq!(bond.bond, Some(Bond { mode: Some("802.3ad".to_string()), slaves: vec!["eth0".to_string(), "eth1".to_string()], active_slave: None }));
+
+        let eth = Interface::read(&root, "eth0", &HashMap::new());
+        assert_eq!(eth.kind, "physical");
+        assert_eq!(eth.driver.as_deref(), Some("ixgbe"));
+        assert_eq!(eth.master.as_deref(), Some("bond0"));
+        assert_eq!(eth.speed_mbps, None);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn vlan_config_is_parsed() {
+        let vlans = parse_vlan_config("VLAN Dev name    | VLAN ID\nName-Type: VLAN_NAME_TYPE_RAW_PLUS_VID_NO_PAD\neth0.100       | 100  | eth0\n");
+        assert_eq!(vlans.get("eth0.100"), Some(&(100, "eth0".to_string())));
+    }
+
+    #[test]
+    fn address_scopes() {
+        assert_eq!(scope(&"127.0.0.1".parse().unwrap()), "host");
+        assert_eq!(scope(&"169.254.1.1".parse().unwrap()), "link");
+        assert_eq!(scope(&"fe80::1".parse().unwrap()), "link");
+        assert_eq!(scope(&"2001:db8::1".parse().unwrap()), "global");
+        let address = Address::from_network(&"10.1.2.3/24".parse().unwrap());
+        assert_eq!((address.prefix, address.family.as_str()), (24, "ipv4"));
+    }
+
+    #[test]
+    fn local_interfaces_include_loopback() {
+        let interfaces = get_interfaces();
+        let lo = interfaces.iter().find(|i| i.name == "lo").unwrap();
+        assert_eq!(lo.kind, "loopback");
+        assert!(lo.addresses.iter().any(|a| a.scope == "host"));
+    }
+}
@@ -0,0 +1,479 @@
+//Scheduled work on the node: system and user crontabs, the /etc/cron.* run-parts directories, anacron and systemd
+//timers, each with its owner, what it runs and when it next runs. Batch jobs make connections a point in time
+//connection scan rarely catches, this says what to expect.
+use crate::budget::Budget;
+use crate::collectors::units::{self, UnitState, UNIT_DIRS};
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use chrono::{DateTime, Datelike, Duration as Days, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const USER_CRONTAB_DIRS: &[&str] = &["/var/spool/cron/crontabs", "/var/spool/cron"];
+//run-parts directories and the period they're named for. When they actually run comes from whichever crontab or
+//anacrontab line runs them.
+const CRON_PERIOD_DIRS: &[(&str, &str)] = &[("/etc/cron.hourly", "@hourly"), ("/etc/cron.daily", "@daily"), ("/etc/cron.weekly", "@weekly"), ("/etc/cron.monthly", "@monthly")];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    //crontab, user_crontab, cron_period, anacron or systemd_timer
+    pub source: String,
+    pub file: String,
+    pub schedule: String,
+    pub command: String,
+    pub owner: Option<String>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    //The service a timer activates
+    pub unit: Option<String>,
+    //anacron's name for the job, also the name of its timestamp file
+    pub job_id: Option<String>,
+    //Script or binary the job runs, when it's an absolute path
+    pub script: Option<String>,
+}
+
+impl Job {
+    fn new(source: &str, file: &Path, schedule: &str, command: &str, owner: Option<String>) -> Self {
+        Self {
+            source: source.to_string(),
+            file: file.display().to_string(),
+            schedule: schedule.to_string(),
+            command: command.to_string(),
+            owner: owner,
+            next_run: None,
+            last_run: None,
+            unit: None,
+            job_id: None,
+            script: script_path(command),
+        }
+    }
+}
+
+//First word of the command if it's a path, skipping "run-parts" style wrappers to the directory they run
+fn script_path(command: &str) -> Option<String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let start = if words.first().map_or(false, |w| w.ends_with("run-parts")) {1} else {0};
+    words.iter().skip(start).find(|w| !w.starts_with('-')).filter(|w| w.starts_with('/')).map(|w| w.to_string())
+}
+
+//One cron field as the set of values it matches
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    matches: Vec<bool>,
+    //"*" in day of month or day of week changes how the two combine
+    star: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Option<Field> {
+    let mut matches = vec![false; max as usize + 1];
+    let value = |v: &str| -> Option<u32> {
+        v.parse().ok().or_else(|| names.iter().position(|n| n.eq_ignore_ascii_case(v)).map(|i| i as u32 + min))
+    };
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (value(start)?, value(end)?)
+        } else {
+            //"5/15" runs from 5 to the end of the range
+            let start = value(range)?;
+            (start, if part.contains('/') {max} else {start})
+        };
+        if start < min || end > max || start > end {
+            return None
+        }
+        for v in (start..=end).step_by(step as usize) {
+            matches[v as usize] = true;
+        }
+    }
+    Some(Field { matches: matches, star: field.starts_with('*') })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl CronSchedule {
+    //Five field expressions or the @ shortcuts, None for @reboot and anything we can't read
+    pub fn parse(expression: &str) -> Option<Self> {
+        let expression = match expression {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None
+        }
+        let months = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+        let weekdays = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+        let mut weekday_field = parse_field(fields[4], 0, 7, &weekdays)?;
+        //7 is Sunday too
+        if weekday_field.matches[7] {
+            weekday_field.matches[0] = true;
+        }
+        Some(Self {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &months)?,
+            weekdays: weekday_field,
+        })
+    }
+
+    //When both day fields are restricted cron runs on either, otherwise on the restricted one
+    fn day_matches(&self, day: u32, weekday: u32) -> bool {
+        let day_match = self.days.matches[day as usize];
+        let weekday_match = self.weekdays.matches[weekday as usize];
+        if self.days.star || self.weekdays.star {
+            day_match && weekday_match
+        } else {
+            day_match || weekday_match
+        }
+    }
+
+    //First matching minute after from, in local time as cron uses. Searches a few years ahead for Feb 29th jobs.
+    pub fn next_after<Tz: TimeZone>(&self, from: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = from.timezone();
+        let start = from.naive_local().with_second(0)?.with_nanosecond(0)? + Days::minutes(1);
+        for offset in 0..(366 * 5) {
+            let date = start.date() + Days::days(offset);
+            if !self.months.matches[date.month() as usize] || !self.day_matches(date.day(), date.weekday().num_days_from_sunday()) {
+                continue;
+            }
+            for hour in (0..24).filter(|h| self.hours.matches[*h as usize]) {
+                for minute in (0..60).filter(|m| self.minutes.matches[*m as usize]) {
+                    let candidate: NaiveDateTime = date.and_hms_opt(hour, minute, 0)?;
+                    if candidate < start {
+                        continue;
+                    }
+                    //Skips times a DST change jumps over
+                    if let Some(time) = timezone.from_local_datetime(&candidate).earliest() {
+                        return Some(time)
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+fn next_run(schedule: &str) -> Option<DateTime<Utc>> {
+    CronSchedule::parse(schedule).and_then(|s| s.next_after(&Local::now())).map(|t| t.with_timezone(&Utc))
+}
+
+//Splits a crontab line into schedule and the rest, @ shortcuts are one word and expressions five
+fn split_schedule(line: &str) -> Option<(String, &str)> {
+    let words = if line.starts_with('@') {1} else {5};
+    let mut rest = line;
+    let mut schedule = Vec::new();
+    for _ in 0..words {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        schedule.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    Some((schedule.join(" "), rest.trim()))
+}
+
+//System crontabs (/etc/crontab, /etc/cron.d) have a user column, user crontabs belong to their file's user
+pub fn parse_crontab(contents: &str, file: &Path, user: Option<&str>) -> Vec<Job> {
+    let mut jobs = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        //Environment settings, NAME=value before any spaces
+        if line.split_whitespace().next().map_or(false, |word| word.contains('=') && !word.starts_with('@')) {
+            continue;
+        }
+        let (schedule, rest) = match split_schedule(line) {
+            Some(split) => split,
+            None => continue,
+        };
+        let (owner, command) = match user {
+            Some(user) => (user.to_string(), rest),
+            None => match rest.split_once(char::is_whitespace) {
+                Some((owner, command)) => (owner.to_string(), command.trim()),
+                None => continue,
+            },
+        };
+        let mut job = Job::new(if user.is_some() {"user_crontab"} else {"crontab"}, file, &schedule, command, Some(owner));
+        job.next_run = next_run(&schedule);
+        jobs.push(job);
+    }
+    jobs
+}
+
+//period(days or @monthly) delay job-id command
+pub fn parse_anacrontab(contents: &str, file: &Path) -> Vec<Job> {
+    let mut jobs = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.split_whitespace().next().map_or(false, |w| w.contains('=')) {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        let schedule = if fields[0].starts_with('@') {fields[0].to_string()} else {format!("every {} days", fields[0])};
+        let command = fields[3..].join(" ");
+        let mut job = Job::new("anacron", file, &schedule, &command, Some("root".to_string()));
+        job.job_id = Some(fields[2].to_string());
+        jobs.push(job);
+    }
+    jobs
+}
+
+//Whether a job is "run-parts ... <dir>"
+fn runs_parts_of(job: &Job, dir: &str) -> bool {
+    job.command.contains("run-parts") && job.command.split(|c: char| c.is_whitespace() || c == ';').any(|word| word.trim_end_matches('/') == dir)
+}
+
+//The scripts in a run-parts directory take their timing from the job that runs the directory. Debian's /etc/crontab
+//line steps aside with "test -x /usr/sbin/anacron ||" when anacron is installed, and Red Hat leaves the daily and
+//longer ones to anacron, so an anacron job wins over a crontab line guarded like that.
+pub fn cron_period_jobs(dir: &str, period: &str, scripts: &[PathBuf], runners: &[Job]) -> Vec<Job> {
+    let crontab = runners.iter().find(|job| job.source == "crontab" && runs_parts_of(job, dir));
+    let anacron = runners.iter().find(|job| job.source == "anacron" && runs_parts_of(job, dir));
+    let runner = match (crontab, anacron) {
+        (Some(crontab), Some(anacron)) if crontab.command.contains("anacron") => Some(anacron),
+        (Some(crontab), _) => Some(crontab),
+        (None, anacron) => anacron,
+    };
+    scripts.iter().map(|script| {
+        let mut job = Job::new("cron_period", Path::new(dir), period, &script.display().to_string(), Some("root".to_string()));
+        //anacron runs a job once its period has passed since the last run, whenever anacron itself is started, so
+        //there's no next run time to give
+        if let Some(runner) = runner {
+            job.schedule = runner.schedule.clone();
+            job.next_run = runner.next_run;
+            job.job_id = runner.job_id.clone();
+        }
+        job
+    }).collect()
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        //Package manager leftovers and placeholders are never run
+        .filter(|path| path.is_file() && path.file_name().map_or(false, |n| {
+            let name = n.to_string_lossy();
+            !name.starts_with('.') && !name.ends_with(".dpkg-dist") && !name.ends_with(".dpkg-old") && !name.ends_with(".rpmsave") && !name.ends_with(".rpmnew") && name != "placeholder"
+        }))
+        .collect();
+    files.sort();
+    files
+}
+
+fn cron_jobs() -> Vec<Job> {
+    let mut jobs = Vec::new();
+    let mut system = vec![PathBuf::from("/etc/crontab")];
+    system.extend(files_in(Path::new("/etc/cron.d")));
+    for file in system {
+        if let Ok(contents) = fs::read_to_string(&file) {
+            jobs.extend(parse_crontab(&contents, &file, None));
+        }
+    }
+    //Debian keeps them in crontabs/, Red Hat directly in the spool dir
+    for dir in USER_CRONTAB_DIRS {
+        for file in files_in(Path::new(dir)) {
+            let user = file.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            if let Ok(contents) = fs::read_to_string(&file) {
+                jobs.extend(parse_crontab(&contents, &file, Some(&user)));
+            }
+        }
+    }
+    if let Ok(contents) = fs::read_to_string("/etc/anacrontab") {
+        jobs.extend(parse_anacrontab(&contents, Path::new("/etc/anacrontab")));
+    }
+    for (dir, period) in CRON_PERIOD_DIRS {
+        let scripts = files_in(Path::new(dir));
+        let period_jobs = cron_period_jobs(dir, period, &scripts, &jobs);
+        jobs.extend(period_jobs);
+    }
+    jobs
+}
+
+//"@1700000000" from --timestamp=unix, older systemctl gives "Mon 2024-01-01 00:00:00 UTC"
+pub fn parse_systemd_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Some(secs) = value.strip_prefix('@') {
+        return secs.parse().ok().and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+    }
+    let (time, zone) = value.rsplit_once(' ')?;
+    let naive = NaiveDateTime::parse_from_str(time, "%a %Y-%m-%d %H:%M:%S").ok()?;
+    if zone == "UTC" {
+        Some(Utc.from_utc_datetime(&naive))
+    } else {
+        Local.from_local_datetime(&naive).earliest().map(|t| t.with_timezone(&Utc))
+    }
+}
+
+//OnCalendar= and the monotonic On*Sec= settings, joined as the schedule
+pub fn timer_job(dirs: &[PathBuf], name: &str, state: Option<&UnitState>) -> Job {
+    let (path, file) = units::load_unit_file(dirs, name);
+    let timer = file.get("Timer").cloned().unwrap_or_default();
+    let mut schedule: Vec<String> = Vec::new();
+    for key in ["OnCalendar", "OnBootSec", "OnStartupSec", "OnActiveSec", "OnUnitActiveSec", "OnUnitInactiveSec"] {
+        for value in timer.get(key).into_iter().flatten() {
+            schedule.push(format!("{}={}", key, value));
+        }
+    }
+    //A timer activates the service of the same name unless Unit= says otherwise
+    let unit = timer.get("Unit").and_then(|u| u.last().cloned())
+        .or_else(|| state.and_then(|s| s.unit.clone()))
+        .unwrap_or_else(|| format!("{}.service", name.trim_end_matches(".timer")));
+    let service = units::Unit::read(dirs, &unit);
+    let command = service.exec_start.first().cloned().unwrap_or_default();
+    let file = path.map(|p| p.display().to_string()).unwrap_or_default();
+    let mut job = Job::new("systemd_timer", Path::new(&file), &schedule.join(" "), &command, Some(service.user.unwrap_or_else(|| "root".to_string())));
+    //ExecStart can carry prefixes such as "-" or "+" before the path
+    job.script = script_path(command.trim_start_matches(['-', '+', '@', '!', ':']));
+    job.unit = Some(unit);
+    job.next_run = state.and_then(|s| s.next_elapse.as_deref()).and_then(parse_systemd_timestamp);
+    job.last_run = state.and_then(|s| s.last_trigger.as_deref()).and_then(parse_systemd_timestamp);
+    job
+}
+
+fn timer_jobs(budget: &Budget) -> Vec<Job> {
+    let dirs: Vec<PathBuf> = UNIT_DIRS.iter().map(PathBuf::from).collect();
+    let states = units::unit_states(&["*.timer".to_string()]);
+    let mut jobs = Vec::new();
+    for name in units::unit_names(&dirs).into_iter().filter(|name| name.ends_with(".timer")) {
+        if !budget.tick() {
+            break;
+        }
+        jobs.push(timer_job(&dirs, &name, states.get(&name)));
+    }
+    jobs
+}
+
+pub struct JobCollector;
+
+impl JobCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for JobCollector {
+    fn name(&self) -> &str {
+        "jobs"
+    }
+    fn description(&self) -> &str {
+        "Cron jobs, anacron and systemd timers with their next run"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(3600))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut jobs = cron_jobs();
+        jobs.extend(timer_jobs(budget));
+        let mut records = Vec::new();
+        for job in jobs {
+            records.push(Record::new("jobs", &job)?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap())
+    }
+
+    #[test]
+    fn next_run_follows_cron_rules() {
+        let from = at("2026-10-19 10:07");
+        let next = |expression: &str| CronSchedule::parse(expression).unwrap().next_after(&from).unwrap();
+        assert_eq!(next("*/15 * * * *"), at("2026-10-19 10:15"));
+        assert_eq!(next("30 2 * * *"), at("2026-10-20 02:30"));
+        assert_eq!(next("0 9 * * mon-fri"), at("2026-10-20 09:00"));
+        assert_eq!(next("@monthly"), at("2026-11-01 00:00"));
+        //Day of month or Sunday when both are restricted
+        assert_eq!(next("0 0 25 * 7"), at("2026-10-25 00:00"));
+        assert_eq!(next("0 0 29 feb *"), at("2028-02-29 00:00"));
+        assert_eq!(CronSchedule::parse("@reboot"), None);
+        assert_eq!(CronSchedule::parse("61 * * * *"), None);
+    }
+
+    #[test]
+    fn crontabs_are_parsed() {
+        let system = parse_crontab("SHELL=/bin/sh\n# comment\n17 *\t* * *\troot\tcd / && run-parts --report /etc/cron.hourly\n@reboot backup /opt/backup/bin/resume.sh --quiet\n", Path::new("/etc/crontab"), None);
+        assert_eq!(system.len(), 2);
+        assert_eq!(system[0].schedule, "17 * * * *");
+        assert_eq!(system[0].owner.as_deref(), Some("root"));
+        assert_eq!(system[0].command, "cd / && run-parts --report /etc/cron.hourly");
+        assert_eq!(system[1].script.as_deref(), Some("/opt/backup/bin/resume.sh"));
+        assert_eq!(system[1].next_run, None);
+
+        let user = parse_crontab("0 3 * * * /home/alice/sync.sh > /dev/null 2>&1\n", Path::new("/var/spool/cron/crontabs/alice"), Some("alice"));
+        assert_eq!((user[0].source.as_str(), user[0].owner.as_deref()), ("user_crontab", Some("alice")));
+        assert!(user[0].next_run.is_some());
+    }
+
+    #[test]
+    fn anacron_and_timers() {
+        let jobs = parse_anacrontab("SHELL=/bin/sh\n1\t5\tcron.daily\trun-parts --report /etc/cron.daily\n@monthly 15 cron.monthly run-parts /etc/cron.monthly\n", Path::new("/etc/anacrontab"));
+        assert_eq!(jobs[0].schedule, "every 1 days");
+        assert_eq!(jobs[0].script.as_deref(), Some("/etc/cron.daily"));
+        assert_eq!(jobs[1].schedule, "@monthly");
+        assert_eq!((jobs[0].job_id.as_deref(), jobs[0].unit.as_deref()), (Some("cron.daily"), None));
+
+        let root = std::env::temp_dir().join(format!("node_agent-timers-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("logrotate.timer"), "[Timer]\nOnCalendar=daily\nPersistent=true\n").unwrap();
+        fs::write(root.join("logrotate.service"), "[Service]\nExecStart=/usr/sbin/logrotate /etc/logrotate.conf\n").unwrap();
+        let state = UnitState { next_elapse: Some("@1760918400".to_string()), ..Default::default() };
+        let job = timer_job(&[root.clone()], "logrotate.timer", Some(&state));
+        assert_eq!(job.schedule, "OnCalendar=daily");
+        assert_eq!(job.unit.as_deref(), Some("logrotate.service"));
+        assert_eq!(job.script.as_deref(), Some("/usr/sbin/logrotate"));
+        assert_eq!(job.next_run.map(|t| t.timestamp()), Some(1760918400));
+        assert_eq!(parse_systemd_timestamp("Mon 2026-10-19 00:00:00 UTC").map(|t| t.timestamp()), Some(1792368000));
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn period_dirs_take_their_runners_timing() {
+        let scripts = vec![PathBuf::from("/etc/cron.daily/logrotate")];
+        //Debian without anacron: 06:25 from /etc/crontab
+        let mut runners = parse_crontab("17 * * * * root cd / && run-parts --report /etc/cron.hourly
+25 6 * * * root test -x /usr/sbin/anacron || { cd / && run-parts --report /etc/cron.daily; }
+", Path::new("/etc/crontab"), None);
+        let daily = cron_period_jobs("/etc/cron.daily", "@daily", &scripts, &runners);
+        assert_eq!(daily[0].schedule, "25 6 * * *");
+        assert_eq!(daily[0].next_run, runners[1].next_run);
+        //With anacron installed it runs them
+        runners.extend(parse_anacrontab("1\t5\tcron.daily\trun-parts --report /etc/cron.daily\n", Path::new("/etc/anacrontab")));
+        let daily = cron_period_jobs("/etc/cron.daily", "@daily", &scripts, &runners);
+        assert_eq!((daily[0].schedule.as_str(), daily[0].next_run, daily[0].job_id.as_deref()), ("every 1 days", None, Some("cron.daily")));
+        //Nothing runs cron.weekly here, so no next run rather than a made up one
+        let weekly = cron_period_jobs("/etc/cron.weekly", "@weekly", &[PathBuf::from("/etc/cron.weekly/man-db")], &runners);
+        assert_eq!((weekly[0].schedule.as_str(), weekly[0].next_run), ("@weekly", None));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::budget::Budget;
+use crate::collectors::packages::{OwnerIndex, SharedOwners};
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use crate::linux::sys_interagator::Processes;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+//Shared libraries actually mapped into running processes, from /proc/<pid>/maps, so "who has this libssl loaded"
+//can be answered rather than just "who has it installed". One record per library with the processes that have it
+//mapped, all carrying the same snapshot id, then a last record saying how many there were and whether the budget let
+//us look at every process. A node's whole set would easily outgrow Kafka's message size limit.
+pub struct LibraryCollector {
+    owners: SharedOwners,
+}
+
+impl LibraryCollector {
+    pub fn new(owners: SharedOwners) -> Self {
+        Self { owners: owners }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LibraryUser {
+    pub pid: u32,
+    pub exe: String,
+    pub unit: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Library {
+    pub path: String,
+    //File name, e.g. libssl.so.3
+    pub name: String,
+    //From the file name, "3" for libssl.so.3 and "2.31" for libc-2.31.so
+    pub version: Option<String>,
+    pub package: Option<String>,
+    pub package_version: Option<String>,
+    //Replaced or removed on disk since it was loaded, typically by an upgrade the process hasn't been restarted for
+    pub deleted: bool,
+    pub processes: Vec<LibraryUser>,
+}
+
+fn is_library(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.ends_with(".so") || name.contains(".so.")
+}
+
+//File backed mappings of shared objects, each path once. Lines look like
+//"7f3c1a400000-7f3c1a48e000 r-xp 00028000 fd:01 1835301    /usr/lib/x86_64-linux-gnu/libssl.so.3"
+pub fn parse_maps(contents: &str) -> BTreeSet<String> {
+    let mut libraries = BTreeSet::new();
+    for line in contents.lines() {
+        //Paths can hold spaces so only split off the first five fields
+        let fields: Vec<&str> = line.splitn(6, char::is_whitespace).collect();
+        if fields.len() < 6 || fields[4] == "0" {
+            continue
+        }
+        let path = fields[5].trim();
+        if path.starts_with('/') && is_library(path.strip_suffix(" (deleted)").unwrap_or(path)) {
+            libraries.insert(path.to_string());
+        }
+    }
+    libraries
+}
+
+pub fn library_version(name: &str) -> Option<String> {
+    if let Some((_, version)) = name.split_once(".so.") {
+        return Some(version.to_string())
+    }
+    let stem = name.strip_suffix(".so")?;
+    let (_, version) = stem.rsplit_once('-')?;
+    if version.starts_with(|c: char| c.is_ascii_digit()) {Some(version.to_string())} else {None}
+}
+
+pub fn library(path: &str, owners: &OwnerIndex) -> Library {
+    let deleted = path.ends_with(" (deleted)");
+    let path = path.strip_suffix(" (deleted)").unwrap_or(path);
+    let name = path.rsplit('/').next().unwrap_or(path).to_string();
+    let owner = owners.owner(path);
+    Library {
+        path: path.to_string(),
+        version: library_version(&name),
+        name: name,
+        package: owner.map(|(name, _)| name.clone()),
+        package_version: owner.map(|(_, version)| version.clone()),
+        deleted: deleted,
+        processes: Vec::new(),
+    }
+}
+
+pub fn snapshot_records(snapshot: &str, libraries: impl Iterator<Item = Library>, complete: bool) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut records = Vec::new();
+    for library in libraries {
+        let mut record = serde_json::to_value(&library)?;
+        record["snapshot"] = json!(snapshot);
+        records.push(Record::new("libraries", &record)?);
+    }
+    records.push(Record::new("libraries", &json!({
+        "snapshot": snapshot,
+        "snapshot_end": {"libraries": records.len(), "complete": complete},
+    }))?);
+    Ok(records)
+}
+
+impl Collector for LibraryCollector {
+    fn name(&self) -> &str {
+        "libraries"
+    }
+    fn description(&self) -> &str {
+        "Shared libraries mapped into running processes, with their owning package and the processes using them"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(900))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Capability("CAP_SYS_PTRACE")]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        self.owners.borrow_mut().refresh_if_changed(budget);
+        let owners = self.owners.borrow();
+        let mut processes: Vec<_> = Processes::new_within(budget).processes.into_iter().collect();
+        processes.sort_by_key(|process| process.pid.parse::<u32>().unwrap_or(0));
+        let mut libraries: BTreeMap<String, Library> = BTreeMap::new();
+        for process in processes {
+            if !budget.tick() {
+                break
+            }
+            let (pid, maps) = match (process.pid.parse(), fs::read_to_string(format!("/proc/{}/maps", process.pid))) {
+                (Ok(pid), Ok(maps)) => (pid, maps),
+                _ => continue,
+            };
+            for path in parse_maps(&maps) {
+                libraries.entry(path.clone()).or_insert_with(|| library(&path, &owners)).processes.push(LibraryUser {
+                    pid: pid,
+                    exe: process.exe.clone(),
+                    unit: process.unit.clone(),
+                });
+            }
+        }
+        snapshot_records(&uuid::Uuid::new_v4().to_string(), libraries.into_values(), budget.cut_short().is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = "\
+55d4c8a00000-55d4c8a2c000 r--p 00000000 fd:01 1311234                    /usr/sbin/nginx
+7f3c1a400000-7f3c1a48e000 r--p 00000000 fd:01 1835301                    /usr/lib/x86_64-linux-gnu/libssl.so.3
+7f3c1a48e000-7f3c1a5a0000 r-xp 0008e000 fd:01 1835301                    /usr/lib/x86_64-linux-gnu/libssl.so.3
+7f3c1a600000-7f3c1a628000 r--p 00000000 fd:01 1835000                    /usr/lib/x86_64-linux-gnu/libc.so.6 (deleted)
+7f3c1a700000-7f3c1a701000 r--p 00000000 fd:01 1900000                    /opt/my app/lib/libfoo-1.2.so
+7f3c1a800000-7f3c1a821000 rw-p 00000000 00:00 0                          [heap]
+7f3c1a900000-7f3c1a921000 rw-p 00000000 00:00 0
+7f3c1aa00000-7f3c1aa21000 r--s 00000000 00:05 4242                       /dev/shm/cache
+";
+
+    #[test]
+    fn maps_give_each_library_once() {
+        let libraries: Vec<String> = parse_maps(MAPS).into_iter().collect();
+        assert_eq!(libraries, vec![
+            "/opt/my app/lib/libfoo-1.2.so".to_string(),
+            "/usr/lib/x86_64-linux-gnu/libc.so.6 (deleted)".to_string(),
+            "/usr/lib/x86_64-linux-gnu/libssl.so.3".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn libraries_resolve_to_packages() {
+        let mut owners = OwnerIndex::new();
+        owners.add_rpm("/usr/lib/x86_64-linux-gnu/libc.so.6\tglibc\t0:2.34-100.el9\n");
+        let libc = library("/usr/lib/x86_64-linux-gnu/libc.so.6 (deleted)", &owners);
+        assert_eq!((libc.name.as_str(), libc.version.as_deref(), libc.package.as_deref(), libc.deleted), ("libc.so.6", Some("6"), Some("glibc"), true));
+        assert_eq!(library("/opt/my app/lib/libfoo-1.2.so", &owners).version.as_deref(), Some("1.2"));
+        assert_eq!(library_version("libfoo.so"), None);
+        assert_eq!(library_version("libstdc++.so.6.0.30").as_deref(), Some("6.0.30"));
+    }
+
+    #[test]
+    fn one_record_per_library_then_the_count() {
+        let owners = OwnerIndex::new();
+        let libraries = parse_maps(MAPS).iter().map(|path| library(path, &owners)).collect::<Vec<_>>();
+        let records = snapshot_records("s1", libraries.into_iter(), false).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!((records[0].payload["path"].as_str(), records[0].payload["snapshot"].as_str()), (Some("/opt/my app/lib/libfoo-1.2.so"), Some("s1")));
+        assert_eq!(records[3].payload["snapshot_end"], json!({"libraries": 3, "complete": false}));
+    }
+
+    #[test]
+    fn own_maps_have_libc() {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        assert!(parse_maps(&maps).iter().any(|path| path.contains("libc")));
+    }
+}
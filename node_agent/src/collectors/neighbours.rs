@@ -0,0 +1,191 @@
+//Layer 2 neighbours: hosts the node has recently resolved on its local segments, whether or not there is a connection
+//to them right now. IPv4 comes from /proc/net/arp, IPv6 only has a netlink view (RTM_GETNEIGH).
+use crate::budget::Budget;
+use crate::collectors::{Collector, Record, Schedule};
+use crate::netlink;
+use log::*;
+use serde::Serialize;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+const RTM_NEWNEIGH: u16 = 28;
+const RTM_GETNEIGH: u16 = 30;
+const NDA_DST: u16 = 1;
+const NDA_LLADDR: u16 = 2;
+//sizeof(struct ndmsg)
+const NDMSG_LEN: usize = 12;
+
+//ATF_* flags from /proc/net/arp
+const ATF_COM: u32 = 0x02;
+const ATF_PERM: u32 = 0x04;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Neighbour {
+    pub ip_address: IpAddr,
+    pub mac: Option<String>,
+    pub interface: String,
+    //Kernel NUD state names (reachable, stale, permanent...), /proc/net/arp only tells complete from incomplete
+    pub state: String,
+}
+
+//IP address, HW type, Flags, HW address, Mask, Device
+pub fn parse_arp(contents: &str) -> Vec<Neighbour> {
+    let mut neighbours = Vec::new();
+    for line in contents.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 6 {
+            continue;
+        }
+        let (ip_address, flags) = match (columns[0].parse(), u32::from_str_radix(columns[2].trim_start_matches("0x"), 16)) {
+            (Ok(ip_address), Ok(flags)) => (ip_address, flags),
+            _ => continue,
+        };
+        let state = if flags & ATF_PERM != 0 {
+            "permanent"
+        } else if flags & ATF_COM != 0 {
+            "reachable"
+        } else {
+            "incomplete"
+        };
+        neighbours.push(Neighbour {
+            ip_address: ip_address,
+            mac: Some(columns[3].to_lowercase()).filter(|mac| mac != "00:00:00:00:00:00"),
+            interface: columns[5].to_string(),
+            state: state.to_string(),
+        });
+    }
+    neighbours
+}
+
+//NUD_* from linux/neighbour.h
+pub fn nud_state(state: u16) -> &'static str {
+    match state {
+        0x01 => "incomplete",
+        0x02 => "reachable",
+        0x04 => "stale",
+        0x08 => "delay",
+        0x10 => "probe",
+        0x20 => "failed",
+        0x40 => "noarp",
+        0x80 => "permanent",
+        _ => "none",
+    }
+}
+
+//Decodes one RTM_NEWNEIGH payload: struct ndmsg then NDA_* attributes
+pub fn parse_ndmsg(payload: &[u8], interface_name: impl Fn(u32) -> Option<String>) -> Option<Neighbour> {
+    if payload.len() < NDMSG_LEN {
+        return None
+    }
+    let ifindex = i32::from_ne_bytes(payload[4..8].try_into().unwrap());
+    let state = u16::from_ne_bytes(payload[8..10].try_into().unwrap());
+    let mut ip_address = None;
+    let mut mac = None;
+    for (kind, value) in netlink::attributes(&payload[NDMSG_LEN..]) {
+        match kind {
+            NDA_DST if value.len() == 16 => ip_address = Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).unwrap()))),
+            NDA_LLADDR if !value.is_empty() => mac = Some(value.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":")),
+            _ => {}
+        }
+    }
+    Some(Neighbour {
+        ip_address: ip_address?,
+        mac: mac,
+        interface: interface_name(ifindex as u32).unwrap_or_else(|| ifindex.to_string()),
+        state: nud_state(state).to_string(),
+    })
+}
+
+pub fn interface_name(index: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return None
+    }
+    Some(unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+pub fn get_ipv6_neighbours() -> std::io::Result<Vec<Neighbour>> {
+    let mut header = [0u8; NDMSG_LEN];
+    header[0] = libc::AF_INET6 as u8;
+    let messages = netlink::dump(RTM_GETNEIGH, &header)?;
+    Ok(messages
+        .iter()
+        .filter(|message| message.kind == RTM_NEWNEIGH)
+        .filter_map(|message| parse_ndmsg(&message.payload, interface_name))
+        .collect())
+}
+
+pub struct NeighbourCollector;
+
+impl NeighbourCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for NeighbourCollector {
+    fn name(&self) -> &str {
+        "neighbours"
+    }
+    fn description(&self) -> &str {
+        "ARP and IPv6 neighbour tables"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(60))
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut neighbours = parse_arp(&fs::read_to_string("/proc/net/arp")?);
+        match get_ipv6_neighbours() {
+            Ok(ipv6) => neighbours.extend(ipv6),
+            Err(e) => warn!("Cannot read the IPv6 neighbour table: {}", e),
+        }
+        let mut records = Vec::new();
+        for neighbour in neighbours {
+            records.push(Record::new("net_neighbour", &neighbour)?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arp_table_is_parsed() {
+        let neighbours = parse_arp("IP address       HW type     Flags       HW address            Mask     Device\n\
+            192.0.2.1        0x1         0x2         02:FC:00:00:00:05     *        eth0\n\
+            192.0.2.9        0x1         0x0         00:00:00:00:00:00     *        eth0\n");
+        assert_eq!(neighbours[0], Neighbour { ip_address: "192.0.2.1".parse().unwrap(), mac: Some("02:fc:00:00:00:05".to_string()), interface: "eth0".to_string(), state: "reachable".to_string() });
+        assert_eq!(neighbours[1].mac, None);
+        assert_eq!(neighbours[1].state, "incomplete");
+    }
+
+    #[test]
+    fn ndmsg_is_decoded() {
+        let mut payload = vec![libc::AF_INET6 as u8, 0, 0, 0];
+        payload.extend_from_slice(&2i32.to_ne_bytes());
+        payload.extend_from_slice(&0x04u16.to_ne_bytes());
+        payload.extend_from_slice(&[0, 0]);
+        payload.extend_from_slice(&20u16.to_ne_bytes());
+        payload.extend_from_slice(&NDA_DST.to_ne_bytes());
+        payload.extend_from_slice(&"fe80::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&10u16.to_ne_bytes());
+        payload.extend_from_slice(&NDA_LLADDR.to_ne_bytes());
+        payload.extend_from_slice(&[0x02, 0xfc, 0, 0, 0, 0x05, 0, 0]);
+
+        let neighbour = parse_ndmsg(&payload, |index| Some(format!("eth{}", index))).unwrap();
+        assert_eq!(neighbour.ip_address, "fe80::1".parse::<IpAddr>().unwrap());
+        assert_eq!(neighbour.mac.as_deref(), Some("02:fc:00:00:00:05"));
+        assert_eq!(neighbour.interface, "eth2");
+        assert_eq!(neighbour.state, "stale");
+    }
+
+    #[test]
+    fn local_neighbour_table_can_be_dumped() {
+        assert!(get_ipv6_neighbours().is_ok());
+    }
+}
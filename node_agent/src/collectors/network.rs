@@ -0,0 +1,92 @@
+use crate::budget::Budget;
+use crate::collectors::banners::{ServiceProbeConfig, ServiceProber};
+use crate::collectors::tls::{TlsProbeConfig, TlsProber};
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use crate::linux::sys_interagator::NetConnections;
+use serde_json::json;
+use std::error::Error;
+use std::time::Duration;
+
+//TCP sockets in the LISTEN state and the process which owns them. With probing switched on they also say what speaks
+//there and what a TLS handshake to them presents.
+pub struct ListenerCollector {
+    services: ServiceProber,
+    tls: TlsProber,
+}
+
+impl ListenerCollector {
+    pub fn new(service_probe: ServiceProbeConfig, tls_probe: TlsProbeConfig) -> Self {
+        Self { services: ServiceProber::new(service_probe), tls: TlsProber::new(tls_probe) }
+    }
+}
+
+impl Collector for ListenerCollector {
+    fn name(&self) -> &str {
+        "listeners"
+    }
+    fn description(&self) -> &str {
+        "Listening sockets and their owning process, optionally the service and TLS certificate behind each"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(60))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        let listeners = listeners::get_all()?;
+        let targets: Vec<_> = listeners.iter().map(|l| (l.socket, l.process.pid, l.process.name.clone())).collect();
+        let services = if self.services.enabled() {Some(self.services.probe_all(&targets, budget))} else {None};
+        let probed = if self.tls.enabled() {Some(self.tls.probe_all(&targets, budget))} else {None};
+        for l in listeners {
+            let mut record = json!({
+                "pid":l.process.pid,
+                "tcp_socket":l.socket,
+            });
+            if let Some(service) = services.and_then(|services| services.get(&(l.socket, l.process.pid))) {
+                record["service"] = serde_json::to_value(service)?;
+            }
+            if let Some(tls) = probed.and_then(|probed| probed.get(&(l.socket, l.process.pid))) {
+                record["tls"] = serde_json::to_value(tls)?;
+            }
+            records.push(Record::new("net_listening", &record)?);
+        }
+        Ok(records)
+    }
+}
+
+//Established TCP connections and the process which owns them
+pub struct ConnectionCollector;
+
+impl ConnectionCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for ConnectionCollector {
+    fn name(&self) -> &str {
+        "connections"
+    }
+    fn description(&self) -> &str {
+        "Established TCP connections and their owning process"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(60))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        for connection in NetConnections::get_established_connections_within(budget)? {
+            records.push(Record::new("net_connection", &json!({
+                "source_socket": connection.0,
+                "destination_socket": connection.1,
+                "pid": connection.2,
+            }))?);
+        }
+        Ok(records)
+    }
+}
@@ -0,0 +1,59 @@
+use crate::budget::Budget;
+use crate::collectors::os::OsInfo;
+use crate::collectors::routes;
+use crate::collectors::virt::Environment;
+use crate::collectors::{Collector, Record, Schedule};
+use crate::linux::sys_interagator::SystemInfo;
+use serde::Serialize;
+use std::error::Error;
+use std::time::Duration;
+
+//The node record published on /nodes/<agent_id>, right now this is the local system but is in place to allow for remote querying later
+#[derive(Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub system: SystemInfo,
+    //node_type plus the hypervisor, container runtime and cloud provider behind it
+    #[serde(flatten)]
+    pub environment: Environment,
+    //Default gateways of the main routing table, the routes collector has the full tables
+    pub ipv4_gateways: Vec<String>,
+    pub ipv6_gateways: Vec<String>,
+    pub os_info: OsInfo,
+}
+
+impl Node {
+    pub fn new() -> Self {
+        let (ipv4_gateways, ipv6_gateways) = routes::default_gateways(&routes::main_routes());
+        Self {
+            system: SystemInfo::new(),
+            environment: Environment::new(),
+            ipv4_gateways: ipv4_gateways,
+            ipv6_gateways: ipv6_gateways,
+            os_info: OsInfo::new(),
+        }
+    }
+}
+
+pub struct NodeCollector;
+
+impl NodeCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for NodeCollector {
+    fn name(&self) -> &str {
+        "node"
+    }
+    fn description(&self) -> &str {
+        "Machine id, hostname, node type, addresses, gateways and operating system of the node"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(3600))
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        Ok(vec![Record::new("", &Node::new())?])
+    }
+}
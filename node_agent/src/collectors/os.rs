@@ -0,0 +1,196 @@
+//Operating system details for the os_info block of the node record: distribution from os-release/lsb-release,
+//kernel from uname, and boot time, uptime, timezone and locale.
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OsInfo {
+    //The README os_info fields: family is what the distribution is derived from (ID_LIKE, falling back to ID),
+    //edition is the variant (Server, Workstation...) where the distribution has one
+    pub os_family: Option<String>,
+    pub os_version: Option<String>,
+    pub os_edition: Option<String>,
+    pub os_id: Option<String>,
+    pub os_name: Option<String>,
+    pub os_pretty_name: Option<String>,
+    pub os_codename: Option<String>,
+    pub lsb_description: Option<String>,
+    pub kernel_name: Option<String>,
+    pub kernel_release: Option<String>,
+    pub kernel_version: Option<String>,
+    pub architecture: Option<String>,
+    pub boot_time: Option<DateTime<Utc>>,
+    pub uptime_secs: Option<u64>,
+    pub timezone: Option<String>,
+    pub locale: Option<String>,
+}
+
+impl OsInfo {
+    pub fn new() -> Self {
+        let os_release = ["/etc/os-release", "/usr/lib/os-release"]
+            .iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .map(|contents| parse_key_values(&contents))
+            .unwrap_or_default();
+        let lsb_release = fs::read_to_string("/etc/lsb-release").map(|contents| parse_key_values(&contents)).unwrap_or_default();
+        let mut info = Self::from_release(&os_release, &lsb_release);
+
+        if let Some(uname) = Uname::new() {
+            info.kernel_name = Some(uname.sysname);
+            info.kernel_release = Some(uname.release);
+            info.kernel_version = Some(uname.version);
+            info.architecture = Some(uname.machine);
+        }
+        info.boot_time = fs::read_to_string("/proc/stat").ok().and_then(|stat| boot_time(&stat));
+        info.uptime_secs = fs::read_to_string("/proc/uptime")
+            .ok()
+            .and_then(|uptime| uptime.split_whitespace().next().and_then(|secs| secs.parse::<f64>().ok()))
+            .map(|secs| secs as u64);
+        info.timezone = timezone();
+        info.locale = locale();
+        info
+    }
+
+    //Distribution fields, os-release first with lsb-release filling any gaps
+    pub fn from_release(os_release: &HashMap<String, String>, lsb_release: &HashMap<String, String>) -> Self {
+        let get = |map: &HashMap<String, String>, key: &str| map.get(key).filter(|v| !v.is_empty()).cloned();
+        let os_id = get(os_release, "ID").or_else(|| get(lsb_release, "DISTRIB_ID").map(|id| id.to_lowercase()));
+        let os_family = get(os_release, "ID_LIKE")
+            .and_then(|like| like.split_whitespace().next().map(String::from))
+            .or_else(|| os_id.clone());
+        Self {
+            os_family: os_family,
+            os_version: get(os_release, "VERSION_ID").or_else(|| get(lsb_release, "DISTRIB_RELEASE")),
+            os_edition: get(os_release, "VARIANT").or_else(|| get(os_release, "VARIANT_ID")),
+            os_id: os_id,
+            os_name: get(os_release, "NAME"),
+            os_pretty_name: get(os_release, "PRETTY_NAME"),
+            os_codename: get(os_release, "VERSION_CODENAME").or_else(|| get(lsb_release, "DISTRIB_CODENAME")),
+            lsb_description: get(lsb_release, "DISTRIB_DESCRIPTION"),
+            ..Default::default()
+        }
+    }
+}
+
+//KEY=value files as used by os-release, lsb-release, locale.conf and friends. Values may be quoted.
+pub fn parse_key_values(contents: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            values.insert(key.trim().to_string(), value.replace("\\\"", "\"").replace("\\$", "$").replace("\\\\", "\\"));
+        }
+    }
+    values
+}
+
+//btime in /proc/stat is the boot time in seconds since the epoch
+pub fn boot_time(stat: &str) -> Option<DateTime<Utc>> {
+    let secs = stat.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse::<i64>().ok()?;
+    Utc.timestamp_opt(secs, 0).single()
+}
+
+fn timezone() -> Option<String> {
+    if let Ok(timezone) = fs::read_to_string("/etc/timezone") {
+        if !timezone.trim().is_empty() {
+            return Some(timezone.trim().to_string())
+        }
+    }
+    if let Ok(target) = fs::read_link("/etc/localtime") {
+        if let Some(zone) = timezone_from_path(&target) {
+            return Some(zone)
+        }
+    }
+    env::var("TZ").ok().filter(|tz| !tz.is_empty())
+}
+
+//"/usr/share/zoneinfo/Europe/London" -> "Europe/London"
+pub fn timezone_from_path(path: &Path) -> Option<String> {
+    let path = path.to_str()?;
+    path.split_once("zoneinfo/").map(|(_, zone)| zone.to_string())
+}
+
+fn locale() -> Option<String> {
+    ["/etc/locale.conf", "/etc/default/locale"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|contents| parse_key_values(&contents).remove("LANG"))
+        .or_else(|| env::var("LANG").ok())
+        .filter(|lang| !lang.is_empty())
+}
+
+struct Uname {
+    sysname: String,
+    release: String,
+    version: String,
+    machine: String,
+}
+
+impl Uname {
+    //uname(2) rather than std::env::consts::ARCH so a 32 bit agent on a 64 bit kernel reports the kernel's architecture
+    fn new() -> Option<Self> {
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } != 0 {
+            return None
+        }
+        let field = |chars: &[libc::c_char]| unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().into_owned();
+        Some(Self {
+            sysname: field(&uts.sysname),
+            release: field(&uts.release),
+            version: field(&uts.version),
+            machine: field(&uts.machine),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROCKY: &str = "NAME=\"Rocky Linux\"\nVERSION=\"9.3 (Blue Onyx)\"\nID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\nVERSION_ID=\"9.3\"\nPRETTY_NAME=\"Rocky Linux 9.3 (Blue Onyx)\"\n# comment\n";
+
+    #[test]
+    fn os_release_is_parsed() {
+        let info = OsInfo::from_release(&parse_key_values(ROCKY), &HashMap::new());
+        assert_eq!(info.os_family.as_deref(), Some("rhel"));
+        assert_eq!(info.os_version.as_deref(), Some("9.3"));
+        assert_eq!(info.os_id.as_deref(), Some("rocky"));
+        assert_eq!(info.os_pretty_name.as_deref(), Some("Rocky Linux 9.3 (Blue Onyx)"));
+    }
+
+    #[test]
+    fn lsb_release_fills_gaps() {
+        let lsb = parse_key_values("DISTRIB_ID=Ubuntu\nDISTRIB_RELEASE=22.04\nDISTRIB_CODENAME=jammy\nDISTRIB_DESCRIPTION=\"Ubuntu 22.04.4 LTS\"\n");
+        let info = OsInfo::from_release(&HashMap::new(), &lsb);
+        assert_eq!(info.os_family.as_deref(), Some("ubuntu"));
+        assert_eq!(info.os_version.as_deref(), Some("22.04"));
+        assert_eq!(info.os_codename.as_deref(), Some("jammy"));
+    }
+
+    #[test]
+    fn boot_time_and_timezone_are_read() {
+        let boot = boot_time("cpu  1 2 3\nbtime 1700000000\nprocesses 10\n").unwrap();
+        assert_eq!(boot.timestamp(), 1700000000);
+        assert_eq!(timezone_from_path(Path::new("/usr/share/zoneinfo/Europe/London")).as_deref(), Some("Europe/London"));
+    }
+
+    #[test]
+    fn local_os_info_has_kernel() {
+        let info = OsInfo::new();
+        assert_eq!(info.kernel_name.as_deref(), Some("Linux"));
+        assert!(info.boot_time.is_some());
+    }
+}
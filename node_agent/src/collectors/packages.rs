@@ -0,0 +1,511 @@
+//Installed software from the package managers on the node: dpkg, rpm, snap and flatpak. The first run sends every
+//package, later runs only what was installed, removed or changed version since.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Record, Schedule};
+use chrono::{DateTime, TimeZone, Utc};
+use log::*;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+const DPKG_INFO: &str = "/var/lib/dpkg/info";
+//Where the rpm database lives on older and newer (sysimage) distributions
+const RPM_DATABASES: &[&str] = &["/var/lib/rpm", "/usr/lib/sysimage/rpm"];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Package {
+    //dpkg, rpm, snap or flatpak
+    pub manager: String,
+    pub name: String,
+    pub version: String,
+    pub architecture: Option<String>,
+    pub vendor: Option<String>,
+    pub install_time: Option<DateTime<Utc>>,
+    pub repository: Option<String>,
+}
+
+impl Package {
+    fn key(&self) -> (String, String, Option<String>) {
+        (self.manager.clone(), self.name.clone(), self.architecture.clone())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct PackageChange {
+    //present on the first run, then installed, removed or changed
+    pub change: String,
+    pub previous_version: Option<String>,
+    #[serde(flatten)]
+    pub package: Package,
+}
+
+//Runs a package manager's query command, None if it isn't installed or fails
+fn query(program: &str, args: &[&str]) -> Option<String> {
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Ok(output) => {
+            warn!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+fn modified(path: &Path) -> Option<DateTime<Utc>> {
+    fs::metadata(path).and_then(|m| m.modified()).ok().map(DateTime::<Utc>::from)
+}
+
+//Stanzas of "Field: value" lines separated by blank lines, continuation lines start with a space and are skipped
+pub fn parse_dpkg_status(contents: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for stanza in contents.split("\n\n") {
+        let mut fields = HashMap::new();
+        for line in stanza.lines().filter(|line| !line.starts_with(' ')) {
+            if let Some((key, value)) = line.split_once(':') {
+                fields.insert(key, value.trim());
+            }
+        }
+        //Removed packages keep a stanza with their config files ("deinstall ok config-files")
+        if fields.get("Status").map_or(true, |status| !status.ends_with(" installed")) {
+            continue;
+        }
+        let (name, version) = match (fields.get("Package"), fields.get("Version")) {
+            (Some(name), Some(version)) => (name.to_string(), version.to_string()),
+            _ => continue,
+        };
+        packages.push(Package {
+            manager: "dpkg".to_string(),
+            name: name,
+            version: version,
+            architecture: fields.get("Architecture").map(|a| a.to_string()),
+            vendor: fields.get("Maintainer").map(|m| m.to_string()),
+            install_time: None,
+            repository: None,
+        });
+    }
+    packages
+}
+
+//"apt-cache policy" marks the installed version with *** and lists the sources it came from underneath, the first
+//that isn't dpkg's own status file is the repository
+pub fn parse_apt_policy(output: &str) -> HashMap<String, String> {
+    let mut repositories = HashMap::new();
+    let mut name: Option<String> = None;
+    let mut installed = false;
+    for line in output.lines() {
+        if !line.starts_with(' ') {
+            //"libc6:" or "libc6:i386:"
+            name = line.strip_suffix(':').map(|n| n.split(':').next().unwrap_or(n).to_string());
+            installed = false;
+        } else if line.starts_with(" *** ") {
+            installed = true;
+        } else if line.starts_with("     ") && !line.starts_with("      ") {
+            //Another version of the same package
+            installed = false;
+        } else if installed {
+            let source = line.split_whitespace().skip(1).collect::<Vec<&str>>();
+            if let (Some(name), Some(url)) = (&name, source.first()) {
+                if !url.starts_with('/') && !repositories.contains_key(name) {
+                    //"http://deb.debian.org/debian bookworm/main amd64 Packages" -> "http://deb.debian.org/debian bookworm/main"
+                    repositories.insert(name.clone(), source.iter().take(2).cloned().collect::<Vec<&str>>().join(" "));
+                }
+            }
+        }
+    }
+    repositories
+}
+
+fn dpkg_packages(budget: &Budget) -> Vec<Package> {
+    let mut packages = match fs::read_to_string(DPKG_STATUS) {
+        Ok(contents) => parse_dpkg_status(&contents),
+        Err(_) => return Vec::new(),
+    };
+    let names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    let mut args = vec!["policy"];
+    args.extend(names);
+    let repositories = query("apt-cache", &args).map(|output| parse_apt_policy(&output)).unwrap_or_default();
+    for package in packages.iter_mut() {
+        if !budget.tick() {
+            break;
+        }
+        //dpkg doesn't record when a package went in, the file list it writes at install is the closest we have
+        let arch_list = Path::new(DPKG_INFO).join(format!("{}:{}.list", package.name, package.architecture.as_deref().unwrap_or("")));
+        package.install_time = modified(&arch_list).or_else(|| modified(&Path::new(DPKG_INFO).join(format!("{}.list", package.name))));
+        package.repository = repositories.get(&package.name).cloned();
+    }
+    packages
+}
+
+pub const RPM_QUERY_FORMAT: &str = "%{NAME}\\t%{EPOCHNUM}:%{VERSION}-%{RELEASE}\\t%{ARCH}\\t%{VENDOR}\\t%{INSTALLTIME}\\n";
+
+//One package per line in RPM_QUERY_FORMAT, epoch 0 is left off the version as rpm -q does
+pub fn parse_rpm_query(output: &str) -> Vec<Package> {
+    let mut packages = Vec::new();
+    for line in output.lines() {
+        let columns: Vec<&str> = line.split('\t').collect();
+        if columns.len() < 5 {
+            continue;
+        }
+        let known = |value: &str| Some(value.to_string()).filter(|v| v != "(none)" && !v.is_empty());
+        packages.push(Package {
+            manager: "rpm".to_string(),
+            name: columns[0].to_string(),
+            version: columns[1].strip_prefix("0:").unwrap_or(columns[1]).to_string(),
+            architecture: known(columns[2]),
+            vendor: known(columns[3]),
+            install_time: columns[4].parse().ok().and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+            repository: None,
+        });
+    }
+    packages
+}
+
+fn rpm_packages(budget: &Budget) -> Vec<Package> {
+    let mut packages = match query("rpm", &["-qa", "--queryformat", RPM_QUERY_FORMAT]) {
+        Some(output) => parse_rpm_query(&output),
+        None => return Vec::new(),
+    };
+    //rpm doesn't know where a package came from, dnf does. -C keeps it to the local cache, no network.
+    let repositories: HashMap<String, String> = query("dnf", &["-C", "-q", "repoquery", "--installed", "--qf", "%{name}.%{arch}\\t%{from_repo}\\n"])
+        .map(|output| output.lines().filter_map(|line| line.split_once('\t')).map(|(k, v)| (k.to_string(), v.to_string())).collect())
+        .unwrap_or_default();
+    for package in packages.iter_mut() {
+        if !budget.tick() {
+            break;
+        }
+        package.repository = repositories.get(&format!("{}.{}", package.name, package.architecture.as_deref().unwrap_or(""))).cloned();
+    }
+    packages
+}
+
+//Name Version Rev Tracking Publisher Notes, publishers may carry a verified marker
+pub fn parse_snap_list(output: &str) -> Vec<Package> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() < 5 {
+                return None
+            }
+            Some(Package {
+                manager: "snap".to_string(),
+                name: columns[0].to_string(),
+                version: columns[1].to_string(),
+                architecture: None,
+                vendor: Some(columns[4].trim_end_matches(['*', '✓', '✪']).to_string()).filter(|v| v != "-"),
+                install_time: modified(&Path::new("/var/lib/snapd/snaps").join(format!("{}_{}.snap", columns[0], columns[2]))),
+                repository: Some(format!("snapcraft:{}", columns[3])),
+            })
+        })
+        .collect()
+}
+
+//Tab separated application, version, arch, origin
+pub fn parse_flatpak_list(output: &str) -> Vec<Package> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let columns: Vec<&str> = line.split('\t').collect();
+            if columns.len() < 4 || columns[0].is_empty() {
+                return None
+            }
+            let known = |value: &str| Some(value.to_string()).filter(|v| !v.is_empty());
+            Some(Package {
+                manager: "flatpak".to_string(),
+                name: columns[0].to_string(),
+                version: columns[1].to_string(),
+                architecture: known(columns[2]),
+                vendor: None,
+                install_time: None,
+                repository: known(columns[3]),
+            })
+        })
+        .collect()
+}
+
+pub fn get_packages(budget: &Budget) -> Vec<Package> {
+    let mut packages = dpkg_packages(budget);
+    packages.extend(rpm_packages(budget));
+    if let Some(output) = query("snap", &["list", "--unicode=never"]) {
+        packages.extend(parse_snap_list(&output));
+    }
+    if let Some(output) = query("flatpak", &["list", "--columns=application,version,arch,origin"]) {
+        packages.extend(parse_flatpak_list(&output));
+    }
+    packages
+}
+
+//Changes between two inventories. Removals are only trusted when the new inventory is complete.
+pub fn diff(known: &HashMap<(String, String, Option<String>), Package>, found: &[Package], complete: bool) -> Vec<PackageChange> {
+    let mut changes = Vec::new();
+    for package in found {
+        match known.get(&package.key()) {
+            None => changes.push(PackageChange { change: "installed".to_string(), previous_version: None, package: package.clone() }),
+            Some(previous) if previous.version != package.version => changes.push(PackageChange {
+                change: "changed".to_string(),
+                previous_version: Some(previous.version.clone()),
+                package: package.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    if complete {
+        let found_keys: std::collections::HashSet<_> = found.iter().map(Package::key).collect();
+        for (key, package) in known {
+            if !found_keys.contains(key) {
+                changes.push(PackageChange { change: "removed".to_string(), previous_version: None, package: package.clone() });
+            }
+        }
+    }
+    changes
+}
+
+//Which package installed a file, built from the dpkg file lists and the rpm file index. Rebuilt when either
+//database changes. It's big, so the collectors needing it share one.
+pub type SharedOwners = Rc<RefCell<OwnerIndex>>;
+
+#[derive(Default)]
+pub struct OwnerIndex {
+    owners: HashMap<String, usize>,
+    packages: Vec<(String, String)>,
+    managers: usize,
+    //Set when the budget stopped a database part way through, so a missing owner proves nothing
+    incomplete: bool,
+    built_from: Vec<Option<SystemTime>>,
+}
+
+impl OwnerIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn database_times() -> Vec<Option<SystemTime>> {
+        let mut paths = vec![DPKG_STATUS];
+        paths.extend(RPM_DATABASES);
+        paths.iter().map(|path| fs::metadata(path).and_then(|m| m.modified()).ok()).collect()
+    }
+
+    pub fn refresh_if_changed(&mut self, budget: &Budget) {
+        let times = Self::database_times();
+        if times == self.built_from {
+            return
+        }
+        *self = Self::new();
+        self.add_dpkg(Path::new(DPKG_STATUS), Path::new(DPKG_INFO), budget);
+        if let Some(output) = query("rpm", &["-qa", "--queryformat", "[%{FILENAMES}\\t%{NAME}\\t%{EPOCHNUM}:%{VERSION}-%{RELEASE}\\n]"]) {
+            self.add_rpm(&output);
+        }
+        //Only remember a complete index, otherwise the next run tries again
+        if !self.incomplete {
+            self.built_from = times;
+        }
+    }
+
+    fn add(&mut self, package: (String, String), files: impl Iterator<Item = String>) {
+        let index = self.packages.len();
+        self.packages.push(package);
+        for file in files {
+            self.owners.insert(file, index);
+        }
+    }
+
+    //Every installed package's info/<name>[:<arch>].list
+    pub fn add_dpkg(&mut self, status: &Path, info: &Path, budget: &Budget) {
+        let packages = match fs::read_to_string(status) {
+            Ok(contents) => parse_dpkg_status(&contents),
+            Err(_) => return,
+        };
+        self.managers += 1;
+        for package in packages {
+            if !budget.tick() {
+                self.incomplete = true;
+                return
+            }
+            let list = [format!("{}:{}.list", package.name, package.architecture.as_deref().unwrap_or("")), format!("{}.list", package.name)]
+                .iter()
+                .find_map(|name| fs::read_to_string(info.join(name)).ok());
+            if let Some(list) = list {
+                self.add((package.name, package.version), list.lines().map(String::from));
+            }
+        }
+    }
+
+    //"path\tname\tepoch:version-release" lines, one per file
+    pub fn add_rpm(&mut self, output: &str) {
+        self.managers += 1;
+        let mut packages: HashMap<(String, String), Vec<String>> = HashMap::new();
+        for line in output.lines() {
+            let columns: Vec<&str> = line.split('\t').collect();
+            if columns.len() == 3 {
+                let version = columns[2].strip_prefix("0:").unwrap_or(columns[2]).to_string();
+                packages.entry((columns[1].to_string(), version)).or_default().push(columns[0].to_string());
+            }
+        }
+        for (package, files) in packages {
+            self.add(package, files.into_iter());
+        }
+    }
+
+    //Package name and version owning a path. With merged /usr the kernel reports /usr/bin/x for a package that
+    //lists /bin/x, or the other way round, so both spellings are tried.
+    pub fn owner(&self, path: &str) -> Option<&(String, String)> {
+        let path = path.strip_suffix(" (deleted)").unwrap_or(path);
+        let alternative = match path.strip_prefix("/usr") {
+            Some(rest) => PathBuf::from(rest),
+            None => Path::new("/usr").join(path.trim_start_matches('/')),
+        };
+        self.owners
+            .get(path)
+            .or_else(|| self.owners.get(alternative.to_str()?))
+            .map(|index| &self.packages[*index])
+    }
+
+    //Whether there was any package database to look in and all of it was read, otherwise nothing can be called unpackaged
+    pub fn has_packages(&self) -> bool {
+        self.managers > 0 && !self.incomplete
+    }
+}
+
+pub struct PackageCollector {
+    known: Option<HashMap<(String, String, Option<String>), Package>>,
+    //Until a run has seen every package, the ones new to us were there all along rather than just installed
+    seen_everything: bool,
+}
+
+impl PackageCollector {
+    pub fn new() -> Self {
+        Self { known: None, seen_everything: false }
+    }
+
+    fn changes(&mut self, found: Vec<Package>, complete: bool) -> Vec<PackageChange> {
+        let known = self.known.get_or_insert_with(HashMap::new);
+        let mut changes = diff(known, &found, complete);
+        if !self.seen_everything {
+            changes.iter_mut().filter(|c| c.change == "installed").for_each(|c| c.change = "present".to_string());
+        }
+        if complete {
+            known.clear();
+        }
+        known.extend(found.into_iter().map(|p| (p.key(), p)));
+        self.seen_everything |= complete;
+        changes
+    }
+}
+
+impl Collector for PackageCollector {
+    fn name(&self) -> &str {
+        "packages"
+    }
+    fn description(&self) -> &str {
+        "Installed dpkg, rpm, snap and flatpak packages"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(3600))
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let found = get_packages(budget);
+        let changes = self.changes(found, budget.cut_short().is_none());
+        let mut records = Vec::new();
+        for change in changes.iter() {
+            records.push(Record::new("packages", change)?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "Package: adduser\nStatus: install ok installed\nMaintainer: Debian Adduser Developers <adduser@packages.debian.org>\nArchitecture: all\nVersion: 3.134\nDescription: add and remove users\n multi line\n\n\
+        Package: oldtool\nStatus: deinstall ok config-files\nArchitecture: amd64\nVersion: 1.0\n\n\
+        Package: libc6\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.36-9+deb12u14\n";
+
+    #[test]
+    fn dpkg_status_skips_removed_packages() {
+        let packages = parse_dpkg_status(STATUS);
+        assert_eq!(packages.iter().map(|p| p.name.as_str()).collect::<Vec<&str>>(), vec!["adduser", "libc6"]);
+        assert_eq!(packages[0].architecture.as_deref(), Some("all"));
+        assert_eq!(packages[0].vendor.as_deref(), Some("Debian Adduser Developers <adduser@packages.debian.org>"));
+    }
+
+    #[test]
+    fn apt_policy_gives_installed_repository() {
+        let repositories = parse_apt_policy("libc6:\n  Installed: 2.36-9+deb12u14\n  Candidate: 2.36-9+deb12u14\n  Version table:\n \
+            *** 2.36-9+deb12u14 500\n        500 http://deb.debian.org/debian bookworm/main amd64 Packages\n        100 /var/lib/dpkg/status\n     \
+            2.36-9+deb12u7 500\n        500 http://deb.debian.org/debian-security bookworm-security/main amd64 Packages\n\
+            local-only:\n  Installed: 1.0\n  Candidate: 1.0\n  Version table:\n *** 1.0 100\n        100 /var/lib/dpkg/status\n");
+        assert_eq!(repositories.get("libc6").map(String::as_str), Some("http://deb.debian.org/debian bookworm/main"));
+        assert_eq!(repositories.get("local-only"), None);
+    }
+
+    #[test]
+    fn rpm_and_snap_output_is_parsed() {
+        let rpm = parse_rpm_query("bash\t0:5.1.8-6.el9\tx86_64\tRocky Enterprise Software Foundation\t1700000000\ngpg-pubkey\t0:8483c65d-5ccc5b19\t(none)\t(none)\t1700000001\n");
+        assert_eq!(rpm[0].version, "5.1.8-6.el9");
+        assert_eq!(rpm[0].install_time.map(|t| t.timestamp()), Some(1700000000));
+        assert_eq!(rpm[1].architecture, None);
+        let snap = parse_snap_list("Name    Version   Rev    Tracking       Publisher   Notes\ncore22  20240111  1122   latest/stable  canonical*  base\n");
+        assert_eq!(snap[0].vendor.as_deref(), Some("canonical"));
+        assert_eq!(snap[0].repository.as_deref(), Some("snapcraft:latest/stable"));
+    }
+
+    #[test]
+    fn later_runs_send_deltas() {
+        let packages = parse_dpkg_status(STATUS);
+        let known = packages.iter().map(|p| (p.key(), p.clone())).collect();
+        let mut found = vec![packages[1].clone()];
+        found[0].version = "2.36-9+deb12u15".to_string();
+        found.push(Package { name: "curl".to_string(), ..packages[1].clone() });
+
+        let changes = diff(&known, &found, true);
+        let summary: Vec<(&str, &str)> = changes.iter().map(|c| (c.change.as_str(), c.package.name.as_str())).collect();
+        assert_eq!(summary, vec![("changed", "libc6"), ("installed", "curl"), ("removed", "adduser")]);
+        assert_eq!(changes[0].previous_version.as_deref(), Some("2.36-9+deb12u14"));
+        assert_eq!(diff(&known, &found, false).len(), 2);
+    }
+
+    #[test]
+    fn packages_missed_by_a_cut_short_first_run_are_present() {
+        let packages = parse_dpkg_status(STATUS);
+        let mut collector = PackageCollector::new();
+        let summary = |changes: Vec<PackageChange>| changes.iter().map(|c| (c.change.clone(), c.package.name.clone())).collect::<Vec<_>>();
+        assert_eq!(summary(collector.changes(vec![packages[0].clone()], false)), vec![("present".to_string(), "adduser".to_string())]);
+        assert_eq!(summary(collector.changes(packages.clone(), true)), vec![("present".to_string(), "libc6".to_string())]);
+        let curl = Package { name: "curl".to_string(), ..packages[1].clone() };
+        assert_eq!(summary(collector.changes(vec![packages[0].clone(), curl], false)), vec![("installed".to_string(), "curl".to_string())]);
+    }
+
+    #[test]
+    fn owners_are_found_across_merged_usr() {
+        let dir = std::env::temp_dir().join(format!("node_agent-dpkg-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("info")).unwrap();
+        fs::write(dir.join("status"), STATUS).unwrap();
+        fs::write(dir.join("info/adduser.list"), "/.\n/usr\n/usr/sbin/adduser\n").unwrap();
+        fs::write(dir.join("info/libc6:amd64.list"), "/lib/x86_64-linux-gnu/libc.so.6\n").unwrap();
+
+        let mut index = OwnerIndex::new();
+        index.add_dpkg(&dir.join("status"), &dir.join("info"), &Budget::unlimited());
+        index.add_rpm("/usr/bin/bash\tbash\t0:5.1.8-6.el9\n");
+        assert_eq!(index.owner("/usr/sbin/adduser"), Some(&("adduser".to_string(), "3.134".to_string())));
+        assert_eq!(index.owner("/usr/lib/x86_64-linux-gnu/libc.so.6").map(|p| p.0.as_str()), Some("libc6"));
+        assert_eq!(index.owner("/bin/bash (deleted)").map(|p| p.1.as_str()), Some("5.1.8-6.el9"));
+        assert_eq!(index.owner("/opt/app/bin/server"), None);
+        assert!(index.has_packages());
+
+        //A budget running out part way leaves an index that can't call anything unpackaged
+        let budget = Budget::new(None, Some(Duration::ZERO));
+        let mut partial = OwnerIndex::new();
+        partial.add_dpkg(&dir.join("status"), &dir.join("info"), &budget);
+        assert!(!partial.has_packages());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
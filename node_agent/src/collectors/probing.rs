@@ -0,0 +1,142 @@
+//What the banner and TLS passes have in common: they connect to the node's own listeners, so both are off by default,
+//paced, capped per run, skip what they're told to and keep each result per (socket, pid) until it's due again.
+use crate::budget::Budget;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProbeLimits {
+    //Off unless asked for, it opens connections to every listener on the node
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "ProbeLimits::default_probes_per_second")]
+    pub probes_per_second: u32,
+    #[serde(default = "ProbeLimits::default_max_probes_per_run")]
+    pub max_probes_per_run: usize,
+    //Results are reused until then unless the listener's process changes
+    #[serde(default = "ProbeLimits::default_reprobe_secs")]
+    pub reprobe_secs: u64,
+    #[serde(default = "ProbeLimits::default_timeout_ms")]
+    pub timeout_ms: u64,
+    //Never probed, by port or by process name
+    #[serde(default)]
+    pub exclude_ports: Vec<u16>,
+    #[serde(default)]
+    pub exclude_processes: Vec<String>,
+}
+
+impl ProbeLimits {
+    fn default_probes_per_second() -> u32 {
+        2
+    }
+    fn default_max_probes_per_run() -> usize {
+        20
+    }
+    fn default_reprobe_secs() -> u64 {
+        3600
+    }
+    fn default_timeout_ms() -> u64 {
+        3000
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+impl Default for ProbeLimits {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            probes_per_second: Self::default_probes_per_second(),
+            max_probes_per_run: Self::default_max_probes_per_run(),
+            reprobe_secs: Self::default_reprobe_secs(),
+            timeout_ms: Self::default_timeout_ms(),
+            exclude_ports: Vec::new(),
+            exclude_processes: Vec::new(),
+        }
+    }
+}
+
+//Wildcard listeners are reached over loopback of the same family
+pub fn probe_address(socket: SocketAddr) -> SocketAddr {
+    match socket.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), socket.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), socket.port()),
+        _ => socket,
+    }
+}
+
+//Keeps results between runs so the 60s listener cadence doesn't mean a connection a minute per port
+pub struct Prober<T> {
+    limits: ProbeLimits,
+    probed: HashMap<(SocketAddr, u32), Instant>,
+    results: HashMap<(SocketAddr, u32), T>,
+}
+
+impl<T> Prober<T> {
+    pub fn new(limits: ProbeLimits) -> Self {
+        Self { limits: limits, probed: HashMap::new(), results: HashMap::new() }
+    }
+
+    pub fn limits(&self) -> &ProbeLimits {
+        &self.limits
+    }
+
+    pub fn excluded(&self, socket: &SocketAddr, process: &str) -> bool {
+        self.limits.exclude_ports.contains(&socket.port()) || self.limits.exclude_processes.iter().any(|name| name == process)
+    }
+
+    //Results for every listener which was probed this run or recently enough, keyed by (socket, pid). probe is
+    //handed the address to connect to.
+    pub fn probe_all(&mut self, listeners: &[(SocketAddr, u32, String)], budget: &Budget, mut probe: impl FnMut(SocketAddr) -> T) -> &HashMap<(SocketAddr, u32), T> {
+        let reprobe = Duration::from_secs(self.limits.reprobe_secs);
+        //Listeners that went away, or changed process, lose their result
+        let current = |key: &(SocketAddr, u32)| listeners.iter().any(|(socket, pid, _)| (*socket, *pid) == *key);
+        self.results.retain(|key, _| current(key));
+        self.probed.retain(|key, _| current(key));
+        let pacing = Budget::new(Some(self.limits.probes_per_second), None);
+        let mut probes = 0;
+        for (socket, pid, process) in listeners {
+            if self.excluded(socket, process) {
+                continue;
+            }
+            if self.probed.get(&(*socket, *pid)).map_or(false, |at| at.elapsed() < reprobe) {
+                continue;
+            }
+            if probes >= self.limits.max_probes_per_run || !budget.check() || !pacing.tick() {
+                break;
+            }
+            probes += 1;
+            self.results.insert((*socket, *pid), probe(probe_address(*socket)));
+            self.probed.insert((*socket, *pid), Instant::now());
+        }
+        &self.results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusions_cap_and_reprobe_interval() {
+        let mut prober = Prober::new(ProbeLimits { enabled: true, exclude_processes: vec!["sshd".to_string()], max_probes_per_run: 2, probes_per_second: 50, ..Default::default() });
+        let web: SocketAddr = "0.0.0.0:443".parse().unwrap();
+        let listeners = vec![(web, 100, "nginx".to_string()), ("127.0.0.1:22".parse().unwrap(), 200, "sshd".to_string())];
+        let mut probed = Vec::new();
+        let results = prober.probe_all(&listeners, &Budget::unlimited(), |address| {probed.push(address); 1});
+        assert_eq!(results.keys().collect::<Vec<_>>(), vec![&(web, 100)]);
+        assert_eq!(probed, vec!["127.0.0.1:443".parse::<SocketAddr>().unwrap()]);
+        //Still fresh, so nothing is probed and the old result stays
+        assert_eq!(prober.probe_all(&listeners, &Budget::unlimited(), |_| 2)[&(web, 100)], 1);
+        //A new process on the port is probed again and the old one forgotten
+        let results = prober.probe_all(&[(web, 101, "nginx".to_string())], &Budget::unlimited(), |_| 3);
+        assert_eq!(results.iter().collect::<Vec<_>>(), vec![(&(web, 101), &3)]);
+        let many: Vec<_> = (1..=5).map(|port| (SocketAddr::new(web.ip(), port), 1, "x".to_string())).collect();
+        assert_eq!(prober.probe_all(&many, &Budget::unlimited(), |_| 0).len(), 2);
+        assert_eq!(probe_address("[::]:443".parse().unwrap()), "[::1]:443".parse().unwrap());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::budget::Budget;
+use crate::collectors::accounts::UserNames;
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use crate::linux::sys_interagator::{Process, Processes};
+use log::*;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+//The parent/child tree of every running process, sent as one nested record next to the flat processes records.
+//Identical workers (nginx, gunicorn, php-fpm...) are collapsed into a count under their master and the sockets
+//they listen on are rolled up to the process heading the service.
+pub struct ProcessTreeCollector;
+
+impl ProcessTreeCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct WorkerGroup {
+    pub exe: String,
+    pub cmdline: String,
+    pub user: Option<String>,
+    pub count: usize,
+    pub pids: Vec<u32>,
+    pub listening: Vec<SocketAddr>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProcessNode {
+    pub pid: u32,
+    pub exe: String,
+    pub cmdline: String,
+    pub user: Option<String>,
+    pub unit: Option<String>,
+    pub listening: Vec<SocketAddr>,
+    //Set on the process heading a service (its parent runs something else): every socket held by it, its children
+    //running the same executable and their workers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub service_listening: Vec<SocketAddr>,
+    pub workers: Vec<WorkerGroup>,
+    pub children: Vec<ProcessNode>,
+}
+
+struct TreeBuilder<'a> {
+    processes: HashMap<u32, &'a Process>,
+    children: BTreeMap<u32, Vec<u32>>,
+    listening: &'a HashMap<u32, Vec<SocketAddr>>,
+    visited: HashSet<u32>,
+}
+
+fn pid_of(process: &Process) -> Option<u32> {
+    process.pid.parse().ok()
+}
+
+//Kernel threads are kthreadd (pid 2) and its children, they aren't services so they're left out
+fn is_kernel_thread(pid: u32, process: &Process) -> bool {
+    pid == 2 || process.ppid == Some(2)
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn sockets(&self, pid: u32) -> Vec<SocketAddr> {
+        let mut sockets = self.listening.get(&pid).cloned().unwrap_or_default();
+        sockets.sort();
+        sockets.dedup();
+        sockets
+    }
+
+    fn is_leaf(&self, pid: u32) -> bool {
+        self.children.get(&pid).map_or(true, Vec::is_empty)
+    }
+
+    fn build(&mut self, pid: u32, parent_exe: Option<&str>) -> ProcessNode {
+        self.visited.insert(pid);
+        let process = self.processes[&pid];
+        let mut node = ProcessNode {
+            pid: pid,
+            exe: process.exe.clone(),
+            cmdline: process.cmdline.clone(),
+            user: process.user.clone(),
+            unit: process.unit.clone(),
+            listening: self.sockets(pid),
+            service_listening: Vec::new(),
+            workers: Vec::new(),
+            children: Vec::new(),
+        };
+        //Childless children running the same thing as the same user are workers
+        let mut groups: BTreeMap<(String, String, Option<u32>), Vec<u32>> = BTreeMap::new();
+        let mut others = Vec::new();
+        for child in self.children.get(&pid).cloned().unwrap_or_default() {
+            if self.visited.contains(&child) {
+                continue
+            }
+            if self.is_leaf(child) {
+                let process = self.processes[&child];
+                groups.entry((process.exe.clone(), process.cmdline.clone(), process.uid)).or_default().push(child);
+            } else {
+                others.push(child);
+            }
+        }
+        for ((exe, cmdline, _), pids) in groups {
+            if pids.len() < 2 {
+                others.extend(pids);
+                continue
+            }
+            let mut listening: Vec<SocketAddr> = pids.iter().flat_map(|pid| self.sockets(*pid)).collect();
+            listening.sort();
+            listening.dedup();
+            pids.iter().for_each(|pid| {self.visited.insert(*pid);});
+            node.workers.push(WorkerGroup {
+                user: self.processes[&pids[0]].user.clone(),
+                exe: exe,
+                cmdline: cmdline,
+                count: pids.len(),
+                pids: pids,
+                listening: listening,
+            });
+        }
+        others.sort();
+        for child in others {
+            if !self.visited.contains(&child) {
+                let child = self.build(child, Some(&process.exe));
+                node.children.push(child);
+            }
+        }
+        if parent_exe != Some(process.exe.as_str()) {
+            let mut sockets = Vec::new();
+            service_sockets(&node, &node.exe, &mut sockets);
+            sockets.sort();
+            sockets.dedup();
+            node.service_listening = sockets;
+        }
+        node
+    }
+}
+
+fn service_sockets(node: &ProcessNode, exe: &str, sockets: &mut Vec<SocketAddr>) {
+    sockets.extend(node.listening.iter().copied());
+    for workers in node.workers.iter().filter(|workers| workers.exe == exe) {
+        sockets.extend(workers.listening.iter().copied());
+    }
+    for child in node.children.iter().filter(|child| child.exe == exe) {
+        service_sockets(child, exe, sockets);
+    }
+}
+
+//Roots are processes without a parent we know of, normally just init
+pub fn build_tree(processes: &[Process], listening: &HashMap<u32, Vec<SocketAddr>>) -> Vec<ProcessNode> {
+    let processes: HashMap<u32, &Process> = processes.iter().filter_map(|process| Some((pid_of(process)?, process))).filter(|(pid, process)| !is_kernel_thread(*pid, process)).collect();
+    let mut children: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    let mut roots = Vec::new();
+    for (pid, process) in &processes {
+        match process.ppid {
+            Some(ppid) if ppid != *pid && processes.contains_key(&ppid) => children.entry(ppid).or_default().push(*pid),
+            _ => roots.push(*pid),
+        }
+    }
+    children.values_mut().for_each(|pids| pids.sort());
+    roots.sort();
+    let mut builder = TreeBuilder { processes: processes, children: children, listening: listening, visited: HashSet::new() };
+    let mut tree = Vec::new();
+    for root in roots {
+        if !builder.visited.contains(&root) {
+            tree.push(builder.build(root, None));
+        }
+    }
+    tree
+}
+
+impl Collector for ProcessTreeCollector {
+    fn name(&self) -> &str {
+        "process_tree"
+    }
+    fn description(&self) -> &str {
+        "Process parent/child tree with identical workers collapsed and listening sockets rolled up to each service"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(300))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut users = UserNames::new();
+        let mut processes: Vec<Process> = Processes::new_within(budget).processes.into_iter().collect();
+        for process in processes.iter_mut() {
+            process.user = process.uid.and_then(|uid| users.name(uid));
+        }
+        let mut listening: HashMap<u32, Vec<SocketAddr>> = HashMap::new();
+        match listeners::get_all() {
+            Ok(all) => all.into_iter().for_each(|l| listening.entry(l.process.pid).or_default().push(l.socket)),
+            Err(e) => warn!("Cannot list listening sockets for the process tree: {e:?}"),
+        }
+        let tree = build_tree(&processes, &listening);
+        Ok(vec![Record::new("process_tree", &json!({
+            "processes": processes.len(),
+            "roots": tree,
+        }))?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, ppid: u32, exe: &str, cmdline: &str) -> Process {
+        Process { pid: pid.to_string(), ppid: Some(ppid), exe: exe.to_string(), cmd: exe.to_string(), cmdline: cmdline.to_string(), uid: Some(0), ..Default::default() }
+    }
+
+    fn nginx() -> Vec<Process> {
+        vec![
+            process(1, 0, "/usr/lib/systemd/systemd", "/sbin/init"),
+            process(2, 0, "", ""),
+            process(3, 2, "", ""),
+            process(100, 1, "/usr/sbin/nginx", "nginx: master process /usr/sbin/nginx"),
+            process(101, 100, "/usr/sbin/nginx", "nginx: worker process"),
+            process(102, 100, "/usr/sbin/nginx", "nginx: worker process"),
+            process(103, 100, "/usr/sbin/nginx", "nginx: worker process"),
+            process(104, 100, "/usr/sbin/nginx", "nginx: cache manager process"),
+        ]
+    }
+
+    #[test]
+    fn workers_collapse_under_their_master() {
+        let tree = build_tree(&nginx(), &HashMap::new());
+        //Kernel threads are left out so init is the only root
+        assert_eq!(tree.len(), 1);
+        let master = &tree[0].children[0];
+        assert_eq!(master.pid, 100);
+        assert_eq!(master.workers.len(), 1);
+        assert_eq!((master.workers[0].count, master.workers[0].pids.clone()), (3, vec![101, 102, 103]));
+        //A lone child stays a child
+        assert_eq!(master.children.iter().map(|child| child.pid).collect::<Vec<_>>(), vec![104]);
+    }
+
+    #[test]
+    fn worker_sockets_roll_up_to_the_service() {
+        let http: SocketAddr = "0.0.0.0:80".parse().unwrap();
+        let https: SocketAddr = "0.0.0.0:443".parse().unwrap();
+        let listening = HashMap::from([(101, vec![http]), (102, vec![http, https]), (1, vec!["0.0.0.0:22".parse().unwrap()])]);
+        let tree = build_tree(&nginx(), &listening);
+        let master = &tree[0].children[0];
+        assert!(master.listening.is_empty());
+        assert_eq!(master.workers[0].listening, vec![http, https]);
+        assert_eq!(master.service_listening, vec![http, https]);
+        //init's own service doesn't pick up nginx's sockets
+        assert_eq!(tree[0].service_listening, vec!["0.0.0.0:22".parse::<SocketAddr>().unwrap()]);
+        assert!(master.children[0].service_listening.is_empty());
+    }
+
+    #[test]
+    fn orphans_and_loops_become_roots() {
+        let processes = vec![process(50, 40, "/opt/a", "a"), process(60, 60, "/opt/b", "b")];
+        let tree = build_tree(&processes, &HashMap::new());
+        assert_eq!(tree.iter().map(|node| node.pid).collect::<Vec<_>>(), vec![50, 60]);
+    }
+}
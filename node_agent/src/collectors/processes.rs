@@ -0,0 +1,142 @@
+use crate::budget::Budget;
+use crate::collectors::accounts::UserNames;
+use crate::collectors::executables::{ExecutableCache, Lookup};
+use crate::collectors::packages::{OwnerIndex, SharedOwners};
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use crate::linux::sys_interagator::{Process, Processes};
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+//Sends every running process on the first run and only the ones which have appeared since on later runs, each with
+//the package its executable came from and its hash. Each binary's metadata is sent once on the executables topic.
+//Processes whose binary the budget didn't let us finish hashing wait for the next run rather than going out without it.
+pub struct ProcessCollector {
+    processes: Option<Processes>,
+    owners: SharedOwners,
+    executables: ExecutableCache,
+    deferred: Vec<Process>,
+}
+
+impl ProcessCollector {
+    pub fn new(owners: SharedOwners) -> Self {
+        Self { processes: None, owners: owners, executables: ExecutableCache::new(), deferred: Vec::new() }
+    }
+
+    //Records for the given processes plus any executables seen for the first time
+    fn describe(&mut self, found: Vec<Process>, budget: &Budget, users: &mut UserNames) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        let owners = self.owners.borrow();
+        for mut process in found {
+            attach_package(&mut process, &owners);
+            process.user = process.uid.and_then(|uid| users.name(uid));
+            if !process.exe.is_empty() {
+                let proc_exe = format!("/proc/{}/exe", process.pid);
+                match self.executables.lookup(Path::new(&proc_exe), &process.exe, budget, users) {
+                    Lookup::Known(executable) => {
+                        process.exe_sha256 = Some(executable.sha256.clone());
+                        process.exe_build_id = executable.elf.as_ref().and_then(|elf| elf.build_id.clone());
+                    }
+                    Lookup::New(executable) => {
+                        process.exe_sha256 = Some(executable.sha256.clone());
+                        process.exe_build_id = executable.elf.as_ref().and_then(|elf| elf.build_id.clone());
+                        records.push(Record::new("executables", executable)?);
+                    }
+                    Lookup::Unfinished => {
+                        self.deferred.push(process);
+                        continue
+                    }
+                    Lookup::Unreadable => {}
+                }
+            }
+            records.push(Record::new("processes", &process)?);
+        }
+        Ok(records)
+    }
+}
+
+pub fn attach_package(process: &mut Process, owners: &OwnerIndex) {
+    //Kernel threads and processes we can't read the exe link of
+    if process.exe.is_empty() {
+        return
+    }
+    match owners.owner(&process.exe) {
+        Some((name, version)) => {
+            process.package = Some(name.clone());
+            process.package_version = Some(version.clone());
+            process.unpackaged = Some(false);
+        }
+        None if owners.has_packages() => process.unpackaged = Some(true),
+        None => {}
+    }
+}
+
+impl Collector for ProcessCollector {
+    fn name(&self) -> &str {
+        "processes"
+    }
+    fn description(&self) -> &str {
+        "Running processes with their executable, command line, owning package, user and /proc details, plus the hash and ELF metadata of each binary"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(60))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Capability("CAP_SYS_PTRACE")]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        self.owners.borrow_mut().refresh_if_changed(budget);
+        //Re-read every run so new accounts resolve
+        let mut users = UserNames::new();
+        let mut found = std::mem::take(&mut self.deferred);
+        found.extend(match self.processes.as_mut() {
+            Some(processes) => processes.get_new_processes_within(budget),
+            None => {
+                let processes = Processes::new_within(budget);
+                let all = processes.processes.clone();
+                self.processes = Some(processes);
+                all
+            }
+        });
+        self.describe(found, budget, &mut users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(exe: &str) -> Process {
+        Process { pid: "1".to_string(), exe: exe.to_string(), cmd: exe.to_string(), cmdline: exe.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn unhashed_processes_wait_for_the_next_run() {
+        let exe = std::env::current_exe().unwrap().to_string_lossy().to_string();
+        let own = Process { pid: std::process::id().to_string(), ..process(&exe) };
+        let mut collector = ProcessCollector::new(SharedOwners::default());
+        let mut users = UserNames::new();
+        let records = collector.describe(vec![own], &Budget::new(None, Some(Duration::ZERO)), &mut users).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(collector.deferred.len(), 1);
+        let deferred = std::mem::take(&mut collector.deferred);
+        let records = collector.describe(deferred, &Budget::unlimited(), &mut users).unwrap();
+        assert_eq!(records.iter().map(|record| record.topic.as_str()).collect::<Vec<_>>(), vec!["executables", "processes"]);
+        assert!(collector.deferred.is_empty());
+    }
+
+    #[test]
+    fn hand_deployed_executables_are_flagged() {
+        let mut owners = OwnerIndex::new();
+        owners.add_rpm("/usr/bin/bash\tbash\t0:5.1.8-6.el9\n");
+        let mut bash = process("/usr/bin/bash");
+        attach_package(&mut bash, &owners);
+        assert_eq!((bash.package.as_deref(), bash.unpackaged), (Some("bash"), Some(false)));
+        let mut server = process("/opt/app/server");
+        attach_package(&mut server, &owners);
+        assert_eq!(server.unpackaged, Some(true));
+        let mut unknown = process("/opt/app/server");
+        attach_package(&mut unknown, &OwnerIndex::new());
+        assert_eq!(unknown.unpackaged, None);
+    }
+}
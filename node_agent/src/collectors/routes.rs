@@ -0,0 +1,422 @@
+//Routing tables of the node: the main table from /proc/net/route and rtnetlink for IPv6, plus any policy routing
+//tables and rules that iproute2 can show us. Default gateways from the main table also go on the node record.
+use crate::budget::Budget;
+use crate::collectors::neighbours::interface_name;
+use crate::collectors::{Collector, Record, Schedule};
+use crate::netlink;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::Command;
+use std::time::Duration;
+
+//Flags from linux/route.h
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+
+//rtnetlink route dumps, from linux/rtnetlink.h
+const RTM_NEWROUTE: u16 = 24;
+const RTM_GETROUTE: u16 = 26;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_PREFSRC: u16 = 7;
+const RTA_MULTIPATH: u16 = 9;
+const RTA_TABLE: u16 = 15;
+const RT_TABLE_MAIN: u32 = 254;
+const RTN_UNICAST: u8 = 1;
+//sizeof(struct rtmsg) and struct rtnexthop
+const RTMSG_LEN: usize = 12;
+const RTNH_LEN: usize = 8;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Route {
+    pub table: String,
+    pub family: String,
+    //CIDR, 0.0.0.0/0 and ::/0 for the default routes
+    pub destination: String,
+    pub gateway: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub metric: Option<u32>,
+    pub source: Option<IpAddr>,
+}
+
+impl Route {
+    pub fn is_default(&self) -> bool {
+        self.destination == "0.0.0.0/0" || self.destination == "::/0"
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub priority: Option<u32>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub input_interface: Option<String>,
+    pub fwmark: Option<String>,
+    pub table: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct RoutingTable {
+    pub ipv4_gateways: Vec<String>,
+    pub ipv6_gateways: Vec<String>,
+    pub routes: Vec<Route>,
+    pub rules: Vec<Rule>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        let mut routes = main_routes();
+        routes.extend(policy_routes());
+        let (ipv4_gateways, ipv6_gateways) = default_gateways(&routes);
+        Self {
+            ipv4_gateways: ipv4_gateways,
+            ipv6_gateways: ipv6_gateways,
+            routes: routes,
+            rules: policy_rules(),
+        }
+    }
+}
+
+//Main table only, which is all the node record needs
+pub fn main_routes() -> Vec<Route> {
+    let mut routes = fs::read_to_string("/proc/net/route").map(|c| parse_ipv4_routes(&c)).unwrap_or_default();
+    match get_ipv6_main_routes() {
+        Ok(ipv6) => routes.extend(ipv6),
+        Err(e) => warn!("Cannot read the IPv6 routing table: {}", e),
+    }
+    routes
+}
+
+//Gateways of the main table's default routes, lowest metric first
+pub fn default_gateways(routes: &[Route]) -> (Vec<String>, Vec<String>) {
+    let mut defaults: Vec<&Route> = routes.iter().filter(|r| r.table == "main" && r.is_default() && r.gateway.is_some()).collect();
+    defaults.sort_by_key(|r| r.metric.unwrap_or(0));
+    let mut ipv4 = Vec::new();
+    let mut ipv6 = Vec::new();
+    for route in defaults {
+        let (list, gateway) = match route.gateway {
+            Some(IpAddr::V4(gateway)) => (&mut ipv4, gateway.to_string()),
+            Some(IpAddr::V6(gateway)) => (&mut ipv6, gateway.to_string()),
+            None => continue,
+        };
+        if !list.contains(&gateway) {
+            list.push(gateway);
+        }
+    }
+    (ipv4, ipv6)
+}
+
+//Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT, addresses are hex in host (little endian) order
+pub fn parse_ipv4_routes(contents: &str) -> Vec<Route> {
+    let mut routes = Vec::new();
+    for line in contents.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 8 {
+            continue;
+        }
+        let hex = |column: &str| u32::from_str_radix(column, 16).ok();
+        let (destination, gateway, flags, metric, mask) = match (hex(columns[1]), hex(columns[2]), hex(columns[3]), columns[6].parse::<u32>().ok(), hex(columns[7])) {
+            (Some(d), Some(g), Some(f), Some(m), Some(k)) => (d, g, f, m, k),
+            _ => continue,
+        };
+        if flags & RTF_UP == 0 || flags & RTF_REJECT != 0 {
+            continue;
+        }
+        let address = |value: u32| Ipv4Addr::from(value.swap_bytes());
+        routes.push(Route {
+            table: "main".to_string(),
+            family: "ipv4".to_string(),
+            destination: format!("{}/{}", address(destination), mask.count_ones()),
+            gateway: Some(address(gateway)).filter(|g| !g.is_unspecified()).map(IpAddr::V4),
+            interface: Some(columns[0].to_string()),
+            metric: Some(metric),
+            source: None,
+        });
+    }
+    routes
+}
+
+//One RTM_NEWROUTE reply, None unless it's a unicast route in the main table. /proc/net/ipv6_route would be simpler but
+//lists every table without saying which, so policy table routes would show up as main routes too.
+pub fn parse_ipv6_route(payload: &[u8], interface_name: impl Fn(u32) -> Option<String>) -> Option<Route> {
+    if payload.len() < RTMSG_LEN || payload[0] != libc::AF_INET6 as u8 || payload[7] != RTN_UNICAST {
+        return None
+    }
+    let prefix = payload[1];
+    //The header only has room for tables up to 255, RTA_TABLE has the real one
+    let mut table = payload[4] as u32;
+    let mut destination = Ipv6Addr::UNSPECIFIED;
+    let mut gateway = None;
+    let mut interface = None;
+    let mut metric = None;
+    let mut source = None;
+    let address = |value: &[u8]| <[u8; 16]>::try_from(value).ok().map(Ipv6Addr::from);
+    let number = |value: &[u8]| <[u8; 4]>::try_from(value).ok().map(u32::from_ne_bytes);
+    for (kind, value) in netlink::attributes(&payload[RTMSG_LEN..]) {
+        match kind {
+            RTA_TABLE => table = number(value).unwrap_or(table),
+            RTA_DST => destination = address(value)?,
+            RTA_GATEWAY => gateway = address(value),
+            RTA_OIF => interface = number(value),
+            RTA_PRIORITY => metric = number(value),
+            RTA_PREFSRC => source = address(value),
+            //ECMP routes carry their next hops as rtnexthop entries, report the first one
+            RTA_MULTIPATH if value.len() >= RTNH_LEN && gateway.is_none() => {
+                let length = (u16::from_ne_bytes([value[0], value[1]]) as usize).min(value.len());
+                interface = number(&value[4..8]);
+                gateway = netlink::attributes(&value[RTNH_LEN..length.max(RTNH_LEN)]).into_iter().find(|(kind, _)| *kind == RTA_GATEWAY).and_then(|(_, value)| address(value));
+            }
+            _ => {}
+        }
+    }
+    if table != RT_TABLE_MAIN || destination.is_multicast() {
+        return None
+    }
+    Some(Route {
+        table: "main".to_string(),
+        family: "ipv6".to_string(),
+        destination: format!("{}/{}", destination, prefix),
+        gateway: gateway.filter(|g| !g.is_unspecified()).map(IpAddr::V6),
+        interface: interface.map(|index| interface_name(index).unwrap_or_else(|| index.to_string())),
+        metric: metric,
+        source: source.map(IpAddr::V6),
+    })
+}
+
+pub fn get_ipv6_main_routes() -> std::io::Result<Vec<Route>> {
+    let mut header = [0u8; RTMSG_LEN];
+    header[0] = libc::AF_INET6 as u8;
+    let messages = netlink::dump(RTM_GETROUTE, &header)?;
+    Ok(messages
+        .iter()
+        .filter(|message| message.kind == RTM_NEWROUTE)
+        .filter_map(|message| parse_ipv6_route(&message.payload, interface_name))
+        .collect())
+}
+
+#[derive(Deserialize, Debug)]
+struct IpRoute {
+    dst: String,
+    table: Option<String>,
+    gateway: Option<IpAddr>,
+    dev: Option<String>,
+    metric: Option<u32>,
+    prefsrc: Option<IpAddr>,
+}
+
+//Runs "ip -j ..." from iproute2. There is no /proc view of tables other than main, so without iproute2 we only report main.
+fn ip_json(args: &[&str]) -> Option<String> {
+    match Command::new("ip").arg("-j").args(args).output() {
+        Ok(output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Ok(output) => {
+            debug!("ip {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+            None
+        }
+        Err(e) => {
+            debug!("Cannot run ip {}: {}", args.join(" "), e);
+            None
+        }
+    }
+}
+
+fn policy_routes() -> Vec<Route> {
+    let mut routes = Vec::new();
+    for (family, flag) in [("ipv4", "-4"), ("ipv6", "-6")] {
+        if let Some(json) = ip_json(&[flag, "route", "show", "table", "all"]) {
+            routes.extend(parse_policy_routes(&json, family));
+        }
+    }
+    routes
+}
+
+//Routes from "ip -j route show table all" outside the main and local tables, main already came from /proc
+pub fn parse_policy_routes(json: &str, family: &str) -> Vec<Route> {
+    let parsed: Vec<IpRoute> = match serde_json::from_str(json) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!("Cannot parse ip route output: {}", e);
+            return Vec::new()
+        }
+    };
+    parsed
+        .into_iter()
+        .filter_map(|route| {
+            let table = route.table.filter(|t| t != "main" && t != "local")?;
+            let destination = if route.dst == "default" {
+                if family == "ipv4" {"0.0.0.0/0".to_string()} else {"::/0".to_string()}
+            } else if route.dst.contains('/') {
+                route.dst
+            } else {
+                format!("{}/{}", route.dst, if family == "ipv4" {32} else {128})
+            };
+            Some(Route {
+                table: table,
+                family: family.to_string(),
+                destination: destination,
+                gateway: route.gateway,
+                interface: route.dev,
+                metric: route.metric,
+                source: route.prefsrc,
+            })
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Debug)]
+struct IpRule {
+    priority: Option<u32>,
+    src: Option<String>,
+    srclen: Option<u8>,
+    dst: Option<String>,
+    dstlen: Option<u8>,
+    iif: Option<String>,
+    fwmark: Option<String>,
+    table: Option<String>,
+}
+
+fn policy_rules() -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for flag in ["-4", "-6"] {
+        if let Some(json) = ip_json(&[flag, "rule", "show"]) {
+            rules.extend(parse_rules(&json));
+        }
+    }
+    rules
+}
+
+//"ip -j rule show" splits selectors into address and prefix length, put them back together as CIDR
+pub fn parse_rules(json: &str) -> Vec<Rule> {
+    let parsed: Vec<IpRule> = match serde_json::from_str(json) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!("Cannot parse ip rule output: {}", e);
+            return Vec::new()
+        }
+    };
+    let cidr = |address: Option<String>, length: Option<u8>| match (address, length) {
+        (Some(address), Some(length)) => Some(format!("{}/{}", address, length)),
+        (address, _) => address,
+    };
+    parsed
+        .into_iter()
+        .map(|rule| Rule {
+            priority: rule.priority,
+            from: cidr(rule.src, rule.srclen),
+            to: cidr(rule.dst, rule.dstlen),
+            input_interface: rule.iif,
+            fwmark: rule.fwmark,
+            table: rule.table,
+        })
+        .collect()
+}
+
+pub struct RouteCollector;
+
+impl RouteCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for RouteCollector {
+    fn name(&self) -> &str {
+        "routes"
+    }
+    fn description(&self) -> &str {
+        "Routing tables, policy rules and default gateways"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(300))
+    }
+    fn collect(&mut self, _budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        Ok(vec![Record::new("routes", &RoutingTable::new())?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTE: &str = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+        eth0\t00000000\t010200C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+        eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+
+    //RTM_NEWROUTE payload: rtmsg then RTA_TABLE, RTA_DST, RTA_GATEWAY, RTA_OIF and RTA_PRIORITY
+    fn rtmsg(destination: &str, prefix: u8, table: u32, route_type: u8, gateway: Option<&str>, metric: u32) -> Vec<u8> {
+        let mut payload = vec![libc::AF_INET6 as u8, prefix, 0, 0, table.min(252) as u8, 3, 0, route_type, 0, 0, 0, 0];
+        let mut attribute = |kind: u16, value: &[u8]| {
+            payload.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+            payload.extend_from_slice(&kind.to_ne_bytes());
+            payload.extend_from_slice(value);
+        };
+        attribute(RTA_TABLE, &table.to_ne_bytes());
+        attribute(RTA_DST, &destination.parse::<Ipv6Addr>().unwrap().octets());
+        if let Some(gateway) = gateway {
+            attribute(RTA_GATEWAY, &gateway.parse::<Ipv6Addr>().unwrap().octets());
+        }
+        attribute(RTA_OIF, &2u32.to_ne_bytes());
+        attribute(RTA_PRIORITY, &metric.to_ne_bytes());
+        payload
+    }
+
+    fn ipv6_routes() -> Vec<Route> {
+        [
+            rtmsg("fd00::", 64, RT_TABLE_MAIN, RTN_UNICAST, None, 256),
+            rtmsg("::", 0, RT_TABLE_MAIN, RTN_UNICAST, Some("fd00::1"), 1024),
+            //Local table and an unreachable route
+            rtmsg("::1", 128, 255, 2, None, 0),
+            rtmsg("::", 0, RT_TABLE_MAIN, 7, None, 4294967295),
+            //Policy table default route, it comes from ip route show table all instead
+            rtmsg("::", 0, 100, RTN_UNICAST, Some("fd09::1"), 1024),
+        ].iter().filter_map(|payload| parse_ipv6_route(payload, |index| Some(format!("eth{}", index - 2)))).collect()
+    }
+
+    #[test]
+    fn ipv4_routes_are_parsed() {
+        let routes = parse_ipv4_routes(ROUTE);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].destination, "0.0.0.0/0");
+        assert_eq!(routes[0].gateway, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(routes[0].metric, Some(100));
+        assert_eq!(routes[1].destination, "192.0.2.0/24");
+        assert_eq!(routes[1].gateway, None);
+    }
+
+    #[test]
+    fn ipv6_routes_outside_main_are_skipped() {
+        let routes = ipv6_routes();
+        assert_eq!(routes.iter().map(|r| r.destination.as_str()).collect::<Vec<&str>>(), vec!["fd00::/64", "::/0"]);
+        assert_eq!(routes[1].gateway, Some("fd00::1".parse().unwrap()));
+        assert_eq!(routes[1].metric, Some(1024));
+    }
+
+    #[test]
+    fn default_gateways_come_from_main_table() {
+        let mut routes = parse_ipv4_routes(ROUTE);
+        routes.extend(ipv6_routes());
+        routes.extend(parse_policy_routes(r#"[{"dst":"default","gateway":"10.9.0.1","dev":"wg0","table":"100","flags":[]},{"dst":"default","gateway":"192.0.2.1","dev":"eth0","flags":[]},{"type":"local","dst":"127.0.0.1","dev":"lo","table":"local","flags":[]}]"#, "ipv4"));
+        assert_eq!(routes.iter().filter(|r| r.table == "100").count(), 1);
+        let (ipv4, ipv6) = default_gateways(&routes);
+        assert_eq!(ipv4, vec!["192.0.2.1".to_string()]);
+        assert_eq!(ipv6, vec!["fd00::1".to_string()]);
+    }
+
+    #[test]
+    fn rules_are_parsed() {
+        let rules = parse_rules(r#"[{"priority":0,"src":"all","table":"local"},{"priority":100,"src":"10.9.0.0","srclen":16,"table":"100"}]"#);
+        assert_eq!(rules[0].from.as_deref(), Some("all"));
+        assert_eq!(rules[1].priority, Some(100));
+        assert_eq!(rules[1].from.as_deref(), Some("10.9.0.0/16"));
+        assert_eq!(rules[1].table.as_deref(), Some("100"));
+    }
+
+    #[test]
+    fn local_ipv6_routes_can_be_dumped() {
+        assert!(get_ipv6_main_routes().unwrap().iter().all(|route| route.table == "main" && route.family == "ipv6"));
+    }
+}
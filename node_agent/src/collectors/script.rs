@@ -0,0 +1,393 @@
+//Site specific checks which live outside the agent. The executable writes one JSON object per line to stdout, each line
+//is checked against the schema declared in the config and published on /nodes/<agent_id>/custom under the script's name.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Record, Schedule};
+use log::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MAX_STDERR_BYTES: u64 = 4096;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScriptConfig {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "ScriptConfig::default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "ScriptConfig::default_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "ScriptConfig::default_max_output")]
+    pub max_output_bytes: u64,
+    #[serde(default)]
+    pub schema: RecordSchema,
+}
+
+impl ScriptConfig {
+    fn default_interval() -> u64 {
+        3600
+    }
+    fn default_timeout() -> u64 {
+        30
+    }
+    fn default_max_output() -> u64 {
+        1024 * 1024
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 {
+            return Err(format!("Script '{}' needs an interval_secs above 0", self.name));
+        }
+        if self.timeout_secs == 0 {
+            return Err(format!("Script '{}' needs a timeout_secs above 0", self.name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Object,
+    Array,
+    Any,
+}
+
+impl FieldType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Number => value.is_number(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Boolean => value.is_boolean(),
+            FieldType::Object => value.is_object(),
+            FieldType::Array => value.is_array(),
+            FieldType::Any => true,
+        }
+    }
+}
+
+//Fields a script promises to emit. Fields not listed are passed through unless additional_fields is switched off.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordSchema {
+    #[serde(default)]
+    pub required: HashMap<String, FieldType>,
+    #[serde(default)]
+    pub optional: HashMap<String, FieldType>,
+    #[serde(default = "RecordSchema::default_additional_fields")]
+    pub additional_fields: bool,
+}
+
+impl Default for RecordSchema {
+    fn default() -> Self {
+        Self {
+            required: HashMap::new(),
+            optional: HashMap::new(),
+            additional_fields: true,
+        }
+    }
+}
+
+impl RecordSchema {
+    fn default_additional_fields() -> bool {
+        true
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), String> {
+        let object = value.as_object().ok_or("record is not a JSON object")?;
+        for (field, field_type) in self.required.iter() {
+            match object.get(field) {
+                None => return Err(format!("missing required field '{}'", field)),
+                Some(v) if !field_type.matches(v) => return Err(format!("field '{}' is not of type {:?}", field, field_type)),
+                Some(_) => {}
+            }
+        }
+        for (field, v) in object.iter() {
+            if self.required.contains_key(field) {
+                continue;
+            }
+            match self.optional.get(field) {
+                Some(field_type) if !field_type.matches(v) => return Err(format!("field '{}' is not of type {:?}", field, field_type)),
+                Some(_) => {}
+                None if !self.additional_fields => return Err(format!("unexpected field '{}'", field)),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+//What came back from one run of a script
+struct ScriptOutput {
+    status: Option<ExitStatus>,
+    stdout: Vec<u8>,
+    stderr: String,
+    timed_out: bool,
+    truncated: bool,
+}
+
+pub struct ScriptCollector {
+    config: ScriptConfig,
+    description: String,
+    warnings: Vec<String>,
+}
+
+impl ScriptCollector {
+    pub fn new(config: ScriptConfig) -> Self {
+        let description = format!("External script {}", config.path.display());
+        Self {
+            config: config,
+            description: description,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>, limit: u64, overflow: Arc<AtomicBool>) -> JoinHandle<io::Result<Vec<u8>>> {
+        thread::spawn(move || {
+            let mut buffer = Vec::new();
+            if let Some(pipe) = pipe {
+                //Read one byte past the limit so we can tell the output was cut off
+                pipe.take(limit + 1).read_to_end(&mut buffer)?;
+            }
+            if buffer.len() as u64 > limit {
+                overflow.store(true, Ordering::Relaxed);
+            }
+            Ok(buffer)
+        })
+    }
+
+    //Kills the script and anything it started, they all share the process group we put the script in. Only call this
+    //before the script is reaped, until then its pid (and so the group id) can't be handed out again.
+    fn kill_group(child: &mut Child) {
+        unsafe {
+            libc::kill(-(child.id() as i32), libc::SIGKILL);
+        }
+        let _ = child.kill();
+    }
+
+    //Whether the script has exited, leaving it unreaped so its process group stays ours to signal
+    fn has_exited(child: &Child) -> io::Result<bool> {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        if unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, libc::WEXITED | libc::WNOHANG | libc::WNOWAIT) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { info.si_pid() } != 0)
+    }
+
+    fn run(&self, timeout: Duration) -> io::Result<ScriptOutput> {
+        let mut child = Command::new(&self.config.path)
+            .args(&self.config.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        let overflow = Arc::new(AtomicBool::new(false));
+        let stdout = Self::read_pipe(child.stdout.take(), self.config.max_output_bytes, Arc::clone(&overflow));
+        let stderr = Self::read_pipe(child.stderr.take(), MAX_STDERR_BYTES, Arc::new(AtomicBool::new(false)));
+
+        let deadline = Instant::now() + timeout;
+        let mut timed_out = false;
+        loop {
+            if Self::has_exited(&child)? {
+                break;
+            }
+            //Stop the script as soon as it has written more than we are willing to take
+            if overflow.load(Ordering::Relaxed) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                timed_out = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        //Also gets anything the script left running in the background, which would hold the pipes open
+        Self::kill_group(&mut child);
+        let exit = child.wait()?;
+        let mut status = if timed_out {None} else {Some(exit)};
+
+        let mut stdout = stdout.join().map_err(|_| io::Error::new(io::ErrorKind::Other, "stdout reader panicked"))??;
+        let stderr = stderr.join().map_err(|_| io::Error::new(io::ErrorKind::Other, "stderr reader panicked"))??;
+        let truncated = overflow.load(Ordering::Relaxed);
+        stdout.truncate(self.config.max_output_bytes as usize);
+        //Once we stop reading the script dies of SIGPIPE or our kill, that isn't the script failing
+        if truncated {
+            status = None;
+        }
+        Ok(ScriptOutput {
+            status: status,
+            stdout: stdout,
+            stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+            timed_out: timed_out,
+            truncated: truncated,
+        })
+    }
+
+    fn parse(&mut self, output: &ScriptOutput) -> Result<Vec<Record>, Box<dyn Error>> {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines: Vec<&str> = stdout.lines().collect();
+        //A partial last line is only expected when the output was cut short
+        if (output.truncated || output.timed_out) && !stdout.ends_with('\n') {
+            lines.pop();
+        }
+        let mut records = Vec::new();
+        let mut invalid = 0;
+        for (number, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: Value = match serde_json::from_str(line) {
+                Ok(value) => value,
+                Err(e) => {
+                    invalid += 1;
+                    debug!("Script {} line {} is not JSON: {}", self.config.name, number + 1, e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.config.schema.validate(&value) {
+                invalid += 1;
+                debug!("Script {} line {} does not match the schema: {}", self.config.name, number + 1, e);
+                continue;
+            }
+            records.push(Record::new("custom", &value)?);
+        }
+        if invalid > 0 {
+            self.warnings.push(format!("{} lines were not valid JSON records matching the schema", invalid));
+        }
+        Ok(records)
+    }
+}
+
+impl Collector for ScriptCollector {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+    fn description(&self) -> &str {
+        &self.description
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(self.config.interval_secs))
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        //Whichever is shorter, the script's own timeout or what is left of the collector's time budget
+        let mut timeout = Duration::from_secs(self.config.timeout_secs);
+        if let Some(remaining) = budget.remaining() {
+            timeout = timeout.min(remaining);
+        }
+        let output = self.run(timeout).map_err(|e| format!("Cannot run {}: {}", self.config.path.display(), e))?;
+        //Marks the run as cut short if the budget ran out while the script was going
+        budget.check();
+        if let Some(status) = output.status {
+            if !status.success() {
+                return Err(format!("{} exited with {}: {}", self.config.path.display(), status, output.stderr).into());
+            }
+        }
+        if output.timed_out {
+            self.warnings.push(format!("timed out after {}s, keeping output received so far", timeout.as_secs()));
+        }
+        if output.truncated {
+            self.warnings.push(format!("output exceeded {} bytes and was cut off", self.config.max_output_bytes));
+        }
+        self.parse(&output)
+    }
+    fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn script(body: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("node_agent-script-{}.sh", uuid::Uuid::new_v4()));
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn config(path: PathBuf) -> ScriptConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "app_version",
+            "path": path,
+            "timeout_secs": 2,
+            "max_output_bytes": 4096,
+            "schema": {"required": {"app": "string", "version": "string"}, "optional": {"build": "integer"}},
+        })).unwrap()
+    }
+
+    #[test]
+    fn valid_lines_become_records() {
+        let path = script(r#"echo '{"app":"billing","version":"1.2.3","build":42}'
+echo 'not json'
+echo '{"app":"billing"}'"#);
+        let mut collector = ScriptCollector::new(config(path.clone()));
+        let records = collector.collect(&Budget::unlimited()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].topic, "custom");
+        assert_eq!(records[0].payload["version"], "1.2.3");
+        assert_eq!(collector.take_warnings().len(), 1);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn zero_interval_or_timeout_is_rejected() {
+        let mut config = config(PathBuf::from("/bin/true"));
+        assert!(config.validate().is_ok());
+        config.timeout_secs = 0;
+        assert!(config.validate().unwrap_err().contains("timeout_secs"));
+        config.timeout_secs = 1;
+        config.interval_secs = 0;
+        assert!(config.validate().unwrap_err().contains("interval_secs"));
+    }
+
+    #[test]
+    fn failing_script_is_an_error() {
+        let path = script("echo broken >&2\nexit 3");
+        let mut collector = ScriptCollector::new(config(path.clone()));
+        let error = collector.collect(&Budget::unlimited()).unwrap_err().to_string();
+        assert!(error.contains("broken"));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn slow_script_is_killed() {
+        let path = script(r#"echo '{"app":"billing","version":"1.2.3"}'
+sleep 30"#);
+        let mut collector = ScriptCollector::new(config(path.clone()));
+        let started = Instant::now();
+        let records = collector.collect(&Budget::unlimited()).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(records.len(), 1);
+        assert!(collector.take_warnings().iter().any(|w| w.contains("timed out")));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn noisy_script_is_cut_off() {
+        let path = script(r#"while true; do echo '{"app":"billing","version":"1.2.3"}'; done"#);
+        let mut collector = ScriptCollector::new(config(path.clone()));
+        let records = collector.collect(&Budget::unlimited()).unwrap();
+        assert!(records.len() > 0 && records.len() < 4096 / 30);
+        assert!(collector.take_warnings().iter().any(|w| w.contains("cut off")));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,467 @@
+//SSH access paths: the sshd settings, the node's host keys, and per account the authorized_keys entries, known_hosts
+//entries and client public keys. Only public keys are read and only their fingerprints leave the node, the processor
+//matches them across nodes into "host A can SSH to host B" edges.
+use crate::budget::Budget;
+use crate::collectors::accounts;
+use crate::collectors::interfaces::{self, scope};
+use crate::collectors::{Collector, Privilege, Record, Schedule};
+use crate::linux::sys_interagator::SystemInfo;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::net::IpAddr;
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SSH_DIR: &str = "/etc/ssh";
+//sshd's own default when AuthorizedKeysFile isn't set
+const DEFAULT_AUTHORIZED_KEYS: &[&str] = &[".ssh/authorized_keys", ".ssh/authorized_keys2"];
+//Deep enough for any sane layout, stops Include loops
+const MAX_INCLUDE_DEPTH: usize = 8;
+//Far more keys than any real authorized_keys or known_hosts, anything past it is ignored
+const MAX_USER_FILE_BYTES: u64 = 1024 * 1024;
+//Container and VM bridges, every host running them has the same addresses there (docker0's 172.17.0.1, libvirt's
+//192.168.122.1)
+const SHARED_BRIDGE_PREFIXES: &[&str] = &["docker", "br-", "virbr", "lxcbr", "lxdbr", "cni", "podman", "flannel", "cali", "veth"];
+
+//OpenSSH style SHA256 fingerprint of the base64 key blob, what ssh-keygen -l prints
+pub fn fingerprint(blob: &str) -> Option<String> {
+    let decoded = STANDARD.decode(blob).ok()?;
+    Some(format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&decoded))))
+}
+
+fn is_key_type(word: &str) -> bool {
+    word.starts_with("ssh-") || word.starts_with("ecdsa-sha2-") || word.starts_with("sk-ssh-") || word.starts_with("sk-ecdsa-")
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    pub key_type: String,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+}
+
+impl PublicKey {
+    //"type base64 [comment]"
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut words = text.split_whitespace();
+        let key_type = words.next().filter(|w| is_key_type(w))?;
+        let fingerprint = fingerprint(words.next()?)?;
+        let comment: Vec<&str> = words.collect();
+        Some(Self {
+            key_type: key_type.to_string(),
+            fingerprint: fingerprint,
+            comment: Some(comment.join(" ")).filter(|c| !c.is_empty()),
+        })
+    }
+}
+
+//Splits at the first whitespace outside double quotes, options like command="a b" contain spaces
+fn split_unquoted(text: &str, separator: fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if separator(c) && !quoted {
+            parts.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts.into_iter().filter(|p| !p.is_empty()).collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuthorizedKey {
+    pub user: String,
+    pub file: String,
+    //from=, command=, no-port-forwarding... they narrow what the key can do
+    pub options: Vec<String>,
+    #[serde(flatten)]
+    pub key: PublicKey,
+}
+
+pub fn parse_authorized_keys(contents: &str, user: &str, file: &Path) -> Vec<AuthorizedKey> {
+    let mut keys = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        //Options come first when the line doesn't start with the key type
+        let (options, key) = match split_unquoted(line, char::is_whitespace).first() {
+            Some(first) if !is_key_type(first) => (split_unquoted(first, |c| c == ','), line[first.len()..].trim_start()),
+            _ => (Vec::new(), line),
+        };
+        if let Some(key) = PublicKey::parse(key) {
+            keys.push(AuthorizedKey {
+                user: user.to_string(),
+                file: file.display().to_string(),
+                options: options.into_iter().map(String::from).collect(),
+                key: key,
+            });
+        }
+    }
+    keys
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KnownHost {
+    //None for the system wide /etc/ssh/ssh_known_hosts
+    pub user: Option<String>,
+    pub file: String,
+    //@cert-authority or @revoked
+    pub marker: Option<String>,
+    //Names and addresses without the [host]:port brackets, empty when HashKnownHosts hid them
+    pub hosts: Vec<String>,
+    pub hashed: bool,
+    #[serde(flatten)]
+    pub key: PublicKey,
+}
+
+pub fn parse_known_hosts(contents: &str, user: Option<&str>, file: &Path) -> Vec<KnownHost> {
+    let mut entries = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (marker, rest) = match line.strip_prefix('@') {
+            Some(marked) => match marked.split_once(char::is_whitespace) {
+                Some((marker, rest)) => (Some(format!("@{}", marker)), rest.trim_start()),
+                None => continue,
+            },
+            None => (None, line),
+        };
+        let (patterns, key) = match rest.split_once(char::is_whitespace) {
+            Some(split) => split,
+            None => continue,
+        };
+        let key = match PublicKey::parse(key) {
+            Some(key) => key,
+            None => continue,
+        };
+        let hashed = patterns.starts_with("|1|");
+        let hosts = if hashed {
+            Vec::new()
+        } else {
+            patterns
+                .split(',')
+                .filter(|p| !p.starts_with('!'))
+                .map(|p| p.strip_prefix('[').and_then(|p| p.split_once("]:")).map_or(p, |(host, _port)| host).to_string())
+                .collect()
+        };
+        entries.push(KnownHost {
+            user: user.map(String::from),
+            file: file.display().to_string(),
+            marker: marker,
+            hosts: hosts,
+            hashed: hashed,
+            key: key,
+        });
+    }
+    entries
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MatchBlock {
+    pub criteria: String,
+    pub settings: BTreeMap<String, Vec<String>>,
+}
+
+//Keywords are lower cased. Values are kept in order, sshd uses the first one for single valued settings.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct SshdConfig {
+    pub files: Vec<String>,
+    pub settings: BTreeMap<String, Vec<String>>,
+    pub match_blocks: Vec<MatchBlock>,
+}
+
+impl SshdConfig {
+    pub fn first(&self, keyword: &str) -> Option<&str> {
+        self.settings.get(keyword).and_then(|values| values.first()).map(String::as_str)
+    }
+}
+
+//Only * in the file name is supported, which is what distributions ship (sshd_config.d/*.conf)
+fn include_paths(pattern: &str) -> Vec<PathBuf> {
+    let path = if pattern.starts_with('/') {PathBuf::from(pattern)} else {Path::new(SSH_DIR).join(pattern)};
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let (prefix, suffix) = match name.split_once('*') {
+        Some(split) => split,
+        None => return vec![path],
+    };
+    let mut paths: Vec<PathBuf> = fs::read_dir(path.parent().unwrap_or(Path::new(SSH_DIR)))
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_str().map_or(false, |n| n.starts_with(prefix) && n.ends_with(suffix)))
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    paths
+}
+
+pub fn parse_sshd_config(path: &Path, depth: usize, config: &mut SshdConfig) {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    config.files.push(path.display().to_string());
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        //"Keyword value" or "Keyword=value"
+        let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((keyword, value)) => (keyword.to_lowercase(), value.trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim()),
+            None => continue,
+        };
+        match keyword.as_str() {
+            "include" if depth < MAX_INCLUDE_DEPTH => {
+                for pattern in value.split_whitespace() {
+                    for include in include_paths(pattern) {
+                        parse_sshd_config(&include, depth + 1, config);
+                    }
+                }
+            }
+            "include" => {}
+            //Everything after a Match line belongs to it until the next one, "Match all" goes back to global
+            "match" if value.eq_ignore_ascii_case("all") => config.match_blocks.push(MatchBlock { criteria: "all".to_string(), ..Default::default() }),
+            "match" => config.match_blocks.push(MatchBlock { criteria: value.to_string(), ..Default::default() }),
+            _ => {
+                let settings = match config.match_blocks.last_mut() {
+                    Some(block) if block.criteria != "all" => &mut block.settings,
+                    _ => &mut config.settings,
+                };
+                settings.entry(keyword).or_default().push(value.to_string());
+            }
+        }
+    }
+    if depth == 0 {
+        config.match_blocks.retain(|block| block.criteria != "all");
+    }
+}
+
+//AuthorizedKeysFile tokens: %h home, %u user, %% a literal %. Relative paths are from the home directory.
+pub fn authorized_keys_paths(config: &SshdConfig, user: &str, home: &str) -> Vec<PathBuf> {
+    let patterns: Vec<String> = match config.settings.get("authorizedkeysfile") {
+        Some(values) => values.iter().flat_map(|v| v.split_whitespace().map(String::from)).filter(|p| p != "none").collect(),
+        None => DEFAULT_AUTHORIZED_KEYS.iter().map(|p| p.to_string()).collect(),
+    };
+    patterns
+        .iter()
+        .map(|pattern| pattern.replace("%%", "\0").replace("%h", home).replace("%u", user).replace('\0', "%"))
+        .map(|path| if path.starts_with('/') {PathBuf::from(path)} else {Path::new(home).join(path)})
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KeyFile {
+    //None for host keys
+    pub user: Option<String>,
+    pub file: String,
+    #[serde(flatten)]
+    pub key: PublicKey,
+}
+
+//Users control what's in their home, and we read it as root. So no following a symlink to /dev/zero or somebody
+//else's file, no hanging on a FIFO and no reading more than MAX_USER_FILE_BYTES.
+pub fn read_user_file(path: &Path) -> Option<String> {
+    let file = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK).open(path).ok()?;
+    if !file.metadata().ok()?.is_file() {
+        return None
+    }
+    let mut contents = Vec::new();
+    file.take(MAX_USER_FILE_BYTES).read_to_end(&mut contents).ok()?;
+    String::from_utf8(contents).ok()
+}
+
+fn read_key_file(path: &Path, user: Option<&str>) -> Option<KeyFile> {
+    let key = PublicKey::parse(read_user_file(path)?.lines().next()?)?;
+    Some(KeyFile { user: user.map(String::from), file: path.display().to_string(), key: key })
+}
+
+//Only the .pub half of each key pair is opened
+fn public_key_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |e| e == "pub"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+//Addresses other nodes could share with this one, which would make their known_hosts entries point at every node
+pub fn shared_address(interface: &str, address: &IpAddr) -> bool {
+    scope(address) != "global" || SHARED_BRIDGE_PREFIXES.iter().any(|prefix| interface.starts_with(prefix))
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct SshInventory {
+    //Hostname and addresses, what other nodes' known_hosts would call this one
+    pub host_names: Vec<String>,
+    pub sshd: SshdConfig,
+    pub host_keys: Vec<KeyFile>,
+    pub client_keys: Vec<KeyFile>,
+    pub authorized_keys: Vec<AuthorizedKey>,
+    pub known_hosts: Vec<KnownHost>,
+    //False when the budget stopped before every account was looked at
+    pub complete: bool,
+}
+
+impl SshInventory {
+    pub fn new_within(budget: &Budget) -> Self {
+        let mut inventory = Self::default();
+        inventory.host_names.push(SystemInfo::get_hostname());
+        for interface in interfaces::get_interfaces().into_iter().filter(|interface| interface.operstate.as_deref() != Some("down")) {
+            for address in interface.addresses.iter().filter(|address| !shared_address(&interface.name, &address.address)) {
+                inventory.host_names.push(address.address.to_string());
+            }
+        }
+        parse_sshd_config(&Path::new(SSH_DIR).join("sshd_config"), 0, &mut inventory.sshd);
+
+        let mut host_key_files: Vec<PathBuf> = inventory.sshd.settings.get("hostkey").into_iter().flatten().map(|key| PathBuf::from(format!("{}.pub", key))).collect();
+        if host_key_files.is_empty() {
+            host_key_files = public_key_files(Path::new(SSH_DIR)).into_iter().filter(|p| p.to_string_lossy().contains("ssh_host_")).collect();
+        }
+        inventory.host_keys = host_key_files.iter().filter_map(|path| read_key_file(path, None)).collect();
+        let system_known_hosts = Path::new(SSH_DIR).join("ssh_known_hosts");
+        inventory.known_hosts = parse_known_hosts(&fs::read_to_string(&system_known_hosts).unwrap_or_default(), None, &system_known_hosts);
+
+        let accounts = fs::read_to_string("/etc/passwd").map(|passwd| accounts::parse_passwd(&passwd, 1000)).unwrap_or_default();
+        for account in accounts {
+            if !budget.tick() {
+                break;
+            }
+            //Service accounts often share / or /nonexistent as home
+            if account.home.is_empty() || account.home == "/" {
+                continue;
+            }
+            let user = account.username.as_str();
+            for path in authorized_keys_paths(&inventory.sshd, user, &account.home) {
+                if let Some(contents) = read_user_file(&path) {
+                    inventory.authorized_keys.extend(parse_authorized_keys(&contents, user, &path));
+                }
+            }
+            let ssh_dir = Path::new(&account.home).join(".ssh");
+            let known_hosts = ssh_dir.join("known_hosts");
+            if let Some(contents) = read_user_file(&known_hosts) {
+                inventory.known_hosts.extend(parse_known_hosts(&contents, Some(user), &known_hosts));
+            }
+            inventory.client_keys.extend(public_key_files(&ssh_dir).iter().filter_map(|path| read_key_file(path, Some(user))));
+        }
+        inventory.complete = budget.cut_short().is_none();
+        inventory
+    }
+}
+
+pub struct SshCollector;
+
+impl SshCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for SshCollector {
+    fn name(&self) -> &str {
+        "ssh"
+    }
+    fn description(&self) -> &str {
+        "sshd settings, host keys and per account authorized_keys, known_hosts and public key fingerprints"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(3600))
+    }
+    fn privileges(&self) -> Vec<Privilege> {
+        vec![Privilege::Root]
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        Ok(vec![Record::new("ssh", &SshInventory::new_within(budget))?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICRvkrq3hBy0UL6OV7fHxEGhGsDHj6kUM5ore9BdZXqs deploy@build01";
+    const FINGERPRINT: &str = "SHA256:lXe0fMbFFQkDwR7b7LZbiE0c8diEdMngfEq+HNq0QBo";
+
+    #[test]
+    fn authorized_keys_with_options() {
+        let contents = format!("# deploy key\n{}\nfrom=\"10.0.0.0/8,192.168.1.5\",command=\"/usr/bin/rsync --server -a .\",no-pty {}\nnot a key\n", KEY, KEY);
+        let keys = parse_authorized_keys(&contents, "backup", Path::new("/home/backup/.ssh/authorized_keys"));
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key, PublicKey { key_type: "ssh-ed25519".to_string(), fingerprint: FINGERPRINT.to_string(), comment: Some("deploy@build01".to_string()) });
+        assert!(keys[0].options.is_empty());
+        assert_eq!(keys[1].options, vec!["from=\"10.0.0.0/8,192.168.1.5\"", "command=\"/usr/bin/rsync --server -a .\"", "no-pty"]);
+        assert_eq!(keys[1].key.fingerprint, FINGERPRINT);
+    }
+
+    #[test]
+    fn known_hosts_entries() {
+        let blob = KEY.split_whitespace().nth(1).unwrap();
+        let contents = format!("db01,10.0.0.12 ssh-ed25519 {}\n[git.example.com]:2222,!bad ssh-ed25519 {}\n|1|F1E1KeoE/eEWhi10WpGv4OdiO6Y=|3988QV0VE8wmZL7suNrYQLITLCg= ssh-ed25519 {}\n@cert-authority *.example.com ssh-ed25519 {}\n", blob, blob, blob, blob);
+        let entries = parse_known_hosts(&contents, Some("alice"), Path::new("/home/alice/.ssh/known_hosts"));
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].hosts, vec!["db01", "10.0.0.12"]);
+        assert_eq!(entries[1].hosts, vec!["git.example.com"]);
+        assert!(entries[2].hashed && entries[2].hosts.is_empty());
+        assert_eq!(entries[3].marker.as_deref(), Some("@cert-authority"));
+        assert!(entries.iter().all(|e| e.key.fingerprint == FINGERPRINT));
+    }
+
+    #[test]
+    fn sshd_config_with_includes_and_match() {
+        let root = std::env::temp_dir().join(format!("node_agent-sshd-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("sshd_config.d")).unwrap();
+        fs::write(root.join("sshd_config.d/10-hardening.conf"), "PasswordAuthentication no\nPermitRootLogin prohibit-password\n").unwrap();
+        fs::write(root.join("sshd_config"), format!("Include {}/sshd_config.d/*.conf\nPort 22\nPasswordAuthentication yes\nAuthorizedKeysFile .ssh/authorized_keys /etc/ssh/keys/%u\nMatch User sftp\n  ForceCommand internal-sftp\nMatch all\nX11Forwarding=no\n", root.display())).unwrap();
+        let mut config = SshdConfig::default();
+        parse_sshd_config(&root.join("sshd_config"), 0, &mut config);
+        assert_eq!(config.files.len(), 2);
+        //The included file comes first so its value is the one sshd uses
+        assert_eq!(config.first("passwordauthentication"), Some("no"));
+        assert_eq!(config.first("x11forwarding"), Some("no"));
+        assert_eq!(config.match_blocks, vec![MatchBlock { criteria: "User sftp".to_string(), settings: BTreeMap::from([("forcecommand".to_string(), vec!["internal-sftp".to_string()])]) }]);
+        assert_eq!(authorized_keys_paths(&config, "alice", "/home/alice"), vec![PathBuf::from("/home/alice/.ssh/authorized_keys"), PathBuf::from("/etc/ssh/keys/alice")]);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn user_files_are_read_carefully() {
+        let dir = std::env::temp_dir().join(format!("node_agent-ssh-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("authorized_keys"), "ssh-ed25519 AAAA\n").unwrap();
+        assert_eq!(read_user_file(&dir.join("authorized_keys")).as_deref(), Some("ssh-ed25519 AAAA\n"));
+        std::os::unix::fs::symlink("/dev/zero", dir.join("known_hosts")).unwrap();
+        assert_eq!(read_user_file(&dir.join("known_hosts")), None);
+        let fifo = std::ffi::CString::new(dir.join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        assert_eq!(read_user_file(&dir.join("fifo")), None);
+        assert_eq!(read_user_file(Path::new("/dev/zero")), None);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn shared_addresses_do_not_name_the_node() {
+        let address = |a: &str| a.parse::<IpAddr>().unwrap();
+        assert!(!shared_address("eth0", &address("10.0.0.12")));
+        assert!(!shared_address("br0", &address("2001:db8::12")));
+        assert!(shared_address("eth0", &address("fe80::1")));
+        assert!(shared_address("eth0", &address("169.254.1.1")));
+        assert!(shared_address("docker0", &address("172.17.0.1")));
+        assert!(shared_address("virbr0", &address("192.168.122.1")));
+    }
+}
@@ -0,0 +1,461 @@
+//Storage on the node: mounted filesystems from /proc/self/mountinfo with their usage, block devices and partitions
+//from /sys/block with LVM/MD stacking, and remote (NFS, CIFS...) mounts which are also published as dependencies on
+//the hosts serving them.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Record, Schedule};
+use log::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const SYS_BLOCK: &str = "/sys/block";
+//statvfs on a dead NFS server hangs, give up on it after this
+const REMOTE_STATVFS_TIMEOUT: Duration = Duration::from_secs(2);
+
+const REMOTE_FS_TYPES: &[&str] = &["nfs", "nfs4", "cifs", "smb3", "smbfs", "ceph", "glusterfs", "fuse.glusterfs", "fuse.sshfs", "9p"];
+//Kernel interfaces rather than storage, usage figures for these mean nothing
+const PSEUDO_FS_TYPES: &[&str] = &["proc", "sysfs", "cgroup", "cgroup2", "devpts", "mqueue", "debugfs", "tracefs", "securityfs",
+    "pstore", "bpf", "configfs", "fusectl", "binfmt_misc", "autofs", "hugetlbfs", "rpc_pipefs", "nsfs", "efivarfs"];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+    pub inodes_total: u64,
+    pub inodes_free: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RemoteSource {
+    pub server: String,
+    pub export: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Mount {
+    pub mount_point: String,
+    pub source: String,
+    pub fs_type: String,
+    //major:minor of the backing device
+    pub device: String,
+    pub options: Vec<String>,
+    pub super_options: Vec<String>,
+    pub remote: Option<RemoteSource>,
+    pub usage: Option<Usage>,
+}
+
+impl Mount {
+    pub fn is_remote(&self) -> bool {
+        REMOTE_FS_TYPES.contains(&self.fs_type.as_str())
+    }
+}
+
+//Paths in mountinfo have space, tab, newline and backslash escaped as \ooo
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(position) = rest.find('\\') {
+        result.push_str(&rest[..position]);
+        match rest.get(position + 1..position + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[position + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[position + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+//mount_id parent_id major:minor root mount_point options [optional fields...] - fs_type source super_options
+pub fn parse_mountinfo(contents: &str) -> Vec<Mount> {
+    let mut mounts = Vec::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let separator = match fields.iter().position(|f| *f == "-") {
+            Some(separator) if separator >= 6 && fields.len() >= separator + 3 => separator,
+            _ => continue,
+        };
+        let fs_type = fields[separator + 1].to_string();
+        let source = unescape(fields[separator + 2]);
+        let remote = if REMOTE_FS_TYPES.contains(&fs_type.as_str()) {remote_source(&source)} else {None};
+        mounts.push(Mount {
+            mount_point: unescape(fields[4]),
+            device: fields[2].to_string(),
+            options: fields[5].split(',').map(String::from).collect(),
+            super_options: fields.get(separator + 3).map(|o| o.split(',').map(String::from).collect()).unwrap_or_default(),
+            remote: remote,
+            source: source,
+            fs_type: fs_type,
+            usage: None,
+        });
+    }
+    mounts
+}
+
+//"server:/export" (NFS, ceph, sshfs user@host:path) or "//server/share" (CIFS)
+pub fn remote_source(source: &str) -> Option<RemoteSource> {
+    if let Some(unc) = source.strip_prefix("//") {
+        let (server, share) = unc.split_once('/').unwrap_or((unc, ""));
+        return Some(RemoteSource { server: server.to_string(), export: format!("/{}", share) })
+    }
+    //IPv6 servers are bracketed, [fd00::1]:/export
+    let (server, export) = if let Some(bracketed) = source.strip_prefix('[') {
+        let (server, rest) = bracketed.split_once(']')?;
+        (server, rest.strip_prefix(':')?)
+    } else {
+        source.split_once(':')?
+    };
+    let server = server.rsplit('@').next().unwrap_or(server);
+    if server.is_empty() {
+        return None
+    }
+    Some(RemoteSource { server: server.to_string(), export: export.to_string() })
+}
+
+pub fn statvfs(path: &str) -> Option<Usage> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None
+    }
+    let fragment = stat.f_frsize as u64;
+    Some(Usage {
+        total_bytes: stat.f_blocks as u64 * fragment,
+        used_bytes: (stat.f_blocks as u64).saturating_sub(stat.f_bfree as u64) * fragment,
+        available_bytes: stat.f_bavail as u64 * fragment,
+        inodes_total: stat.f_files as u64,
+        inodes_free: stat.f_ffree as u64,
+    })
+}
+
+//Remote mounts whose statvfs thread never came back, by mount point. A hung server leaves that thread blocked, so
+//rather than stacking up another stuck thread every run we skip the mount until the first one returns.
+#[derive(Default)]
+pub struct HungMounts {
+    pending: HashMap<String, mpsc::Receiver<Option<Usage>>>,
+}
+
+impl HungMounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn usage(&mut self, path: &str, timeout: Duration) -> Option<Usage> {
+        self.usage_with(path, timeout, statvfs)
+    }
+
+    //Runs stat on a thread we can walk away from
+    fn usage_with(&mut self, path: &str, timeout: Duration, stat: fn(&str) -> Option<Usage>) -> Option<Usage> {
+        if let Some(receiver) = self.pending.get(path) {
+            if let Err(mpsc::TryRecvError::Empty) = receiver.try_recv() {
+                debug!("Skipping {}, the last usage check is still hung", path);
+                return None
+            }
+            //It came back in the end, give it another go
+            self.pending.remove(path);
+        }
+        let (sender, receiver) = mpsc::channel();
+        let owned = path.to_string();
+        thread::spawn(move || {
+            let _ = sender.send(stat(&owned));
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(usage) => usage,
+            Err(_) => {
+                warn!("Gave up waiting for the usage of remote mount {}", path);
+                self.pending.insert(path.to_string(), receiver);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Partition {
+    pub name: String,
+    pub size_bytes: Option<u64>,
+    pub start_sector: Option<u64>,
+    pub holders: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BlockDevice {
+    pub name: String,
+    //disk, partition, lvm, dm, md, loop
+    pub kind: String,
+    pub size_bytes: Option<u64>,
+    pub rotational: Option<bool>,
+    pub removable: Option<bool>,
+    pub read_only: Option<bool>,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub serial: Option<String>,
+    pub partitions: Vec<Partition>,
+    //Devices built on top of this one (dm-0 for an LVM PV, md0 for a RAID member)
+    pub holders: Vec<String>,
+    //Devices this one is built from, for dm and md devices
+    pub slaves: Vec<String>,
+    //Device mapper name, vg-lv for LVM volumes
+    pub dm_name: Option<String>,
+    pub raid_level: Option<String>,
+}
+
+fn read_value(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn list_names(path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .map(|entries| entries.filter_map(Result::ok).map(|e| e.file_name().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+//size in /sys/block is always in 512 byte sectors whatever the device's sector size
+fn sectors_to_bytes(value: Option<String>) -> Option<u64> {
+    value.and_then(|v| v.parse::<u64>().ok()).map(|sectors| sectors * 512)
+}
+
+impl BlockDevice {
+    pub fn read(sys_block: &Path, name: &str) -> Self {
+        let dir = sys_block.join(name);
+        let value = |file: &str| read_value(&dir.join(file));
+        let flag = |file: &str| value(file).map(|v| v == "1");
+
+        let partitions = list_names(&dir)
+            .into_iter()
+            .filter(|entry| dir.join(entry).join("partition").exists())
+            .map(|entry| {
+                let partition = dir.join(&entry);
+                Partition {
+                    size_bytes: sectors_to_bytes(read_value(&partition.join("size"))),
+                    start_sector: read_value(&partition.join("start")).and_then(|s| s.parse().ok()),
+                    holders: list_names(&partition.join("holders")),
+                    name: entry,
+                }
+            })
+            .collect();
+        let dm_uuid = value("dm/uuid");
+        let raid_level = value("md/level");
+        let kind = if dm_uuid.as_deref().map_or(false, |uuid| uuid.starts_with("LVM-")) {
+            "lvm"
+        } else if dm_uuid.is_some() || dir.join("dm").is_dir() {
+            "dm"
+        } else if raid_level.is_some() {
+            "md"
+        } else if name.starts_with("loop") {
+            "loop"
+        } else {
+            "disk"
+        };
+
+        Self {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            size_bytes: sectors_to_bytes(value("size")),
+            rotational: flag("queue/rotational"),
+            removable: flag("removable"),
+            read_only: flag("ro"),
+            model: value("device/model"),
+            vendor: value("device/vendor"),
+            serial: value("device/serial").or_else(|| value("serial")),
+            partitions: partitions,
+            holders: list_names(&dir.join("holders")),
+            slaves: list_names(&dir.join("slaves")),
+            dm_name: value("dm/name"),
+            raid_level: raid_level,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Storage {
+    pub mounts: Vec<Mount>,
+    pub block_devices: Vec<BlockDevice>,
+}
+
+impl Storage {
+    pub fn new_within(budget: &Budget) -> Result<Self, Box<dyn Error>> {
+        Self::scan(budget, &mut HungMounts::new())
+    }
+
+    //Stops early if the budget runs out, what was read so far is still returned
+    pub fn scan(budget: &Budget, hung: &mut HungMounts) -> Result<Self, Box<dyn Error>> {
+        let mut mounts = Vec::new();
+        for mut mount in parse_mountinfo(&fs::read_to_string(MOUNTINFO)?) {
+            if !budget.tick() {
+                break;
+            }
+            if !PSEUDO_FS_TYPES.contains(&mount.fs_type.as_str()) {
+                mount.usage = if mount.is_remote() {
+                    hung.usage(&mount.mount_point, REMOTE_STATVFS_TIMEOUT)
+                } else {
+                    statvfs(&mount.mount_point)
+                };
+            }
+            mounts.push(mount);
+        }
+        let mut block_devices = Vec::new();
+        for name in list_names(Path::new(SYS_BLOCK)) {
+            if !budget.tick() {
+                break;
+            }
+            block_devices.push(BlockDevice::read(Path::new(SYS_BLOCK), &name));
+        }
+        Ok(Self { mounts: mounts, block_devices: block_devices })
+    }
+}
+
+//A relationship from this node to another host it needs, for the architecture views
+#[derive(Serialize, Debug)]
+pub struct Dependency {
+    pub kind: String,
+    pub server: String,
+    pub protocol: String,
+    pub resource: String,
+    pub mount_point: String,
+}
+
+pub struct StorageCollector {
+    hung: HungMounts,
+}
+
+impl StorageCollector {
+    pub fn new() -> Self {
+        Self { hung: HungMounts::new() }
+    }
+}
+
+impl Collector for StorageCollector {
+    fn name(&self) -> &str {
+        "storage"
+    }
+    fn description(&self) -> &str {
+        "Mounted filesystems, block devices and remote mounts"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(900))
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let storage = Storage::scan(budget, &mut self.hung)?;
+        let mut records = Vec::new();
+        for mount in &storage.mounts {
+            if let Some(remote) = &mount.remote {
+                records.push(Record::new("dependencies", &Dependency {
+                    kind: "remote_mount".to_string(),
+                    server: remote.server.clone(),
+                    protocol: mount.fs_type.clone(),
+                    resource: remote.export.clone(),
+                    mount_point: mount.mount_point.clone(),
+                })?);
+            }
+        }
+        records.insert(0, Record::new("storage", &storage)?);
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "23 28 0:22 / /proc rw,relatime - proc proc rw\n\
+        28 1 253:1 / / rw,relatime shared:1 - ext4 /dev/mapper/vg0-root rw,errors=remount-ro\n\
+        40 28 0:45 / /mnt/team\\040share rw,relatime shared:20 - nfs4 files.example.com:/exports/team rw,vers=4.2,addr=10.0.0.9\n\
+        41 28 0:46 / /mnt/scans rw,relatime - cifs //nas01/scans rw,vers=3.1.1\n";
+
+    #[test]
+    fn mountinfo_is_parsed() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(mounts[1].mount_point, "/");
+        assert_eq!(mounts[1].fs_type, "ext4");
+        assert_eq!(mounts[1].device, "253:1");
+        assert_eq!(mounts[1].super_options, vec!["rw".to_string(), "errors=remount-ro".to_string()]);
+        assert_eq!(mounts[2].mount_point, "/mnt/team share");
+        assert!(mounts[1].remote.is_none());
+    }
+
+    #[test]
+    fn remote_mounts_have_server_and_export() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts[2].remote, Some(RemoteSource { server: "files.example.com".to_string(), export: "/exports/team".to_string() }));
+        assert_eq!(mounts[3].remote, Some(RemoteSource { server: "nas01".to_string(), export: "/scans".to_string() }));
+        assert_eq!(remote_source("[fd00::9]:/srv").map(|r| r.server), Some("fd00::9".to_string()));
+        assert_eq!(remote_source("backup@host:/data").map(|r| r.server), Some("host".to_string()));
+    }
+
+    #[test]
+    fn lvm_stack_is_read() {
+        let root = std::env::temp_dir().join(format!("node_agent-block-{}", uuid::Uuid::new_v4()));
+        let write = |file: &str, contents: &str| {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("sda/size", "41943040\n");
+        write("sda/queue/rotational", "0\n");
+        write("sda/device/model", "QEMU HARDDISK   \n");
+        write("sda/sda2/partition", "2\n");
+        write("sda/sda2/size", "39843840\n");
+        write("sda/sda2/start", "2099200\n");
+        write("sda/sda2/holders/dm-0", "");
+        write("dm-0/dm/name", "vg0-root\n");
+        write("dm-0/dm/uuid", "LVM-abc\n");
+        write("dm-0/slaves/sda2", "");
+
+        let sda = BlockDevice::read(&root, "sda");
+        assert_eq!(sda.kind, "disk");
+        assert_eq!(sda.size_bytes, Some(21474836480));
+        assert_eq!(sda.rotational, Some(false));
+        assert_eq!(sda.model.as_deref(), Some("QEMU HARDDISK"));
+        assert_eq!(sda.partitions[0].holders, vec!["dm-0".to_string()]);
+        let dm = BlockDevice::read(&root, "dm-0");
+        assert_eq!(dm.kind, "lvm");
+        assert_eq!(dm.dm_name.as_deref(), Some("vg0-root"));
+        assert_eq!(dm.slaves, vec!["sda2".to_string()]);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn local_root_has_usage() {
+        let storage = Storage::new_within(&Budget::unlimited()).unwrap();
+        let root = storage.mounts.iter().find(|m| m.mount_point == "/").unwrap();
+        assert!(root.usage.as_ref().map_or(false, |u| u.total_bytes > 0));
+    }
+
+    static STATS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    fn slow_stat(_path: &str) -> Option<Usage> {
+        STATS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(300));
+        None
+    }
+
+    #[test]
+    fn hung_mounts_are_not_retried_until_they_return() {
+        let mut hung = HungMounts::new();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(hung.usage_with("/mnt/nfs", timeout, slow_stat), None);
+        assert_eq!(hung.usage_with("/mnt/nfs", timeout, slow_stat), None);
+        assert_eq!(STATS.load(std::sync::atomic::Ordering::SeqCst), 1);
+        //Once the stuck call returns the mount is checked again
+        thread::sleep(Duration::from_millis(400));
+        hung.usage_with("/mnt/nfs", timeout, slow_stat);
+        assert_eq!(STATS.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
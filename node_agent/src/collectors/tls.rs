@@ -0,0 +1,260 @@
+//TLS handshakes against our own listening sockets, to see what certificate a service really presents rather than what
+//is lying around on disk. The probe accepts whatever it's given since it only records, it never trusts. Each endpoint
+//is tried once without SNI and once with the node's name to see whether the service needs or changes on SNI.
+//rustls only speaks TLS 1.2 and 1.3, endpoints limited to older versions show up as handshake errors.
+use crate::budget::Budget;
+use crate::collectors::certificates::Certificate;
+use crate::collectors::probing::{ProbeLimits, Prober};
+use crate::linux::sys_interagator::SystemInfo;
+use chrono::{DateTime, Utc};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TlsProbeConfig {
+    #[serde(flatten)]
+    pub limits: ProbeLimits,
+    //Name sent as SNI, the hostname when not set
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+//Records the chain whatever it is, signatures are taken on trust too
+#[derive(Debug)]
+struct RecordOnly(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for RecordOnly {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(&self, _message: &[u8], _cert: &CertificateDer<'_>, _dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+    fn verify_tls13_signature(&self, _message: &[u8], _cert: &CertificateDer<'_>, _dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub protocol: Option<String>,
+    pub cipher: Option<String>,
+    //Leaf first, "path" on each is the endpoint
+    pub chain: Vec<Certificate>,
+    pub error: Option<String>,
+}
+
+impl Handshake {
+    fn leaf(&self) -> Option<&str> {
+        self.chain.first().map(|certificate| certificate.fingerprint.as_str())
+    }
+}
+
+//A bare IP as the server name means rustls sends no SNI
+pub fn handshake(address: SocketAddr, server_name: Option<&str>, timeout: Duration) -> Handshake {
+    let mut result = Handshake { protocol: None, cipher: None, chain: Vec::new(), error: None };
+    if let Err(e) = try_handshake(address, server_name, timeout, &mut result) {
+        result.error = Some(e.to_string());
+    }
+    result
+}
+
+fn try_handshake(address: SocketAddr, server_name: Option<&str>, timeout: Duration, result: &mut Handshake) -> Result<(), Box<dyn Error>> {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(RecordOnly(provider)))
+        .with_no_client_auth();
+    let name = match server_name {
+        Some(name) => ServerName::try_from(name.to_string())?,
+        None => ServerName::IpAddress(address.ip().into()),
+    };
+    let mut connection = ClientConnection::new(Arc::new(config), name)?;
+    let mut socket = TcpStream::connect_timeout(&address, timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    let deadline = Instant::now() + timeout;
+    while connection.is_handshaking() {
+        if Instant::now() > deadline {
+            return Err("handshake timed out".into())
+        }
+        connection.complete_io(&mut socket)?;
+    }
+    let endpoint = address.to_string();
+    result.protocol = connection.protocol_version().map(|version| format!("{:?}", version));
+    result.cipher = connection.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite()));
+    for (index, der) in connection.peer_certificates().unwrap_or_default().iter().enumerate() {
+        result.chain.extend(Certificate::parse(der.as_ref(), Path::new(&endpoint), index));
+    }
+    connection.send_close_notify();
+    let _ = connection.complete_io(&mut socket);
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TlsEndpoint {
+    pub address: String,
+    pub tls: bool,
+    pub probed_at: DateTime<Utc>,
+    //The handshake with SNI when it worked, otherwise the one without
+    #[serde(flatten)]
+    pub handshake: Handshake,
+    pub server_name: String,
+    //Handshake fails without SNI but works with it
+    pub requires_sni: bool,
+    //A different certificate comes back with SNI than without
+    pub sni_changes_certificate: bool,
+}
+
+pub fn probe(address: SocketAddr, server_name: &str, timeout: Duration) -> TlsEndpoint {
+    let without = handshake(address, None, timeout);
+    let with = handshake(address, Some(server_name), timeout);
+    let requires_sni = without.error.is_some() && with.error.is_none();
+    let sni_changes_certificate = without.leaf().is_some() && with.leaf().is_some() && without.leaf() != with.leaf();
+    let tls = without.error.is_none() || with.error.is_none();
+    TlsEndpoint {
+        address: address.to_string(),
+        tls: tls,
+        probed_at: Utc::now(),
+        handshake: if with.error.is_none() || without.error.is_some() {with} else {without},
+        server_name: server_name.to_string(),
+        requires_sni: requires_sni,
+        sni_changes_certificate: sni_changes_certificate,
+    }
+}
+
+pub struct TlsProber {
+    prober: Prober<TlsEndpoint>,
+    server_name: Option<String>,
+}
+
+impl TlsProber {
+    pub fn new(config: TlsProbeConfig) -> Self {
+        Self { prober: Prober::new(config.limits), server_name: config.server_name }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.prober.limits().enabled
+    }
+
+    pub fn probe_all(&mut self, listeners: &[(SocketAddr, u32, String)], budget: &Budget) -> &HashMap<(SocketAddr, u32), TlsEndpoint> {
+        let server_name = self.server_name.clone().unwrap_or_else(SystemInfo::get_hostname);
+        let timeout = self.prober.limits().timeout();
+        self.prober.probe_all(listeners, budget, |address| probe(address, &server_name, timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const KEY: &str = "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgvUzLqjxjNEk2LLYqBQlDdauF32t5CWMhnfh1FxxYMkShRANCAASgllcQSLSuXTeYQKzkLiWyd3fUnitc6uOTnWEUi25OjAY0sizWspFgNJUdaz41g1HLT77fWLq+XhzncmjkSmPX";
+    //CN=web01.example.com, served when the client asks for it by SNI
+    const NAMED: &str = "MIIBlzCCAT6gAwIBAgIBATAKBggqhkjOPQQDAjAcMRowGAYDVQQDDBF3ZWIwMS5leGFtcGxlLmNvbTAeFw0yNjEwMTkwNTM3MjFaFw0zNjEwMTYwNTM3MjFaMBwxGjAYBgNVBAMMEXdlYjAxLmV4YW1wbGUuY29tMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEoJZXEEi0rl03mECs5C4lsnd31J4rXOrjk51hFItuTowGNLIs1rKRYDSVHWs+NYNRy0++31i6vl4c53Jo5Epj16NxMG8wHQYDVR0OBBYEFLH2+lcLGdNtpkI+yER9a5EWri+4MB8GA1UdIwQYMBaAFLH2+lcLGdNtpkI+yER9a5EWri+4MA8GA1UdEwEB/wQFMAMBAf8wHAYDVR0RBBUwE4IRd2ViMDEuZXhhbXBsZS5jb20wCgYIKoZIzj0EAwIDRwAwRAIgCuV2izi7/KLmkzqCkERt9DO9S0YdVHakgDbncsOFpzcCIDejNpMn5ahV7R05+7c8ysF8rKmVsVLJYnrfijP8LDZ1";
+    //CN=default.invalid, for everyone else
+    const FALLBACK: &str = "MIIBdjCCARygAwIBAgIBAjAKBggqhkjOPQQDAjAaMRgwFgYDVQQDDA9kZWZhdWx0LmludmFsaWQwHhcNMjYxMDE5MDUzNzIxWhcNMzYxMDE2MDUzNzIxWjAaMRgwFgYDVQQDDA9kZWZhdWx0LmludmFsaWQwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAASgllcQSLSuXTeYQKzkLiWyd3fUnitc6uOTnWEUi25OjAY0sizWspFgNJUdaz41g1HLT77fWLq+XhzncmjkSmPXo1MwUTAdBgNVHQ4EFgQUsfb6VwsZ022mQj7IRH1rkRauL7gwHwYDVR0jBBgwFoAUsfb6VwsZ022mQj7IRH1rkRauL7gwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiB4FnguwKytRWiHuqSTzbGAw3H+G17Ik4vJyn4aHHP1oAIhAIa4pXXR9ddRBiZIXRhZuyPlRfVXJ5AqCp+TyRs9S9/8";
+
+    #[derive(Debug)]
+    struct BySni {
+        named: Arc<CertifiedKey>,
+        fallback: Option<Arc<CertifiedKey>>,
+    }
+
+    impl ResolvesServerCert for BySni {
+        fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            match hello.server_name() {
+                Some("web01.example.com") => Some(self.named.clone()),
+                _ => self.fallback.clone(),
+            }
+        }
+    }
+
+    //Serves handshakes on a loopback port until the test is done with it
+    fn tls_server(with_fallback: bool) -> SocketAddr {
+        let provider = Arc::new(ring::default_provider());
+        let key = provider.key_provider.load_private_key(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(STANDARD.decode(KEY).unwrap()))).unwrap();
+        let certified = |cert: &str| Arc::new(CertifiedKey::new(vec![CertificateDer::from(STANDARD.decode(cert).unwrap())], key.clone()));
+        let resolver = BySni { named: certified(NAMED), fallback: if with_fallback {Some(certified(FALLBACK))} else {None} };
+        let config = Arc::new(ServerConfig::builder_with_provider(provider).with_safe_default_protocol_versions().unwrap().with_no_client_auth().with_cert_resolver(Arc::new(resolver)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut socket in listener.incoming().filter_map(Result::ok) {
+                let mut connection = ServerConnection::new(config.clone()).unwrap();
+                while connection.is_handshaking() {
+                    if connection.complete_io(&mut socket).is_err() {
+                        break;
+                    }
+                }
+                let _ = connection.complete_io(&mut socket);
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn handshake_records_chain_and_sni_behaviour() {
+        let endpoint = probe(tls_server(true), "web01.example.com", Duration::from_secs(5));
+        assert!(endpoint.tls);
+        assert_eq!(endpoint.handshake.protocol.as_deref(), Some("TLSv1_3"));
+        assert!(endpoint.handshake.cipher.is_some());
+        assert_eq!(endpoint.handshake.chain[0].common_name.as_deref(), Some("web01.example.com"));
+        assert!(endpoint.sni_changes_certificate && !endpoint.requires_sni);
+
+        let strict = probe(tls_server(false), "web01.example.com", Duration::from_secs(5));
+        assert!(strict.tls && strict.requires_sni && !strict.sni_changes_certificate);
+    }
+
+    #[test]
+    fn plain_tcp_services_are_not_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut socket in listener.incoming().filter_map(Result::ok) {
+                let _ = socket.write_all(b"SSH-2.0-OpenSSH_9.6\r\n");
+            }
+        });
+        let endpoint = probe(address, "web01.example.com", Duration::from_secs(5));
+        assert!(!endpoint.tls);
+        assert!(endpoint.handshake.error.is_some() && endpoint.handshake.chain.is_empty());
+    }
+
+    #[test]
+    fn exclusions_and_reprobe_interval() {
+        let address = tls_server(true);
+        let limits = ProbeLimits { enabled: true, exclude_processes: vec!["sshd".to_string()], probes_per_second: 50, ..Default::default() };
+        let config = TlsProbeConfig { limits: limits, server_name: Some("web01.example.com".to_string()) };
+        let mut prober = TlsProber::new(config);
+        let ssh: SocketAddr = "127.0.0.1:22".parse().unwrap();
+        let listeners = vec![(address, 100, "nginx".to_string()), (ssh, 200, "sshd".to_string())];
+        let first = prober.probe_all(&listeners, &Budget::unlimited()).clone();
+        assert_eq!(first.keys().collect::<Vec<_>>(), vec![&(address, 100)]);
+        //Still fresh, so the same result comes back without a new handshake
+        assert_eq!(prober.probe_all(&listeners, &Budget::unlimited())[&(address, 100)].probed_at, first[&(address, 100)].probed_at);
+        //A new process on the port gets probed again
+        assert!(prober.probe_all(&[(address, 101, "nginx".to_string())], &Budget::unlimited()).contains_key(&(address, 101)));
+        assert_eq!(crate::collectors::probing::probe_address("0.0.0.0:443".parse().unwrap()), "127.0.0.1:443".parse().unwrap());
+    }
+}
@@ -0,0 +1,279 @@
+//systemd units on the node: what the unit files say (ExecStart, User, WantedBy...), whether they're enabled, and from
+//systemctl what is running now and its main PID. Processes get their unit from their cgroup in the process scan.
+use crate::budget::Budget;
+use crate::collectors::{Collector, Record, Schedule};
+use log::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+//Highest priority first, a unit in /etc overrides the packaged one of the same name
+pub const UNIT_DIRS: &[&str] = &["/etc/systemd/system", "/run/systemd/system", "/usr/lib/systemd/system", "/lib/systemd/system"];
+const UNIT_KINDS: &[&str] = &["service", "socket", "timer"];
+
+//Unit file sections, keys keep every value in order since ExecStart and friends can repeat
+pub type UnitFile = HashMap<String, HashMap<String, Vec<String>>>;
+
+//INI style with line continuations. An empty assignment resets a list, which is how drop-ins clear ExecStart.
+pub fn parse_unit_file(contents: &str, unit: &mut UnitFile) {
+    let mut section = String::new();
+    let mut pending = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(continued) = line.strip_suffix('\\') {
+            pending.push_str(continued);
+            pending.push(' ');
+            continue;
+        }
+        let line = format!("{}{}", pending, line);
+        pending.clear();
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            let values = unit.entry(section.clone()).or_default().entry(key.trim().to_string()).or_default();
+            let value = value.trim();
+            if value.is_empty() {
+                values.clear();
+            } else {
+                values.push(value.to_string());
+            }
+        }
+    }
+}
+
+//The unit file found first in dirs plus its drop-ins (name.d/*.conf) from every dir, lowest priority first
+pub fn load_unit_file(dirs: &[PathBuf], name: &str) -> (Option<PathBuf>, UnitFile) {
+    let path = dirs.iter().map(|dir| dir.join(name)).find(|path| path.exists() || path.is_symlink());
+    let mut file = UnitFile::new();
+    if let Some(path) = &path {
+        parse_unit_file(&fs::read_to_string(path).unwrap_or_default(), &mut file);
+    }
+    let mut drop_ins: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for dir in dirs.iter().rev() {
+        for entry in fs::read_dir(dir.join(format!("{}.d", name))).into_iter().flatten().filter_map(Result::ok) {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.ends_with(".conf") {
+                drop_ins.insert(file_name, entry.path());
+            }
+        }
+    }
+    for drop_in in drop_ins.values() {
+        parse_unit_file(&fs::read_to_string(drop_in).unwrap_or_default(), &mut file);
+    }
+    (path, file)
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Unit {
+    pub name: String,
+    pub kind: String,
+    pub path: Option<String>,
+    pub description: Option<String>,
+    pub service_type: Option<String>,
+    pub exec_start: Vec<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub wanted_by: Vec<String>,
+    //enabled, disabled, static (no [Install] section), masked or alias
+    pub enablement: String,
+    pub active_state: Option<String>,
+    pub sub_state: Option<String>,
+    pub main_pid: Option<u32>,
+}
+
+impl Unit {
+    pub fn read(dirs: &[PathBuf], name: &str) -> Self {
+        let kind = name.rsplit('.').next().unwrap_or("").to_string();
+        let (path, file) = load_unit_file(dirs, name);
+        let masked = path.as_ref().and_then(|path| fs::read_link(path).ok()).map_or(false, |target| target == Path::new("/dev/null"));
+
+        let get = |section: &str, key: &str| file.get(section).and_then(|s| s.get(key)).cloned().unwrap_or_default();
+        let last = |section: &str, key: &str| get(section, key).pop();
+        let wanted_by: Vec<String> = get("Install", "WantedBy").iter().chain(get("Install", "RequiredBy").iter()).flat_map(|v| v.split_whitespace().map(String::from)).collect();
+        //Enabling a unit symlinks it into the .wants/.requires directory of each target in /etc
+        let enabled = wanted_by.iter().any(|target| {
+            dirs.iter().any(|dir| dir.join(format!("{}.wants", target)).join(name).exists() || dir.join(format!("{}.requires", target)).join(name).exists())
+        });
+        let enablement = if masked {
+            "masked"
+        } else if enabled {
+            "enabled"
+        } else if !file.contains_key("Install") {
+            "static"
+        } else {
+            "disabled"
+        };
+
+        Self {
+            name: name.to_string(),
+            path: path.map(|p| p.display().to_string()),
+            description: last("Unit", "Description"),
+            service_type: last("Service", "Type"),
+            exec_start: get(&if kind == "socket" {"Socket".to_string()} else {"Service".to_string()}, "ExecStart"),
+            user: last("Service", "User"),
+            group: last("Service", "Group"),
+            wanted_by: wanted_by,
+            enablement: enablement.to_string(),
+            kind: kind,
+            ..Default::default()
+        }
+    }
+}
+
+//Unit names of the kinds we track across every unit dir, skipping templates (foo@.service) which never run as is
+pub fn unit_names(dirs: &[PathBuf]) -> Vec<String> {
+    let mut names: Vec<String> = dirs
+        .iter()
+        .flat_map(|dir| fs::read_dir(dir).into_iter().flatten().filter_map(Result::ok))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| UNIT_KINDS.iter().any(|kind| name.ends_with(&format!(".{}", kind))) && !name.contains("@."))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct UnitState {
+    pub unit: Option<String>,
+    pub next_elapse: Option<String>,
+    pub last_trigger: Option<String>,
+    pub active_state: Option<String>,
+    pub sub_state: Option<String>,
+    pub main_pid: Option<u32>,
+}
+
+//Blocks of Key=value separated by blank lines, from "systemctl show -p Id,..."
+pub fn parse_systemctl_show(output: &str) -> HashMap<String, UnitState> {
+    let mut states = HashMap::new();
+    for block in output.split("\n\n") {
+        let mut id = None;
+        let mut state = UnitState::default();
+        for (key, value) in block.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "Id" => id = Some(value.to_string()),
+                "ActiveState" => state.active_state = Some(value.to_string()),
+                "SubState" => state.sub_state = Some(value.to_string()),
+                //0 when nothing is running
+                "MainPID" => state.main_pid = value.parse().ok().filter(|pid| *pid != 0),
+                //Timers only
+                "Unit" => state.unit = Some(value.to_string()),
+                "NextElapseUSecRealtime" => state.next_elapse = Some(value.to_string()).filter(|v| !v.is_empty()),
+                "LastTriggerUSec" => state.last_trigger = Some(value.to_string()).filter(|v| !v.is_empty()),
+                _ => {}
+            }
+        }
+        if let Some(id) = id {
+            states.insert(id, state);
+        }
+    }
+    states
+}
+
+//Timestamps come back as @<epoch seconds>
+pub fn unit_states(patterns: &[String]) -> HashMap<String, UnitState> {
+    let mut args = vec!["show".to_string(), "--timestamp=unix".to_string(), "--property=Id,ActiveState,SubState,MainPID,Unit,NextElapseUSecRealtime,LastTriggerUSec".to_string(), "--".to_string()];
+    args.extend(patterns.iter().cloned());
+    match Command::new("systemctl").args(&args).output() {
+        Ok(output) if output.status.success() => parse_systemctl_show(&String::from_utf8_lossy(&output.stdout)),
+        Ok(output) => {
+            debug!("systemctl show failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+            HashMap::new()
+        }
+        Err(e) => {
+            debug!("Cannot run systemctl: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+pub fn get_units(budget: &Budget) -> Vec<Unit> {
+    let dirs: Vec<PathBuf> = UNIT_DIRS.iter().map(PathBuf::from).collect();
+    let mut states = unit_states(&UNIT_KINDS.iter().map(|kind| format!("*.{}", kind)).collect::<Vec<String>>());
+    let mut units = Vec::new();
+    for name in unit_names(&dirs) {
+        if !budget.tick() {
+            break;
+        }
+        let mut unit = Unit::read(&dirs, &name);
+        if let Some(state) = states.remove(&name) {
+            unit.active_state = state.active_state;
+            unit.sub_state = state.sub_state;
+            unit.main_pid = state.main_pid;
+        }
+        units.push(unit);
+    }
+    units
+}
+
+pub struct UnitCollector;
+
+impl UnitCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Collector for UnitCollector {
+    fn name(&self) -> &str {
+        "units"
+    }
+    fn description(&self) -> &str {
+        "systemd services, sockets and timers with their state and main PID"
+    }
+    fn schedule(&self) -> Schedule {
+        Schedule::Every(Duration::from_secs(300))
+    }
+    fn collect(&mut self, budget: &Budget) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut records = Vec::new();
+        for unit in get_units(budget) {
+            records.push(Record::new("units", &unit)?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_ins_override_the_unit_file() {
+        let root = std::env::temp_dir().join(format!("node_agent-units-{}", uuid::Uuid::new_v4()));
+        let etc = root.join("etc");
+        let lib = root.join("lib");
+        fs::create_dir_all(etc.join("multi-user.target.wants")).unwrap();
+        fs::create_dir_all(lib.join("nginx.service.d")).unwrap();
+        fs::write(lib.join("nginx.service"), "[Unit]\nDescription=A high performance web server\n\n[Service]\nType=forking\nExecStart=/usr/sbin/nginx \\\n  -g 'daemon on;'\n\n[Install]\nWantedBy=multi-user.target\n").unwrap();
+        fs::write(lib.join("nginx.service.d/override.conf"), "[Service]\nExecStart=\nExecStart=/usr/local/sbin/nginx\nUser=www-data\n").unwrap();
+        std::os::unix::fs::symlink(lib.join("nginx.service"), etc.join("multi-user.target.wants/nginx.service")).unwrap();
+        fs::write(lib.join("systemd-journald.service"), "[Service]\nExecStart=/lib/systemd/systemd-journald\n").unwrap();
+        std::os::unix::fs::symlink("/dev/null", etc.join("apache2.service")).unwrap();
+
+        let dirs = vec![etc, lib];
+        assert_eq!(unit_names(&dirs), vec!["apache2.service", "nginx.service", "systemd-journald.service"]);
+        let nginx = Unit::read(&dirs, "nginx.service");
+        assert_eq!(nginx.exec_start, vec!["/usr/local/sbin/nginx".to_string()]);
+        assert_eq!(nginx.user.as_deref(), Some("www-data"));
+        assert_eq!(nginx.wanted_by, vec!["multi-user.target".to_string()]);
+        assert_eq!(nginx.enablement, "enabled");
+        assert_eq!(Unit::read(&dirs, "systemd-journald.service").enablement, "static");
+        assert_eq!(Unit::read(&dirs, "apache2.service").enablement, "masked");
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn systemctl_show_is_parsed() {
+        let states = parse_systemctl_show("Id=nginx.service\nActiveState=active\nSubState=running\nMainPID=812\n\nId=apt-daily.timer\nActiveState=active\nSubState=waiting\nMainPID=0\nUnit=apt-daily.service\nNextElapseUSecRealtime=@1760918400\n");
+        assert_eq!(states["nginx.service"], UnitState { active_state: Some("active".to_string()), sub_state: Some("running".to_string()), main_pid: Some(812), ..Default::default() });
+        assert_eq!(states["apt-daily.timer"].main_pid, None);
+        assert_eq!(states["apt-daily.timer"].next_elapse.as_deref(), Some("@1760918400"));
+    }
+}
@@ -0,0 +1,211 @@
+//What the node runs on, for node_type on the node record: bare metal, a virtual machine, a container or a cloud
+//instance. Uses the same evidence as systemd-detect-virt: DMI vendor strings, the CPU hypervisor flag, /sys/hypervisor,
+//container marker files and the environment and cgroups of pid 1.
+use crate::collectors::hardware::{CpuInfo, Dmi};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+//Everything detection looks at, gathered up front so the rules can be tested without the real /proc and /sys
+#[derive(Debug, Default)]
+pub struct Evidence {
+    pub dmi: Dmi,
+    pub cpu_hypervisor_flag: bool,
+    //Contents of /sys/hypervisor/type, "xen" on Xen guests without DMI
+    pub sys_hypervisor: Option<String>,
+    //container= from pid 1's environment (set by podman, lxc, systemd-nspawn...)
+    pub pid1_container: Option<String>,
+    pub pid1_cgroup: String,
+    pub dockerenv: bool,
+    pub containerenv: bool,
+    pub kubernetes: bool,
+    pub kernel_release: String,
+}
+
+impl Evidence {
+    pub fn read() -> Self {
+        let pid1_environ = fs::read("/proc/1/environ").unwrap_or_default();
+        Self {
+            dmi: Dmi::read(),
+            cpu_hypervisor_flag: CpuInfo::read().flags.iter().any(|flag| flag == "hypervisor"),
+            sys_hypervisor: fs::read_to_string("/sys/hypervisor/type").ok().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()),
+            pid1_container: pid1_environ
+                .split(|b| *b == 0)
+                .find_map(|var| var.strip_prefix(b"container="))
+                .map(|value| String::from_utf8_lossy(value).into_owned()),
+            pid1_cgroup: fs::read_to_string("/proc/1/cgroup").unwrap_or_default(),
+            dockerenv: Path::new("/.dockerenv").exists(),
+            containerenv: Path::new("/run/.containerenv").exists(),
+            kubernetes: std::env::var_os("KUBERNETES_SERVICE_HOST").is_some(),
+            kernel_release: fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Environment {
+    //bare_metal, virtual_machine, container or cloud_instance
+    pub node_type: String,
+    //systemd-detect-virt style names: kvm, qemu, vmware, microsoft, oracle, xen...
+    pub hypervisor: Option<String>,
+    pub container_runtime: Option<String>,
+    pub cloud_provider: Option<String>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::detect(&Evidence::read())
+    }
+
+    //A container wins over anything the host's DMI says, and a cloud instance is a VM we can name the provider of
+    pub fn detect(evidence: &Evidence) -> Self {
+        let container_runtime = container_runtime(evidence);
+        let hypervisor = hypervisor(evidence);
+        let cloud_provider = cloud_provider(&evidence.dmi);
+        let node_type = if container_runtime.is_some() {
+            "container"
+        } else if cloud_provider.is_some() {
+            "cloud_instance"
+        } else if hypervisor.is_some() {
+            "virtual_machine"
+        } else {
+            "bare_metal"
+        };
+        Self {
+            node_type: node_type.to_string(),
+            hypervisor: hypervisor,
+            container_runtime: container_runtime,
+            cloud_provider: cloud_provider,
+        }
+    }
+}
+
+fn container_runtime(evidence: &Evidence) -> Option<String> {
+    if evidence.kubernetes || evidence.pid1_cgroup.contains("kubepods") {
+        return Some("kubernetes".to_string())
+    }
+    if let Some(container) = &evidence.pid1_container {
+        //"oci" is what podman and crun set without naming themselves
+        return Some(if container == "oci" {"podman".to_string()} else {container.clone()})
+    }
+    if evidence.containerenv {
+        return Some("podman".to_string())
+    }
+    if evidence.dockerenv {
+        return Some("docker".to_string())
+    }
+    let cgroup_runtimes = [("/docker", "docker"), ("/libpod-", "podman"), ("/lxc", "lxc"), ("containerd", "containerd")];
+    if let Some((_, runtime)) = cgroup_runtimes.iter().find(|(marker, _)| evidence.pid1_cgroup.contains(marker)) {
+        return Some(runtime.to_string())
+    }
+    //WSL2 kernels are built by Microsoft and say so in the release string
+    if evidence.kernel_release.to_lowercase().contains("microsoft") {
+        return Some("wsl".to_string())
+    }
+    None
+}
+
+fn hypervisor(evidence: &Evidence) -> Option<String> {
+    let dmi = &evidence.dmi;
+    let vendors = [&dmi.sys_vendor, &dmi.product_name, &dmi.board_vendor, &dmi.bios_vendor];
+    let dmi_vendors: &[(&str, &str)] = &[
+        ("KVM", "kvm"),
+        ("Amazon EC2", "amazon"),
+        ("QEMU", "qemu"),
+        ("VMware", "vmware"),
+        ("VMW", "vmware"),
+        ("innotek GmbH", "oracle"),
+        ("VirtualBox", "oracle"),
+        ("Xen", "xen"),
+        ("Bochs", "bochs"),
+        ("Parallels", "parallels"),
+        ("BHYVE", "bhyve"),
+        ("Google", "google"),
+        ("OpenStack", "kvm"),
+    ];
+    for vendor in vendors.iter().filter_map(|v| v.as_deref()) {
+        if let Some((_, name)) = dmi_vendors.iter().find(|(marker, _)| vendor.starts_with(marker)) {
+            return Some(name.to_string())
+        }
+    }
+    //Hyper-V guests report the host's manufacturer, the product name gives them away
+    if dmi.sys_vendor.as_deref() == Some("Microsoft Corporation") && dmi.product_name.as_deref() == Some("Virtual Machine") {
+        return Some("microsoft".to_string())
+    }
+    if let Some(hypervisor) = &evidence.sys_hypervisor {
+        return Some(hypervisor.clone())
+    }
+    if evidence.cpu_hypervisor_flag {
+        return Some("unknown".to_string())
+    }
+    None
+}
+
+fn cloud_provider(dmi: &Dmi) -> Option<String> {
+    let has = |field: &Option<String>, marker: &str| field.as_deref().map_or(false, |value| value.to_lowercase().contains(marker));
+    let provider = if has(&dmi.sys_vendor, "amazon") || has(&dmi.bios_version, "amazon") || has(&dmi.product_version, "amazon") {
+        "aws"
+    } else if has(&dmi.sys_vendor, "google") || has(&dmi.product_name, "google compute engine") {
+        "gcp"
+    //Azure's fixed chassis asset tag
+    } else if dmi.chassis_asset_tag.as_deref() == Some("7783-7084-3265-9085-8269-3286-77") {
+        "azure"
+    } else if has(&dmi.chassis_asset_tag, "oraclecloud.com") {
+        "oci"
+    } else if has(&dmi.sys_vendor, "digitalocean") {
+        "digitalocean"
+    } else if has(&dmi.sys_vendor, "hetzner") {
+        "hetzner"
+    } else if has(&dmi.sys_vendor, "alibaba cloud") {
+        "alibaba"
+    } else if has(&dmi.product_name, "openstack") || has(&dmi.sys_vendor, "openstack") {
+        "openstack"
+    } else {
+        return None
+    };
+    Some(provider.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dmi(sys_vendor: &str, product_name: &str) -> Dmi {
+        Dmi { sys_vendor: Some(sys_vendor.to_string()), product_name: Some(product_name.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn physical_server_is_bare_metal() {
+        let environment = Environment::detect(&Evidence { dmi: dmi("Dell Inc.", "PowerEdge R650"), ..Default::default() });
+        assert_eq!(environment, Environment { node_type: "bare_metal".to_string(), ..Default::default() });
+    }
+
+    #[test]
+    fn hypervisors_are_named() {
+        let environment = Environment::detect(&Evidence { dmi: dmi("VMware, Inc.", "VMware7,1"), cpu_hypervisor_flag: true, ..Default::default() });
+        assert_eq!(environment.node_type, "virtual_machine");
+        assert_eq!(environment.hypervisor.as_deref(), Some("vmware"));
+        let hyperv = Environment::detect(&Evidence { dmi: dmi("Microsoft Corporation", "Virtual Machine"), ..Default::default() });
+        assert_eq!(hyperv.hypervisor.as_deref(), Some("microsoft"));
+        let unknown = Environment::detect(&Evidence { cpu_hypervisor_flag: true, ..Default::default() });
+        assert_eq!(unknown.hypervisor.as_deref(), Some("unknown"));
+    }
+
+    #[test]
+    fn cloud_instances_are_recognised() {
+        let aws = Environment::detect(&Evidence { dmi: dmi("Amazon EC2", "m5.large"), ..Default::default() });
+        assert_eq!((aws.node_type.as_str(), aws.cloud_provider.as_deref(), aws.hypervisor.as_deref()), ("cloud_instance", Some("aws"), Some("amazon")));
+        let azure = Dmi { chassis_asset_tag: Some("7783-7084-3265-9085-8269-3286-77".to_string()), ..dmi("Microsoft Corporation", "Virtual Machine") };
+        assert_eq!(Environment::detect(&Evidence { dmi: azure, ..Default::default() }).cloud_provider.as_deref(), Some("azure"));
+    }
+
+    #[test]
+    fn containers_win_over_host_dmi() {
+        let docker = Environment::detect(&Evidence { dmi: dmi("QEMU", "Standard PC"), dockerenv: true, ..Default::default() });
+        assert_eq!((docker.node_type.as_str(), docker.container_runtime.as_deref()), ("container", Some("docker")));
+        let k8s = Environment::detect(&Evidence { pid1_cgroup: "0::/kubepods/besteffort/pod1234/abcd\n".to_string(), ..Default::default() });
+        assert_eq!(k8s.container_runtime.as_deref(), Some("kubernetes"));
+        let nspawn = Environment::detect(&Evidence { pid1_container: Some("systemd-nspawn".to_string()), ..Default::default() });
+        assert_eq!(nspawn.container_runtime.as_deref(), Some("systemd-nspawn"));
+    }
+}
@@ -0,0 +1,94 @@
+//Agent configuration file (JSON). Everything is optional, anything left out falls back to the built in defaults.
+use crate::budget::BudgetConfig;
+use crate::collectors::banners::ServiceProbeConfig;
+use crate::collectors::certificates::CertificateConfig;
+use crate::collectors::tls::TlsProbeConfig;
+use crate::collectors::script::ScriptConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub collectors: CollectorsConfig,
+    //External executables run as collectors, see collectors::script
+    pub scripts: Vec<ScriptConfig>,
+    //CPU/IO priority and per collector scan limits, see budget
+    pub budgets: BudgetConfig,
+    //Where the certificates collector looks, see collectors::certificates
+    pub certificates: CertificateConfig,
+    //Connections to the node's own listeners to name the service behind them, see collectors::banners
+    pub service_probe: ServiceProbeConfig,
+    //Handshakes with the node's own listeners, see collectors::tls
+    pub tls_probe: TlsProbeConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct CollectorsConfig {
+    //Collectors to switch on or off by name
+    pub enable: Vec<String>,
+    pub disable: Vec<String>,
+    //Run interval overrides in seconds, by collector name
+    pub intervals: HashMap<String, u64>,
+}
+
+impl AgentConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Cannot read config {}: {}", path.display(), e))?;
+        let config: Self = serde_json::from_str(&contents).map_err(|e| format!("Cannot parse config {}: {}", path.display(), e))?;
+        for script in &config.scripts {
+            script.validate().map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config: AgentConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.collectors.enable.is_empty());
+        assert!(config.collectors.intervals.is_empty());
+    }
+
+    #[test]
+    fn collector_settings_are_read() {
+        let config: AgentConfig = serde_json::from_str(r#"{"collectors":{"disable":["connections"],"intervals":{"processes":30}}}"#).unwrap();
+        assert_eq!(config.collectors.disable, vec!["connections".to_string()]);
+        assert_eq!(config.collectors.intervals.get("processes"), Some(&30));
+    }
+
+    #[test]
+    fn script_defaults_are_filled_in() {
+        let config: AgentConfig = serde_json::from_str(r#"{"scripts":[{"name":"licences","path":"/opt/checks/licences"}]}"#).unwrap();
+        assert_eq!(config.scripts[0].timeout_secs, 30);
+        assert!(config.scripts[0].schema.additional_fields);
+    }
+
+    #[test]
+    fn certificate_paths_replace_the_defaults() {
+        let config: AgentConfig = serde_json::from_str(r#"{"certificates":{"paths":["/srv/app/tls"]}}"#).unwrap();
+        assert_eq!(config.certificates.paths, vec![std::path::PathBuf::from("/srv/app/tls")]);
+        assert_eq!(config.certificates.max_depth, 6);
+        assert!(AgentConfig::default().certificates.paths.contains(&std::path::PathBuf::from("/etc/ssl")));
+    }
+
+    #[test]
+    fn listener_probing_is_opt_in() {
+        assert!(!AgentConfig::default().tls_probe.limits.enabled);
+        assert!(!AgentConfig::default().service_probe.limits.enabled);
+        let config: AgentConfig = serde_json::from_str(r#"{"tls_probe":{"enabled":true,"exclude_ports":[5432],"server_name":"web01"},"service_probe":{"greeting_wait_ms":500}}"#).unwrap();
+        assert!(config.tls_probe.limits.enabled);
+        assert_eq!(config.tls_probe.limits.exclude_ports, vec![5432]);
+        assert_eq!(config.tls_probe.limits.max_probes_per_run, 20);
+        assert_eq!(config.tls_probe.server_name.as_deref(), Some("web01"));
+        assert_eq!((config.service_probe.greeting_wait_ms, config.service_probe.limits.timeout_ms), (500, 3000));
+    }
+}
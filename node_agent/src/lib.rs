@@ -4,6 +4,10 @@ pub mod inventory_client {
     use log::*;
     use std::error::Error;
     use std::collections::VecDeque;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::path::{Path, PathBuf};
+    use chrono::{DateTime, Utc};
     use uuid::Uuid;
     use serde::{Deserialize, Serialize};
     extern crate paho_mqtt as mqtt;
@@ -17,8 +21,14 @@ pub mod inventory_client {
         message_queue: VecDeque<Message>,
         pub queue_length: usize,
         pub max_queue_length: usize,
+        last_will: Option<mqtt::Message>,
+        //With a spool, messages that don't fit on the queue go to disk instead of being dropped
+        spool_path: Option<PathBuf>,
+        //Messages in the spool file waiting for room on the queue
+        pub spooled: usize,
     }
 
+    #[derive(Serialize, Deserialize)]
     struct Message {
         topic: String,
         payload: String,
@@ -42,17 +52,25 @@ pub mod inventory_client {
                 message_queue: VecDeque::new(),
                 queue_length: 0,
                 max_queue_length: 100,
+                last_will: None,
+                spool_path: None,
+                spooled: 0,
             }
 
         }
         pub fn connect(&mut self) -> Result<(),mqtt::Error>{
-            let conn_opts = mqtt::ConnectOptionsBuilder::new()
+            let mut conn_builder = mqtt::ConnectOptionsBuilder::new();
+            conn_builder
             .keep_alive_interval(Duration::from_secs(20))
             .clean_session(true)
             .retry_interval(Duration::from_secs(self.retry_delay_secs))
             .connect_timeout(Duration::from_secs(20))
-            .automatic_reconnect(Duration::from_secs(5),Duration::from_secs(3600))
-            .finalize();
+            .automatic_reconnect(Duration::from_secs(5),Duration::from_secs(3600));
+            //The broker publishes the will for us if the agent dies without disconnecting
+            if let Some(will) = &self.last_will {
+                conn_builder.will_message(will.clone());
+            }
+            let conn_opts = conn_builder.finalize();
 
             //let max_retries = 10;
             let wait = time::Duration::from_secs(self.retry_delay_secs);
@@ -84,9 +102,9 @@ pub mod inventory_client {
             return Err(mqtt::Error::from("Failed to connect"))
         }
         pub fn disconnect(&mut self) -> Result<(),mqtt::Error>{
-            if self.client.is_connected() == false {
+            if self.client.is_connected() == true {
                 let _ = self.client.disconnect(None)?;
-                let _ = self.connected == true;
+                self.connected = false;
                 info!{"Disconnected from MQTT server"};
                 Ok(())
             }
@@ -111,12 +129,19 @@ pub mod inventory_client {
                 qos: qos,
             };
 
-            if self.queue_length < self.max_queue_length {
+            //Once anything has gone to the spool later messages follow it there, so they still go out in order
+            if self.queue_length < self.max_queue_length && self.spooled == 0 {
                 self.message_queue.push_back(new_message);
                 self.queue_length = self.message_queue.len();
                 //debug!("Message: '{}' queued. Queue Size: {}", new_message.payload,self.queue_length);
                 return Ok(self.queue_length.try_into().unwrap())
             }
+            else if let Some(spool_path) = self.spool_path.clone() {
+                Self::append_to_spool(&spool_path, &[new_message])?;
+                self.spooled += 1;
+                debug!("Queue full, spooled message. Spooled: {}", self.spooled);
+                return Ok((self.queue_length + self.spooled).try_into().unwrap())
+            }
             else
             {
                 error!("Queue is full, dumping message.");
@@ -125,6 +150,20 @@ pub mod inventory_client {
             }
             
         }
+        //Where messages go when the queue is full and at shutdown
+        pub fn set_spool(&mut self, path: &Path) {
+            self.spool_path = Some(path.to_path_buf());
+        }
+        fn append_to_spool(path: &Path, messages: &[Message]) -> io::Result<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            for message in messages {
+                writeln!(file, "{}", serde_json::to_string(message)?)?;
+            }
+            file.sync_all()
+        }
         pub fn flush_queue(&mut self){
             self.message_queue.clear();
             self.queue_length = self.message_queue.len();
@@ -134,6 +173,139 @@ pub mod inventory_client {
             //self.send_message(&message.topic,&message.payload).unwrap_or_else(self.message_queue.pop_back(message) );
             Ok(1)
         }
+        //Sends a message straight away, falling back to the queue if the server can't take it so nothing is lost
+        pub fn publish(&mut self, topic: &String, message: &String) -> Result<(), Box<dyn Error>> {
+            if !self.message_queue.is_empty() || self.spooled > 0 {
+                self.drain_queue();
+            }
+            if self.message_queue.is_empty() && self.spooled == 0 {
+                match self.send_message(topic, message) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => warn!("Failed to publish on {}, queueing message: {}", topic, e),
+                }
+            }
+            self.queue_message(message.clone(), topic.clone(), 0)?;
+            Ok(())
+        }
+        //Sends queued messages in order, stopping at the first failure so the rest stay queued. Returns how many were sent.
+        //Refills the queue from the spool as it empties.
+        pub fn drain_queue(&mut self) -> usize {
+            let mut sent = 0;
+            loop {
+                while let Some(message) = self.message_queue.pop_front() {
+                    if let Err(e) = self.send_message(&message.topic, &message.payload) {
+                        warn!("Failed to send queued message on {}: {}", message.topic, e);
+                        self.message_queue.push_front(message);
+                        self.queue_length = self.message_queue.len();
+                        debug!("Sent {} queued messages, {} remaining", sent, self.queue_length);
+                        return sent
+                    }
+                    sent += 1;
+                }
+                self.queue_length = 0;
+                let spool_path = match (&self.spool_path, self.spooled) {
+                    (Some(spool_path), spooled) if spooled > 0 => spool_path.clone(),
+                    _ => break,
+                };
+                match self.load_spool(&spool_path) {
+                    Ok(loaded) if loaded > 0 => continue,
+                    Ok(_) => break,
+                    Err(e) => {
+                        warn!("Cannot load spooled messages from {}: {}", spool_path.display(), e);
+                        break
+                    }
+                }
+            }
+            self.queue_length = self.message_queue.len();
+            debug!("Sent {} queued messages, {} remaining", sent, self.queue_length);
+            sent
+        }
+        //Writes whatever is still queued to the spool file (one JSON message per line) ahead of anything already spooled,
+        //since the queue holds the older messages, and empties the queue
+        pub fn spool_queue(&mut self, path: &Path) -> io::Result<usize> {
+            if self.message_queue.is_empty() {
+                return Ok(0)
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let partial = path.with_extension("partial");
+            let mut file = fs::File::create(&partial)?;
+            let mut spooled = 0;
+            for message in self.message_queue.iter() {
+                writeln!(file, "{}", serde_json::to_string(message)?)?;
+                spooled += 1;
+            }
+            match fs::File::open(path) {
+                Ok(existing) => {
+                    io::copy(&mut BufReader::new(existing), &mut file)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            file.sync_all()?;
+            fs::rename(&partial, path)?;
+            self.message_queue.clear();
+            self.queue_length = 0;
+            info!("Spooled {} messages to {}", spooled, path.display());
+            Ok(spooled)
+        }
+        //Loads messages spooled by a previous run (or that overflowed the queue) back onto the queue. Whatever doesn't
+        //fit is written back to the spool, which is only removed once everything in it is queued.
+        pub fn load_spool(&mut self, path: &Path) -> Result<usize, Box<dyn Error>> {
+            let file = match fs::File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.spooled = 0;
+                    return Ok(0)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let partial = path.with_extension("partial");
+            let mut rest: Option<fs::File> = None;
+            let mut loaded = 0;
+            let mut remaining = 0;
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let message: Message = match serde_json::from_str(&line) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Skipping unreadable spooled message in {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if self.message_queue.len() < self.max_queue_length && rest.is_none() {
+                    self.message_queue.push_back(message);
+                    loaded += 1;
+                } else {
+                    if rest.is_none() {
+                        rest = Some(fs::File::create(&partial)?);
+                    }
+                    if let Some(rest) = rest.as_mut() {
+                        writeln!(rest, "{}", line)?;
+                    }
+                    remaining += 1;
+                }
+            }
+            match rest {
+                Some(rest) => {
+                    rest.sync_all()?;
+                    fs::rename(&partial, path)?;
+                }
+                None => fs::remove_file(path)?,
+            }
+            self.queue_length = self.message_queue.len();
+            self.spooled = remaining;
+            info!("Loaded {} spooled messages from {}, {} still spooled", loaded, path.display(), remaining);
+            Ok(loaded)
+        }
+        //Message the broker publishes on our behalf if the connection drops without a clean disconnect
+        pub fn set_last_will(&mut self, topic: &String, message: &String) {
+            self.last_will = Some(mqtt::Message::new(topic, message.clone().into_bytes(), 1));
+        }
     }
     
     #[derive(Serialize, Deserialize)]
@@ -152,6 +324,174 @@ pub mod inventory_client {
             }
         }
     }
+
+    //Tells subscribers whether an agent is running, published on start up and shut down and registered as the last will
+    #[derive(Serialize, Deserialize)]
+    pub struct Presence {
+        pub agent_id: String,
+        pub correlation_id: Uuid,
+        pub status: String,
+        pub reason: String,
+        pub timestamp: DateTime<Utc>,
+    }
+
+    impl Presence {
+        pub fn online(agent: &AgentInfo) -> Self {
+            Self::new(agent, "online", "started")
+        }
+        pub fn offline(agent: &AgentInfo, reason: &str) -> Self {
+            Self::new(agent, "offline", reason)
+        }
+        fn new(agent: &AgentInfo, status: &str, reason: &str) -> Self {
+            Self {
+                agent_id: agent.agent_id.clone(),
+                correlation_id: agent.correlation_id,
+                status: status.to_string(),
+                reason: reason.to_string(),
+                timestamp: Utc::now(),
+            }
+        }
+    }
+}
+
+pub mod lifecycle {
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    //Shared flag which is raised by SIGTERM/SIGINT so collectors can stop at a safe point instead of being killed mid-publish
+    #[derive(Clone)]
+    pub struct Shutdown {
+        flag: Arc<AtomicBool>,
+    }
+
+    impl Shutdown {
+        pub fn new() -> Self {
+            Self { flag: Arc::new(AtomicBool::new(false)) }
+        }
+        pub fn install() -> io::Result<Self> {
+            let shutdown = Self::new();
+            signal_hook::flag::register(SIGTERM, Arc::clone(&shutdown.flag))?;
+            signal_hook::flag::register(SIGINT, Arc::clone(&shutdown.flag))?;
+            Ok(shutdown)
+        }
+        pub fn requested(&self) -> bool {
+            self.flag.load(Ordering::Relaxed)
+        }
+        pub fn request(&self) {
+            self.flag.store(true, Ordering::Relaxed);
+        }
+        //Sleeps for the duration unless a shutdown comes in first. Returns false if it was interrupted.
+        pub fn sleep(&self, duration: Duration) -> bool {
+            let deadline = Instant::now() + duration;
+            while !self.requested() {
+                let now = Instant::now();
+                if now >= deadline {
+                    return true
+                }
+                thread::sleep((deadline - now).min(Duration::from_millis(200)));
+            }
+            false
+        }
+    }
+}
+
+pub mod systemd {
+    use std::env;
+    use std::io;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::path::Path;
+    use std::process;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+    use log::*;
+
+    //Speaks the sd_notify protocol (datagrams of KEY=VALUE lines to $NOTIFY_SOCKET). Does nothing when not run by systemd.
+    pub struct Notifier {
+        socket: Option<(UnixDatagram, SocketAddr)>,
+        watchdog_interval: Option<Duration>,
+        last_ping: Cell<Option<Instant>>,
+    }
+
+    impl Notifier {
+        pub fn from_env() -> Self {
+            let socket = match env::var("NOTIFY_SOCKET") {
+                Ok(path) => Self::open(&path).map_err(|e| warn!("Cannot use NOTIFY_SOCKET {}: {}", path, e)).ok(),
+                Err(_) => None,
+            };
+            Self {
+                socket: socket,
+                watchdog_interval: Self::watchdog_from_env(),
+                last_ping: Cell::new(None),
+            }
+        }
+        pub fn with_socket(path: &str, watchdog_interval: Option<Duration>) -> io::Result<Self> {
+            Ok(Self {
+                socket: Some(Self::open(path)?),
+                watchdog_interval: watchdog_interval,
+                last_ping: Cell::new(None),
+            })
+        }
+        fn open(path: &str) -> io::Result<(UnixDatagram, SocketAddr)> {
+            //A leading @ means a socket in the abstract namespace
+            let addr = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+                None => SocketAddr::from_pathname(Path::new(path))?,
+            };
+            Ok((UnixDatagram::unbound()?, addr))
+        }
+        //systemd asks for pings every WATCHDOG_USEC, we ping at half that to leave some slack
+        fn watchdog_from_env() -> Option<Duration> {
+            if let Ok(pid) = env::var("WATCHDOG_PID") {
+                if pid.parse::<u32>().ok() != Some(process::id()) {
+                    return None
+                }
+            }
+            let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+            if usec == 0 {
+                return None
+            }
+            Some(Duration::from_micros(usec / 2))
+        }
+        pub fn is_enabled(&self) -> bool {
+            self.socket.is_some()
+        }
+        pub fn watchdog_interval(&self) -> Option<Duration> {
+            self.watchdog_interval
+        }
+        pub fn notify(&self, state: &str) -> io::Result<()> {
+            if let Some((socket, addr)) = &self.socket {
+                debug!("sd_notify: {}", state.replace('\n', " "));
+                socket.send_to_addr(state.as_bytes(), addr)?;
+            }
+            Ok(())
+        }
+        pub fn ready(&self) {
+            let _ = self.notify("READY=1").map_err(|e| warn!("sd_notify READY failed: {}", e));
+        }
+        pub fn stopping(&self) {
+            let _ = self.notify("STOPPING=1").map_err(|e| warn!("sd_notify STOPPING failed: {}", e));
+        }
+        pub fn status(&self, text: &str) {
+            let _ = self.notify(&format!("STATUS={}", text.replace('\n', " "))).map_err(|e| warn!("sd_notify STATUS failed: {}", e));
+        }
+        pub fn watchdog(&self) {
+            let _ = self.notify("WATCHDOG=1").map_err(|e| warn!("sd_notify WATCHDOG failed: {}", e));
+            self.last_ping.set(Some(Instant::now()));
+        }
+        //Cheap enough to call from inside collection loops, only pings once the interval has passed
+        pub fn watchdog_if_due(&self) {
+            if let Some(interval) = self.watchdog_interval {
+                if self.last_ping.get().map_or(true, |last| last.elapsed() >= interval) {
+                    self.watchdog();
+                }
+            }
+        }
+    }
 }
 
 
@@ -162,9 +502,13 @@ pub mod inventory_client {
 mod tests {
     use super::*;
     extern crate paho_mqtt as mqtt;
-    use inventory_client::InventoryTransport;
+    use inventory_client::{AgentInfo, InventoryTransport, Presence};
+    use lifecycle::Shutdown;
+    use systemd::Notifier;
     use env_logger::*;
     use serde_json::json;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
 
     //Tests for InventoryTransport object
     #[test]
@@ -275,4 +619,85 @@ mod tests {
         assert_eq!(result,1);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn publish_without_server_queues_message() {
+        let mut my_server = InventoryTransport::new("localhost".to_string(),9901,"fail2".to_string());
+        let result = my_server.publish(&"testtopic".to_string(),&"A simple message".to_string());
+        assert!(result.is_ok());
+        assert_eq!(my_server.queue_length,1);
+    }
+    #[test]
+    fn spool_and_load_queue_succeeds() {
+        let spool = std::env::temp_dir().join(format!("node_agent-spool-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut my_server = InventoryTransport::new("localhost".to_string(),9901,"fail3".to_string());
+        for n in 0..3 {
+            my_server.queue_message(format!("message {}", n),"testtopic".to_string(),0).unwrap();
+        }
+        assert_eq!(my_server.spool_queue(&spool).unwrap(),3);
+        assert_eq!(my_server.queue_length,0);
+
+        let mut next_run = InventoryTransport::new("localhost".to_string(),9901,"fail4".to_string());
+        assert_eq!(next_run.load_spool(&spool).unwrap(),3);
+        assert_eq!(next_run.queue_length,3);
+        assert!(!spool.exists());
+    }
+    #[test]
+    fn full_queue_overflows_to_spool() {
+        let spool = std::env::temp_dir().join(format!("node_agent-spool-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut my_server = InventoryTransport::new("localhost".to_string(),9901,"fail5".to_string());
+        my_server.max_queue_length = 2;
+        my_server.set_spool(&spool);
+        for n in 0..5 {
+            my_server.queue_message(format!("message {}", n),"testtopic".to_string(),0).unwrap();
+        }
+        assert_eq!((my_server.queue_length, my_server.spooled), (2, 3));
+        //Shutting down puts the queue ahead of the overflow
+        assert_eq!(my_server.spool_queue(&spool).unwrap(),2);
+
+        let mut next_run = InventoryTransport::new("localhost".to_string(),9901,"fail6".to_string());
+        next_run.max_queue_length = 2;
+        next_run.set_spool(&spool);
+        assert_eq!(next_run.load_spool(&spool).unwrap(),2);
+        assert_eq!((next_run.queue_length, next_run.spooled), (2, 3));
+        //Nothing was lost or loaded twice
+        assert_eq!(std::fs::read_to_string(&spool).unwrap().lines().count(),3);
+        assert!(std::fs::read_to_string(&spool).unwrap().starts_with(r#"{"topic":"testtopic","payload":"message 2""#));
+        std::fs::remove_file(&spool).unwrap();
+    }
+    #[test]
+    fn offline_presence_has_status() {
+        let agent = AgentInfo::new("123456567788990".to_string(),"default".to_string());
+        let presence = serde_json::to_value(Presence::offline(&agent,"SIGTERM")).unwrap();
+        assert_eq!(presence["status"],"offline");
+        assert_eq!(presence["agent_id"],"123456567788990");
+    }
+    #[test]
+    fn shutdown_interrupts_sleep() {
+        let shutdown = Shutdown::new();
+        assert!(shutdown.sleep(Duration::from_millis(10)));
+        shutdown.request();
+        assert!(!shutdown.sleep(Duration::from_secs(60)));
+    }
+    #[test]
+    fn notifier_sends_to_notify_socket() {
+        let path = std::env::temp_dir().join(format!("node_agent-notify-{}.sock", uuid::Uuid::new_v4()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let notifier = Notifier::with_socket(path.to_str().unwrap(),Some(Duration::from_secs(30))).unwrap();
+        let mut buf = [0u8; 256];
+
+        notifier.ready();
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len],b"READY=1");
+
+        notifier.status("Collecting processes");
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len],b"STATUS=Collecting processes");
+
+        notifier.watchdog_if_due();
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len],b"WATCHDOG=1");
+        let _ = std::fs::remove_file(&path);
+    }
+
+}
@@ -10,10 +10,14 @@ pub mod sys_interagator {
     use std::fs::{self, File};
     use std::io::{self, BufRead,Read};
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
     use std::io::ErrorKind;
+    use std::os::unix::fs::MetadataExt;
+    use std::hash::{Hash, Hasher};
     use std::path::PathBuf;
     use uuid::Uuid;
+    use chrono::{DateTime, TimeZone, Utc};
+    use crate::budget::Budget;
 
     //This is the struct to capture information about the agent. Right now I'm working on just a local agent querying local information.
     #[derive(Serialize, Deserialize)]
@@ -57,18 +61,20 @@ pub mod sys_interagator {
             return result
         }
 
-        //Function which gets all ipaddresses of the specified hamily "v4" or "v6"
+        //Function which gets all ipaddresses of the specified hamily "v4" or "v6" across every interface which is up,
+        //the full per interface detail comes from the interfaces collector
         pub fn get_ipaddresses(v: &str) -> Vec<String> {
             let mut result = vec![];
             let all_interfaces = interfaces();
-            let interfaces = all_interfaces
+            let interfaces: Vec<_> = all_interfaces
                 .iter()
-                .find(|int| int.is_up() && !int.is_loopback() && !int.ips.is_empty());
-            match interfaces {
-                Some(interface) => info!("Found interface with [{}]", interface.name),
-                None => warn!("Where did all the networks go?"),
+                .filter(|int| int.is_up() && !int.is_loopback() && !int.ips.is_empty())
+                .collect();
+            if interfaces.is_empty() {
+                warn!("Where did all the networks go?");
             }
-            for interface in &interfaces{
+            for interface in interfaces {
+                info!("Found interface with [{}]", interface.name);
                 for ip in &interface.ips {
                     match ip {
                         IpNetwork::V4(_) => {
@@ -83,7 +89,7 @@ pub mod sys_interagator {
                     
                 }
             }
-            debug! ("IPv4 addresses: {:?}", result);
+            debug! ("IP{} addresses: {:?}", v, result);
             return result
         }
     }
@@ -132,23 +138,31 @@ pub mod sys_interagator {
             }
         }
 
-        fn get_pid_from_inode(inode: &str) -> Option<u32> {
-            let proc_dir = fs::read_dir("/proc").ok()?;
-            
-            for entry in proc_dir {
-                if let Ok(entry) = entry {
-                    if let Ok(pid) = entry.file_name().into_string() {
-                        if pid.chars().all(char::is_numeric) {
-                            let fd_dir = format!("/proc/{}/fd", pid);
-                            if let Ok(fds) = fs::read_dir(fd_dir) {
-                                for fd in fds {
-                                    if let Ok(fd) = fd {
-                                        if let Ok(link) = fs::read_link(fd.path()) {
-                                            if let Some(socket_inode) = link.to_str() {
-                                                if socket_inode.contains(inode) {
-                                                    return pid.parse().ok();
-                                                }
-                                            }
+        //Walks every /proc/<pid>/fd once and maps socket inodes to the pid holding them, rather than walking them all again for each socket
+        fn get_socket_inodes(budget: &Budget) -> HashMap<String, u32> {
+            let mut inodes = HashMap::new();
+            let proc_dir = match fs::read_dir("/proc") {
+                Ok(proc_dir) => proc_dir,
+                Err(e) => {
+                    warn!("Cannot read /proc to find socket owners: {e:?}");
+                    return inodes
+                }
+            };
+
+            for entry in proc_dir.filter_map(Result::ok) {
+                if let Ok(pid) = entry.file_name().into_string() {
+                    if pid.chars().all(char::is_numeric) {
+                        let fd_dir = format!("/proc/{}/fd", pid);
+                        if let Ok(fds) = fs::read_dir(fd_dir) {
+                            for fd in fds.filter_map(Result::ok) {
+                                if !budget.tick() {
+                                    return inodes
+                                }
+                                if let Ok(link) = fs::read_link(fd.path()) {
+                                    //Socket fds link to "socket:[<inode>]"
+                                    if let Some(socket_inode) = link.to_str().and_then(|l| l.strip_prefix("socket:[")).and_then(|l| l.strip_suffix(']')) {
+                                        if let Ok(pid) = pid.parse() {
+                                            inodes.entry(socket_inode.to_string()).or_insert(pid);
                                         }
                                     }
                                 }
@@ -157,11 +171,11 @@ pub mod sys_interagator {
                     }
                 }
             }
-            
-            None
+
+            inodes
         }
 
-        fn get_established_connections_from_proc(proc_file: &str, is_ipv6: bool) -> io::Result<Vec<(SocketAddr, SocketAddr,u32)>> {
+        fn get_established_connections_from_proc(proc_file: &str, is_ipv6: bool, inodes: &HashMap<String, u32>) -> io::Result<Vec<(SocketAddr, SocketAddr,u32)>> {
             let file = File::open(proc_file)?;
             let reader = io::BufReader::new(file);
             let mut connections = Vec::new();
@@ -169,9 +183,9 @@ pub mod sys_interagator {
             for line in reader.lines().skip(1) {
                 let line = line?;
                 let columns: Vec<&str> = line.split_whitespace().collect();
-                if columns.len() > 3 && columns[3] == "01" { // 01 means ESTABLISHED
-                    if let (Some(local), Some(remote), Some(pid)) = (Self::parse_ip_port(columns[1], is_ipv6), Self::parse_ip_port(columns[2], is_ipv6), Self::get_pid_from_inode(columns[9])) {
-                        connections.push((SocketAddr::new(local.0, local.1), SocketAddr::new(remote.0, remote.1),pid));
+                if columns.len() > 9 && columns[3] == "01" { // 01 means ESTABLISHED
+                    if let (Some(local), Some(remote), Some(pid)) = (Self::parse_ip_port(columns[1], is_ipv6), Self::parse_ip_port(columns[2], is_ipv6), inodes.get(columns[9])) {
+                        connections.push((SocketAddr::new(local.0, local.1), SocketAddr::new(remote.0, remote.1),*pid));
                     }
                 }
             }
@@ -180,19 +194,214 @@ pub mod sys_interagator {
         }
 
         pub fn get_established_connections() -> io::Result<Vec<(SocketAddr, SocketAddr,u32)>> {
+            Self::get_established_connections_within(&Budget::unlimited())
+        }
+
+        //Same as get_established_connections but paces the /proc/<pid>/fd walk. If the budget runs out sockets whose
+        //owner wasn't found yet are left out.
+        pub fn get_established_connections_within(budget: &Budget) -> io::Result<Vec<(SocketAddr, SocketAddr,u32)>> {
+            let inodes = Self::get_socket_inodes(budget);
             let mut connections = Vec::new();
-            connections.extend(Self::get_established_connections_from_proc("/proc/net/tcp", false)?);
-            connections.extend(Self::get_established_connections_from_proc("/proc/net/tcp6", true)?);
+            connections.extend(Self::get_established_connections_from_proc("/proc/net/tcp", false, &inodes)?);
+            connections.extend(Self::get_established_connections_from_proc("/proc/net/tcp6", true, &inodes)?);
             Ok(connections)
         }
     }
     
-    #[derive(Hash, Eq, PartialEq, Debug, Deserialize,Serialize,Clone)]
+    //Counters like rss and cpu time change every scan, so a process is the same process as long as these match
+    #[derive(Debug, Deserialize,Serialize,Clone,Default)]
     pub struct Process{
         pub pid: String,
         pub exe: String,
         pub cmd: String,
         pub cmdline: String,
+        //Effective uid and gid from /proc/<pid>/status. The process collector resolves the uid to a username.
+        #[serde(default)]
+        pub uid: Option<u32>,
+        #[serde(default)]
+        pub gid: Option<u32>,
+        #[serde(default)]
+        pub user: Option<String>,
+        //systemd unit (service or scope) the process runs under, from its cgroup
+        #[serde(default)]
+        pub unit: Option<String>,
+        //Owning package of exe, filled in by the process collector
+        #[serde(default)]
+        pub package: Option<String>,
+        #[serde(default)]
+        pub package_version: Option<String>,
+        //True for executables no installed package owns (hand deployed), None when we can't tell
+        #[serde(default)]
+        pub unpackaged: Option<bool>,
+        //Identify the binary behind exe, the rest of its metadata goes out once per binary on the executables topic
+        #[serde(default)]
+        pub exe_sha256: Option<String>,
+        #[serde(default)]
+        pub exe_build_id: Option<String>,
+        //From /proc/<pid>/stat, see ProcStat
+        #[serde(default)]
+        pub ppid: Option<u32>,
+        #[serde(default)]
+        pub process_launch_time: Option<DateTime<Utc>>,
+        #[serde(default)]
+        pub state: Option<String>,
+        #[serde(default)]
+        pub threads: Option<u32>,
+        #[serde(default)]
+        pub rss_bytes: Option<u64>,
+        #[serde(default)]
+        pub vsz_bytes: Option<u64>,
+        //User plus system time
+        #[serde(default)]
+        pub cpu_time_ms: Option<u64>,
+        #[serde(default)]
+        pub nice: Option<i32>,
+        #[serde(default)]
+        pub tty: Option<String>,
+        #[serde(default)]
+        pub session_id: Option<u32>,
+        #[serde(default)]
+        pub cwd: Option<String>,
+    }
+
+    impl Process {
+        fn identity(&self) -> (&str, Option<&DateTime<Utc>>, &str, &str, &str) {
+            (&self.pid, self.process_launch_time.as_ref(), &self.exe, &self.cmd, &self.cmdline)
+        }
+    }
+
+    impl PartialEq for Process {
+        fn eq(&self, other: &Self) -> bool {
+            self.identity() == other.identity()
+        }
+    }
+
+    impl Eq for Process {}
+
+    impl Hash for Process {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.identity().hash(state);
+        }
+    }
+
+    //The fields of /proc/<pid>/stat we use, times still in clock ticks and memory in pages
+    #[derive(Debug, Default, PartialEq)]
+    pub struct ProcStat {
+        pub state: char,
+        pub ppid: u32,
+        pub session_id: u32,
+        pub tty_nr: u32,
+        pub utime: u64,
+        pub stime: u64,
+        pub nice: i32,
+        pub threads: u32,
+        pub start_ticks: u64,
+        pub vsz_bytes: u64,
+        pub rss_pages: u64,
+    }
+
+    //The command name is in brackets and can hold spaces and brackets itself, so fields are counted from the last ')'
+    pub fn parse_stat(contents: &str) -> Option<ProcStat> {
+        let fields: Vec<&str> = contents[contents.rfind(')')? + 1..].split_whitespace().collect();
+        //fields[0] is field 3 of proc(5)
+        let field = |n: usize| fields.get(n - 3).copied();
+        Some(ProcStat {
+            state: field(3)?.chars().next()?,
+            ppid: field(4)?.parse().ok()?,
+            session_id: field(6)?.parse().ok()?,
+            tty_nr: field(7)?.parse().ok()?,
+            utime: field(14)?.parse().ok()?,
+            stime: field(15)?.parse().ok()?,
+            nice: field(19)?.parse().ok()?,
+            threads: field(20)?.parse().ok()?,
+            start_ticks: field(22)?.parse().ok()?,
+            vsz_bytes: field(23)?.parse().ok()?,
+            rss_pages: field(24)?.parse().ok()?,
+        })
+    }
+
+    pub fn state_name(state: char) -> String {
+        match state {
+            'R' => "running",
+            'S' => "sleeping",
+            'D' => "disk_sleep",
+            'Z' => "zombie",
+            'T' => "stopped",
+            't' => "tracing_stop",
+            'X' | 'x' => "dead",
+            'I' => "idle",
+            'P' => "parked",
+            'W' => "waking",
+            'K' => "wakekill",
+            _ => "unknown",
+        }.to_string()
+    }
+
+    //Effective ids are the second column of "Uid:\treal\teffective\tsaved\tfs"
+    pub fn parse_status_ids(contents: &str) -> (Option<u32>, Option<u32>) {
+        let effective = |key: &str| contents.lines().find(|line| line.starts_with(key)).and_then(|line| line.split_whitespace().nth(2)).and_then(|id| id.parse().ok());
+        (effective("Uid:"), effective("Gid:"))
+    }
+
+    //tty_nr packs the device number as minor bits 0-7 and 20-31, major bits 8-15
+    pub fn tty_name(tty_nr: u32) -> Option<String> {
+        if tty_nr == 0 {
+            return None
+        }
+        let major = (tty_nr >> 8) & 0xfff;
+        let minor = (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00);
+        Some(match major {
+            136..=143 => format!("pts/{}", (major - 136) * 256 + minor),
+            4 if minor < 64 => format!("tty{}", minor),
+            4 => format!("ttyS{}", minor - 64),
+            5 if minor == 1 => "console".to_string(),
+            _ => format!("{}:{}", major, minor),
+        })
+    }
+
+    //Seconds since the epoch the system booted, for turning start ticks into a time
+    pub fn boot_time() -> Option<i64> {
+        fs::read_to_string("/proc/stat").ok()?.lines().find_map(|line| line.strip_prefix("btime ")).and_then(|btime| btime.trim().parse().ok())
+    }
+
+    //Fills in everything /proc/<pid>/stat, status and cwd give us
+    pub fn read_proc_details(process: &mut Process, proc_path: &std::path::Path, boot_time: Option<i64>) {
+        let clock_ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        if let Some(stat) = fs::read_to_string(proc_path.join("stat")).ok().and_then(|contents| parse_stat(&contents)) {
+            process.ppid = Some(stat.ppid);
+            process.state = Some(state_name(stat.state));
+            process.threads = Some(stat.threads);
+            process.rss_bytes = Some(stat.rss_pages * page_size);
+            process.vsz_bytes = Some(stat.vsz_bytes);
+            process.cpu_time_ms = Some((stat.utime + stat.stime) * 1000 / clock_ticks);
+            process.nice = Some(stat.nice);
+            process.tty = tty_name(stat.tty_nr);
+            process.session_id = Some(stat.session_id);
+            process.process_launch_time = boot_time.and_then(|boot| {
+                let millis = boot * 1000 + (stat.start_ticks * 1000 / clock_ticks) as i64;
+                Utc.timestamp_millis_opt(millis).single()
+            });
+        }
+        if let Ok(status) = fs::read_to_string(proc_path.join("status")) {
+            let (uid, gid) = parse_status_ids(&status);
+            process.uid = uid.or(process.uid);
+            process.gid = gid;
+        }
+        process.cwd = fs::read_link(proc_path.join("cwd")).ok().map(|cwd| cwd.display().to_string());
+    }
+
+    //The innermost unit in the systemd cgroup path, "0::/system.slice/nginx.service" on cgroup v2 or the name=systemd
+    //hierarchy on v1. Slices are only containers so they're skipped.
+    pub fn unit_from_cgroup(cgroup: &str) -> Option<String> {
+        let path = cgroup
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2).map(|path| (line, path)))
+            .find(|(line, _)| line.starts_with("0::") || line.contains(":name=systemd:"))
+            .map(|(_, path)| path)?;
+        path.rsplit('/')
+            .find(|part| part.contains('.') && !part.ends_with(".slice"))
+            .map(String::from)
     }
 
     #[derive(Serialize, Deserialize)]
@@ -208,8 +417,17 @@ pub mod sys_interagator {
             new_processes : Self::get_current_processes(),}
          
         }
+        //A first scan which stops when the budget runs out
+        pub fn new_within(budget: &Budget) -> Self {
+            let (processes, _) = Self::scan_processes(budget);
+            Self {processes : processes.clone(), new_processes : processes}
+        }
         fn get_current_processes() -> HashSet<Process> {
+            Self::scan_processes(&Budget::unlimited()).0
+        }
+        fn scan_processes(budget: &Budget) -> (HashSet<Process>, bool) {
             let mut processes = HashSet::new();
+            let boot_time = boot_time();
             
             // The /proc directory contains all running processes
             let proc_dir = "/proc";
@@ -231,25 +449,24 @@ pub mod sys_interagator {
                 if path.is_dir() {
                     if let Some(pid_str) = path.file_name().and_then(|s| s.to_str()) {
                         if pid_str.chars().all(char::is_numeric) {
+                            if !budget.tick() {
+                                return (processes, false)
+                            }
                             
-                            //Read the cmdline file to get the path
+                            //Read the cmdline file to get the path. Processes can exit between listing /proc and reading it so those are skipped
                             let cmdline_path = path.join("cmdline");
-                            let mut file = fs::File::open(&cmdline_path).unwrap_or_else(|error| {
-                                if error.kind() == ErrorKind::NotFound {
-                                    panic!("{} file was not found {error:?}",cmdline_path.display());
+                            let mut file = match fs::File::open(&cmdline_path) {
+                                Ok(file) => file,
+                                Err(error) => {
+                                    debug!("Skipping {}: {error:?}",cmdline_path.display());
+                                    continue;
                                 }
-                                else {
-                                    panic!("I be broken {error:?}");
-                                }
-                            });
+                            };
                             let mut cmdline = String::new();
-                            file.read_to_string(&mut cmdline).unwrap_or_else(|error| {
-                                match error.kind() {
-                                    ErrorKind::NotFound =>  { panic!("{} file was not found {error:?}", cmdline)},
-                                    ErrorKind::PermissionDenied =>  { panic!("{} permission denied {error:?}", cmdline)},
-                                    _ => { panic!("Shit be hard dude {error:?}")},
-                                }
-                            });
+                            if let Err(error) = file.read_to_string(&mut cmdline) {
+                                debug!("Skipping {}: {error:?}",cmdline_path.display());
+                                continue;
+                            }
                             let executable_path = cmdline.split('\0').next().unwrap_or("").split(" ").next().unwrap().to_string();
 
                             //Get the symlink path to the executable
@@ -266,22 +483,35 @@ pub mod sys_interagator {
                                     nopath
                                 }
                             });
+                            let uid = fs::metadata(&path).ok().map(|metadata| metadata.uid());
+                            let unit = fs::read_to_string(path.join("cgroup")).ok().and_then(|cgroup| unit_from_cgroup(&cgroup));
                             //Insert into a struct
-                            processes.insert(Process {exe: exe_file.display().to_string(),pid: pid_str.to_string(),cmd: executable_path,cmdline: cmdline});
+                            let mut process = Process {exe: exe_file.display().to_string(),pid: pid_str.to_string(),cmd: executable_path,cmdline: cmdline,uid: uid,unit: unit,..Default::default()};
+                            read_proc_details(&mut process, &path, boot_time);
+                            processes.insert(process);
                         }
                     }
                 }
             }
             
             
-            processes
+            (processes, true)
         }
 
         pub fn get_new_processes(&mut self) -> HashSet<Process> {
-            let all_processes: HashSet<Process> = Self::get_current_processes();
+            self.get_new_processes_within(&Budget::unlimited())
+        }
+
+        //If the scan was cut short we only know about the processes we got to, so nothing is dropped from the known set until a full scan
+        pub fn get_new_processes_within(&mut self, budget: &Budget) -> HashSet<Process> {
+            let (all_processes, complete) = Self::scan_processes(budget);
             let new_processes: HashSet<Process> = all_processes.difference(&self.processes).cloned().collect::<HashSet<Process>>();
             //panic!("{} new processes", new_processes.len());
-            self.processes = all_processes;
+            if complete {
+                self.processes = all_processes;
+            } else {
+                self.processes.extend(all_processes);
+            }
             new_processes
         }
     }
@@ -348,5 +578,47 @@ mod tests {
         let result = processes.get_new_processes();
         assert!(if result.iter().count()>0{true}else{false});
     }
-}
 
+    #[test]
+    fn get_processes_stops_when_budget_runs_out(){
+        let budget = crate::budget::Budget::new(None, Some(std::time::Duration::from_secs(0)));
+        let result = sys_interagator::Processes::new_within(&budget);
+        assert_eq!(result.processes.len(),0);
+        assert!(budget.cut_short().is_some());
+    }
+
+    #[test]
+    fn unit_comes_from_systemd_cgroup(){
+        assert_eq!(sys_interagator::unit_from_cgroup("0::/system.slice/nginx.service\n").as_deref(),Some("nginx.service"));
+        assert_eq!(sys_interagator::unit_from_cgroup("12:pids:/user.slice\n1:name=systemd:/user.slice/user-1000.slice/session-3.scope\n").as_deref(),Some("session-3.scope"));
+        assert_eq!(sys_interagator::unit_from_cgroup("0::/\n"),None);
+    }
+
+    #[test]
+    fn proc_stat_is_parsed(){
+        //A command name with spaces and brackets in it
+        let stat = "4242 (my (odd) app) S 1 4242 4242 34817 4242 4194560 1234 0 0 0 250 50 0 0 20 5 3 0 123456 104857600 2560 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 2 0 0 0 0 0";
+        let parsed = sys_interagator::parse_stat(stat).unwrap();
+        assert_eq!((parsed.state, parsed.ppid, parsed.session_id, parsed.nice, parsed.threads), ('S', 1, 4242, 5, 3));
+        assert_eq!((parsed.utime, parsed.stime, parsed.start_ticks, parsed.vsz_bytes, parsed.rss_pages), (250, 50, 123456, 104857600, 2560));
+        assert_eq!(sys_interagator::tty_name(parsed.tty_nr).as_deref(), Some("pts/1"));
+        assert_eq!(sys_interagator::tty_name(0), None);
+        assert_eq!(sys_interagator::parse_status_ids("Name:\tapp\nUid:\t1000\t33\t33\t33\nGid:\t1000\t44\t44\t44\n"), (Some(33), Some(44)));
+    }
+
+    #[test]
+    fn own_process_details_are_read(){
+        let mut process = sys_interagator::Process::default();
+        let path = std::path::PathBuf::from(format!("/proc/{}", std::process::id()));
+        sys_interagator::read_proc_details(&mut process, &path, sys_interagator::boot_time());
+        assert!(process.threads.unwrap_or(0) >= 1);
+        assert!(process.rss_bytes.unwrap_or(0) > 0);
+        assert_eq!(process.cwd, std::env::current_dir().ok().map(|cwd| cwd.display().to_string()));
+        let launched = process.process_launch_time.unwrap();
+        assert!(launched <= chrono::Utc::now() && launched > chrono::Utc::now() - chrono::Duration::hours(1));
+        //Counters changing doesn't make it a different process
+        let mut later = process.clone();
+        later.rss_bytes = Some(1);
+        assert!(later == process);
+    }
+}
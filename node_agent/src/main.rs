@@ -1,13 +1,23 @@
 //use std::sync::mpsc;
 //use std::thread;
+use collectors::Record;
+use config::AgentConfig;
 use linux::sys_interagator;
-use node_agent::inventory_client::{AgentInfo, InventoryTransport};
-use serde_json::json;
+use node_agent::inventory_client::{AgentInfo, InventoryTransport, Presence};
+use node_agent::lifecycle::Shutdown;
+use node_agent::systemd::Notifier;
 use log::*;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 //use std::process::{Command, Stdio};
 
+pub mod budget;
+pub mod collectors;
+pub mod config;
 pub mod linux;
+pub mod netlink;
 
 #[derive(StructOpt, Debug)]
 #[structopt()]
@@ -23,7 +33,30 @@ struct Opt {
     ts: Option<stderrlog::Timestamp>,
     /// Site code (-s sitecode)
     #[structopt(short = "s", long = "sitecode", default_value="default")]
-    sitecode: String
+    sitecode: String,
+    /// Directory where unsent messages are kept between runs
+    #[structopt(long = "spool-dir", default_value="/var/lib/node_agent", parse(from_os_str))]
+    spool_dir: PathBuf,
+    /// Agent configuration file (JSON)
+    #[structopt(short = "c", long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Collectors to enable, overriding the config file (-e processes,listeners)
+    #[structopt(short = "e", long = "enable", use_delimiter = true)]
+    enable: Vec<String>,
+    /// Collectors to disable, overriding the config file (-d connections)
+    #[structopt(short = "d", long = "disable", use_delimiter = true)]
+    disable: Vec<String>,
+    /// Keep running and re-run collectors on their schedules until stopped
+    #[structopt(long = "daemon")]
+    daemon: bool,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// List the available collectors
+    Collectors,
 }
 
 fn main() {
@@ -37,16 +70,67 @@ fn main() {
         .timestamp(opt.ts.unwrap_or(stderrlog::Timestamp::Millisecond))
         .init()
         .unwrap();
-           
-    //Set up a new onject to get specifc system information needed
-    let system = sys_interagator::SystemInfo::new();
+
+    let config = match &opt.config {
+        Some(path) => match AgentConfig::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}", e);
+                return
+            }
+        },
+        None => AgentConfig::default(),
+    };
+
+    //Config file first, then the command line on top
+    let mut registry = collectors::default_registry();
+    let configured = collectors::register_scripts(&mut registry, &config.scripts)
+        .and_then(|_| registry.replace(Box::new(collectors::certificates::CertificateCollector::new(config.certificates.clone()))))
+        .and_then(|_| registry.replace(Box::new(collectors::network::ListenerCollector::new(config.service_probe.clone(), config.tls_probe.clone()))))
+        .and_then(|_| registry.apply(&config.collectors.enable, &config.collectors.disable))
+        .and_then(|_| config.collectors.intervals.iter().try_for_each(|(name, secs)| registry.set_interval(name, Duration::from_secs(*secs))))
+        .and_then(|_| registry.apply(&opt.enable, &opt.disable));
+    if let Err(e) = configured {
+        error!("{}", e);
+        return
+    }
+
+    if let Some(Command::Collectors) = opt.cmd {
+        println!("{:<14} {:<9} {:<12} {:<20} {}", "NAME", "ENABLED", "SCHEDULE", "PRIVILEGES", "DESCRIPTION");
+        for collector in registry.available() {
+            let privileges = collector.privileges.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(",");
+            println!("{:<14} {:<9} {:<12} {:<20} {}", collector.name, collector.enabled, collector.schedule.to_string(), if privileges.is_empty() {"-".to_string()} else {privileges}, collector.description);
+        }
+        return
+    }
+
+    //Stop at the next safe point on SIGTERM/SIGINT rather than dying mid-publish
+    let shutdown = match Shutdown::install() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            error!("Cannot install signal handlers: {}", e);
+            return
+        }
+    };
+    let notifier = Rc::new(Notifier::from_env());
+    //Collections can take longer than the watchdog interval, so budgets ping it as they go
+    let heartbeat = Rc::clone(&notifier);
+    registry.set_heartbeat(Rc::new(move || heartbeat.watchdog_if_due()));
+    //Collectors and the scripts they start inherit this, so do it before anything runs
+    if let Err(e) = budget::lower_priority(&config.budgets) {
+        warn!("Cannot lower the agent's priority: {}", e);
+    }
+    let spool_path = opt.spool_dir.join("outbox.jsonl");
 
     //Sets up an agent object
-    let agent: AgentInfo = AgentInfo::new(system.agent_id.clone(), opt.sitecode);
+    let agent: AgentInfo = AgentInfo::new(sys_interagator::SystemInfo::get_machineid(), opt.sitecode);
 
     //Setup a connection to MQTT
-    let mut server = InventoryTransport::new("localhost".to_string(),9001,system.agent_id.clone());
+    let mut server = InventoryTransport::new("localhost".to_string(),9001,agent.agent_id.clone());
+    let lost_presence = serde_json::to_string(&Presence::offline(&agent,"connection lost")).unwrap();
+    server.set_last_will(&"/agents".to_string(),&lost_presence);
     info!("Connecting to {}",server.url);
+    notifier.status(&format!("Connecting to {}",server.url));
     match server.connect() {
         Ok(()) => {info!("Connected to {}", server.url);}//println!("Connected"),
         Err(e) => {
@@ -55,73 +139,102 @@ fn main() {
         }
     };
 
+    //Anything a previous run couldn't send goes out first
+    server.set_spool(&spool_path);
+    if let Err(e) = server.load_spool(&spool_path) {
+        warn!("Cannot load spooled messages from {}: {}", spool_path.display(), e);
+    }
+    notifier.ready();
+
     //Send agent information to inform subscribers that there is a new agent
     let agent_json = serde_json::to_string(&agent).unwrap();
 
     let system_json_string = serde_json::to_string(&agent_json).unwrap();
     debug!("System message payload: {}",system_json_string);
 
-    server.send_message(&"/agents".to_string(),&system_json_string).unwrap();
-
-    //Construct topics for this agent
-    let node_topic = format!("/nodes/{0}",system.agent_id); //use this to send node system information and TODO: look to use the mqtt retained flag
-    debug!("MQTT Node Topic path: {}", node_topic);
-    let process_topic = format!("/nodes/{0}/processes",system.agent_id);
-    debug!("MQTT Process Topic path: {}", process_topic);
-    let net_listening_topic = format!("/nodes/{0}/net_listening",system.agent_id);
-    debug!("MQTT Network Listening Topic path: {}", net_listening_topic);
-    let net_connection_topic = format!("/nodes/{0}/net_connection",system.agent_id);
-    debug!("MQTT Network Connections Topic path: {}", net_connection_topic);
-
-    //Send node information, right now this is the same as the local system but is in place to allow for remote querying later
-    let node_json = serde_json::to_string(&system).unwrap();
-    debug!("Node message payload: {}", node_json);
-    server.send_message(&node_topic,&node_json).unwrap();
-
-    let mut syspids = sys_interagator::Processes::new();
-    for syspid in syspids.processes.iter() {
-        let process_json = serde_json::to_string(&syspid).unwrap();
-        debug!("Process message payload: {}", process_json);
-        server.send_message(&process_topic,&process_json).unwrap();
+    publish(&mut server,&"/agents".to_string(),&system_json_string);
+    let online_presence = serde_json::to_string(&Presence::online(&agent)).unwrap();
+    publish(&mut server,&"/agents".to_string(),&online_presence);
+
+    loop {
+        registry.run_due(&shutdown, &config.budgets, |collector, records| {
+            notifier.status(&format!("Publishing {} records from {}", records.len(), collector));
+            for record in records {
+                let topic = record.mqtt_topic(&agent.agent_id);
+                let payload = envelope(record, &agent, collector);
+                debug!("{} message payload: {}", collector, payload);
+                //Stopping, keep the rest for the next run rather than dropping them
+                if shutdown.requested() {
+                    if let Err(e) = server.queue_message(payload, topic.clone(), 0) {
+                        error!("Dropping message for {}: {}", topic, e);
+                    }
+                    continue;
+                }
+                publish(&mut server,&topic,&payload);
+                notifier.watchdog_if_due();
+            }
+        });
+        if !opt.daemon || shutdown.requested() {
+            break;
+        }
+
+        let next_due = match registry.next_due() {
+            Some(next_due) => next_due,
+            None => {
+                info!("No scheduled collectors left to run");
+                break;
+            }
+        };
+        notifier.status("Waiting for next collection");
+        //Wake up at least as often as the watchdog needs pinging
+        while Instant::now() < next_due && !shutdown.requested() {
+            let mut wait = next_due.saturating_duration_since(Instant::now());
+            if let Some(interval) = notifier.watchdog_interval() {
+                wait = wait.min(interval);
+            }
+            shutdown.sleep(wait);
+            notifier.watchdog_if_due();
+        }
+        if shutdown.requested() {
+            break;
+        }
     }
 
-    for new_syspid in syspids.get_new_processes().iter() {
-        let new_process_json = serde_json::to_string(&new_syspid).unwrap();
-        debug!("New process message payload: {}", new_process_json);
-        server.send_message(&process_topic,&new_process_json).unwrap();
+    let reason = if shutdown.requested() {"signal"} else {"run complete"};
+    stop(&mut server, &agent, &notifier, &spool_path, reason);
+}
+
+fn envelope(record: Record, agent: &AgentInfo, collector: &str) -> String {
+    serde_json::to_string(&record.into_envelope(agent, collector)).unwrap()
+}
+
+//Publishes a message, anything the server can't take right now stays on the outbox queue
+fn publish(server: &mut InventoryTransport, topic: &String, payload: &String) {
+    if let Err(e) = server.publish(topic, payload) {
+        error!("Dropping message for {}: {}", topic, e);
     }
+}
 
-    if let Ok(listeners) = listeners::get_all() {
-        for l in listeners {
-            //println!("{}");
-            let net_listening_json = json!({
-                "correlation_id":agent.correlation_id,
-                "node":system.agent_id,
-                "pid":l.process.pid,
-                "tcp_socket":l.socket,
-            });
-            let net_listening_json_string = serde_json::to_string(&net_listening_json).unwrap();
-            debug!("Network listening mmessage payload: {}", net_listening_json_string);
-            server.send_message(&net_listening_topic.to_string(),&net_listening_json_string).unwrap();    
+//Flushes the outbox, spools anything left over to disk, tells subscribers we're going away and disconnects
+fn stop(server: &mut InventoryTransport, agent: &AgentInfo, notifier: &Notifier, spool_path: &PathBuf, reason: &str) {
+    info!("Shutting down: {}", reason);
+    notifier.stopping();
+    notifier.status("Flushing outbox");
+    server.drain_queue();
+    if server.queue_length > 0 {
+        if let Err(e) = server.spool_queue(spool_path) {
+            error!("Cannot spool {} unsent messages to {}: {}", server.queue_length, spool_path.display(), e);
         }
     }
-    let system_network = sys_interagator::NetConnections::new();
-    for connection in system_network.connections {
-        //let connection_json = serde_json::to_string(&connection);
-        let net_connection_json = json!({
-            "correlation_id":agent.correlation_id,
-            "node":system.agent_id,
-            "source_socket": connection.0,
-            "destination_socket": connection.1,
-            "pid": connection.2,
-        });
-        let net_connection_json_string = serde_json::to_string(&net_connection_json).unwrap();
-        debug!("Network connection message payload: {}", net_connection_json_string);
-        server.send_message(&net_connection_topic.to_string(),&net_connection_json_string).unwrap();
+
+    let offline_presence = serde_json::to_string(&Presence::offline(agent, reason)).unwrap();
+    if let Err(e) = server.send_message(&"/agents".to_string(),&offline_presence) {
+        warn!("Cannot publish offline presence: {}", e);
     }
 
     //Disconnect from MQTT
     info!("Disconnecting from {}",server.url);
-    server.disconnect().unwrap();
-
+    if let Err(e) = server.disconnect() {
+        warn!("Error disconnecting from {}: {}", server.url, e);
+    }
 }
@@ -0,0 +1,143 @@
+//Just enough rtnetlink to dump kernel tables that have no /proc view, such as the IPv6 neighbour table. Requests are
+//NLM_F_DUMP requests on a NETLINK_ROUTE socket and replies are returned as raw messages for the caller to decode.
+use std::io;
+use std::mem;
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_DUMP: u16 = 0x300;
+
+//Netlink messages and attributes are padded to 4 bytes
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+pub struct Message {
+    pub kind: u16,
+    pub payload: Vec<u8>,
+}
+
+//Closes the socket however we leave
+struct Socket(i32);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+//Sends a dump request of the given type with header as the family specific header (ndmsg, rtmsg...) and collects every
+//reply up to NLMSG_DONE
+pub fn dump(request: u16, header: &[u8]) -> io::Result<Vec<Message>> {
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = Socket(fd);
+
+    let length = NLMSG_HDRLEN + header.len();
+    let mut packet = Vec::with_capacity(align(length));
+    packet.extend_from_slice(&(length as u32).to_ne_bytes());
+    packet.extend_from_slice(&request.to_ne_bytes());
+    packet.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    packet.extend_from_slice(&1u32.to_ne_bytes()); //sequence
+    packet.extend_from_slice(&0u32.to_ne_bytes()); //port id, 0 is the kernel
+    packet.extend_from_slice(header);
+    packet.resize(align(length), 0);
+
+    let mut kernel: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as u16;
+    let sent = unsafe {
+        libc::sendto(socket.0, packet.as_ptr() as *const libc::c_void, packet.len(), 0,
+            &kernel as *const libc::sockaddr_nl as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as u32)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut messages = Vec::new();
+    let mut buffer = vec![0u8; 32768];
+    loop {
+        let received = unsafe { libc::recv(socket.0, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (parsed, done) = parse_messages(&buffer[..received as usize])?;
+        messages.extend(parsed);
+        if done || received == 0 {
+            return Ok(messages);
+        }
+    }
+}
+
+//Splits one recv() worth of data into messages, true once NLMSG_DONE has been seen
+pub fn parse_messages(data: &[u8]) -> io::Result<(Vec<Message>, bool)> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= data.len() {
+        let length = u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(data[offset + 4..offset + 6].try_into().unwrap());
+        if length < NLMSG_HDRLEN || offset + length > data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"));
+        }
+        let payload = &data[offset + NLMSG_HDRLEN..offset + length];
+        match kind {
+            NLMSG_DONE => return Ok((messages, true)),
+            NLMSG_ERROR => {
+                let errno = payload.get(..4).map(|e| i32::from_ne_bytes(e.try_into().unwrap())).unwrap_or(0);
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+            }
+            _ => messages.push(Message { kind: kind, payload: payload.to_vec() }),
+        }
+        offset += align(length);
+    }
+    Ok((messages, false))
+}
+
+//Route attributes (struct rtattr) following the family header, as (type, value) pairs
+pub fn attributes(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attributes = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        let length = u16::from_ne_bytes(data[offset..offset + 2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(data[offset + 2..offset + 4].try_into().unwrap());
+        if length < 4 || offset + length > data.len() {
+            break;
+        }
+        attributes.push((kind, &data[offset + 4..offset + length]));
+        offset += align(length);
+    }
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_and_attributes_are_split() {
+        let mut data = Vec::new();
+        //One message carrying a 4 byte header and a 6 byte attribute padded to 8
+        data.extend_from_slice(&32u32.to_ne_bytes());
+        data.extend_from_slice(&28u16.to_ne_bytes());
+        data.extend_from_slice(&[0; 10]);
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        data.extend_from_slice(&10u16.to_ne_bytes());
+        data.extend_from_slice(&2u16.to_ne_bytes());
+        data.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0, 0]);
+        data.extend_from_slice(&16u32.to_ne_bytes());
+        data.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        data.extend_from_slice(&[0; 10]);
+
+        let (messages, done) = parse_messages(&data).unwrap();
+        assert!(done);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].kind, 28);
+        let attributes = attributes(&messages[0].payload[4..]);
+        assert_eq!(attributes, vec![(2, &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff][..])]);
+    }
+}